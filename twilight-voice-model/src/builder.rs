@@ -0,0 +1,301 @@
+//! Builders for payloads sent by the client, upholding the invariants Discord expects of them.
+
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    net::SocketAddr,
+};
+
+use twilight_model::id::marker::{GuildMarker, UserMarker};
+use twilight_model::id::Id;
+
+use crate::encryption_mode::EncryptionMode;
+use crate::payload::{Heartbeat, Identify, SelectProtocol, Speaking};
+use crate::protocol_data::ProtocolData;
+use crate::speaking_state::SpeakingState;
+use crate::transport_protocol::TransportProtocol;
+
+/// A payload failed validation.
+#[derive(Debug, Eq, PartialEq)]
+pub struct BuildError {
+    kind: BuildErrorType,
+}
+
+impl BuildError {
+    const fn new(kind: BuildErrorType) -> Self {
+        Self { kind }
+    }
+
+    pub const fn kind(&self) -> BuildErrorType {
+        self.kind
+    }
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.kind {
+            BuildErrorType::EmptySessionId => "session ID is empty",
+            BuildErrorType::EmptyToken => "token is empty",
+            BuildErrorType::UnspecifiedAddress => "address is unspecified",
+            BuildErrorType::UnspecifiedPort => "port is 0",
+        })
+    }
+}
+
+impl Error for BuildError {}
+
+/// Why a payload failed validation.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum BuildErrorType {
+    /// [`Identify`] has an empty session ID.
+    EmptySessionId,
+    /// [`Identify`] has an empty token.
+    EmptyToken,
+    /// [`SelectProtocol`] has an unspecified IP address, such as `0.0.0.0`.
+    UnspecifiedAddress,
+    /// [`SelectProtocol`] has port 0.
+    UnspecifiedPort,
+}
+
+/// Builds an [`Identify`], rejecting an empty session ID or token.
+#[derive(Clone, Debug)]
+#[must_use = "builders have no effect unless built"]
+pub struct IdentifyBuilder(Identify);
+
+impl IdentifyBuilder {
+    /// Identify `user_id` to the voice server of `server_id`, with the session ID and token
+    /// from the voice state and voice server updates of the main gateway.
+    pub fn new(
+        server_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+        session_id: impl Into<String>,
+        token: impl Into<String>,
+    ) -> Self {
+        Self(Identify {
+            server_id,
+            session_id: session_id.into(),
+            token: token.into(),
+            user_id,
+        })
+    }
+
+    pub fn build(self) -> Result<Identify, BuildError> {
+        if self.0.session_id.is_empty() {
+            return Err(BuildError::new(BuildErrorType::EmptySessionId));
+        }
+        if self.0.token.is_empty() {
+            return Err(BuildError::new(BuildErrorType::EmptyToken));
+        }
+
+        Ok(self.0)
+    }
+}
+
+/// Builds a [`Heartbeat`].
+#[derive(Clone, Copy, Debug)]
+#[must_use = "builders have no effect unless built"]
+pub struct HeartbeatBuilder(Heartbeat);
+
+impl HeartbeatBuilder {
+    /// Heartbeat with `nonce`, mirrored by the server in its acknowledgement.
+    pub const fn new(nonce: u64) -> Self {
+        Self(Heartbeat {
+            t: nonce,
+            seq_ack: -1,
+        })
+    }
+
+    /// Acknowledge the numbered message `seq`, defaulting to none having been received.
+    pub const fn with_seq_ack(mut self, seq: Option<i64>) -> Self {
+        self.0.seq_ack = match seq {
+            Some(seq) => seq,
+            None => -1,
+        };
+        self
+    }
+
+    pub const fn build(self) -> Heartbeat {
+        self.0
+    }
+}
+
+/// Builds a [`SelectProtocol`], rejecting an address the server could not send to.
+#[derive(Clone, Copy, Debug)]
+#[must_use = "builders have no effect unless built"]
+pub struct SelectProtocolBuilder {
+    address: SocketAddr,
+    mode: EncryptionMode,
+    protocol: TransportProtocol,
+}
+
+impl SelectProtocolBuilder {
+    /// Ask for voice to be sent to `address`, as found through IP discovery, encrypted with
+    /// `mode`.
+    pub const fn new(address: SocketAddr, mode: EncryptionMode) -> Self {
+        Self {
+            address,
+            mode,
+            protocol: TransportProtocol::Udp,
+        }
+    }
+
+    /// Transport protocol, defaulting to [`TransportProtocol::Udp`].
+    pub const fn with_protocol(mut self, protocol: TransportProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn build(self) -> Result<SelectProtocol, BuildError> {
+        if self.address.ip().is_unspecified() {
+            return Err(BuildError::new(BuildErrorType::UnspecifiedAddress));
+        }
+        if self.address.port() == 0 {
+            return Err(BuildError::new(BuildErrorType::UnspecifiedPort));
+        }
+
+        Ok(SelectProtocol {
+            data: ProtocolData {
+                address: self.address.ip(),
+                mode: self.mode.name().into(),
+                port: self.address.port(),
+            },
+            protocol: self.protocol,
+        })
+    }
+}
+
+/// Builds a [`Speaking`] as sent by the client, which never names a user.
+#[derive(Clone, Copy, Debug)]
+#[must_use = "builders have no effect unless built"]
+pub struct SpeakingBuilder(Speaking);
+
+impl SpeakingBuilder {
+    /// Announce that audio from `ssrc` has stopped, until a state is set.
+    pub const fn new(ssrc: u32) -> Self {
+        Self(Speaking {
+            delay: Some(0),
+            speaking: SpeakingState::empty(),
+            ssrc,
+            user_id: None,
+        })
+    }
+
+    /// How audio is being sent, or empty if it has stopped.
+    pub const fn with_state(mut self, state: SpeakingState) -> Self {
+        self.0.speaking = state;
+        self
+    }
+
+    /// Delay, defaulting to 0 as Discord asks of bots.
+    pub const fn with_delay(mut self, delay: u32) -> Self {
+        self.0.delay = Some(delay);
+        self
+    }
+
+    pub const fn build(self) -> Speaking {
+        self.0
+    }
+}
+
+impl Heartbeat {
+    pub const fn builder(nonce: u64) -> HeartbeatBuilder {
+        HeartbeatBuilder::new(nonce)
+    }
+}
+
+impl Identify {
+    pub fn builder(
+        server_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+        session_id: impl Into<String>,
+        token: impl Into<String>,
+    ) -> IdentifyBuilder {
+        IdentifyBuilder::new(server_id, user_id, session_id, token)
+    }
+}
+
+impl SelectProtocol {
+    pub const fn builder(address: SocketAddr, mode: EncryptionMode) -> SelectProtocolBuilder {
+        SelectProtocolBuilder::new(address, mode)
+    }
+}
+
+impl Speaking {
+    pub const fn builder(ssrc: u32) -> SpeakingBuilder {
+        SpeakingBuilder::new(ssrc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use static_assertions::assert_impl_all;
+    use twilight_model::id::Id;
+
+    use super::{BuildError, BuildErrorType};
+    use crate::payload::{Heartbeat, Identify, SelectProtocol, Speaking};
+    use crate::{EncryptionMode, SpeakingState, TransportProtocol};
+
+    assert_impl_all!(BuildError: std::error::Error, Send, Sync);
+
+    #[test]
+    fn identify_rejects_empty_credentials() {
+        let build = |session_id: &str, token: &str| {
+            Identify::builder(Id::new(1), Id::new(2), session_id, token)
+                .build()
+                .map_err(|error| error.kind())
+        };
+
+        assert!(build("session", "token").is_ok());
+        assert_eq!(build("", "token"), Err(BuildErrorType::EmptySessionId));
+        assert_eq!(build("session", ""), Err(BuildErrorType::EmptyToken));
+    }
+
+    #[test]
+    fn heartbeat_seq_ack() {
+        assert_eq!(Heartbeat::builder(7).build().seq_ack, -1);
+        assert_eq!(
+            Heartbeat::builder(7).with_seq_ack(Some(3)).build().seq_ack,
+            3
+        );
+    }
+
+    #[test]
+    fn select_protocol() {
+        let address = SocketAddr::from((Ipv4Addr::new(192, 168, 0, 141), 40404));
+        let select = SelectProtocol::builder(address, EncryptionMode::XSalsa20Poly1305Suffix)
+            .build()
+            .unwrap();
+
+        assert_eq!(select.protocol, TransportProtocol::Udp);
+        assert_eq!(select.data.address, address.ip());
+        assert_eq!(select.data.port, 40404);
+        assert_eq!(select.data.mode, "xsalsa20_poly1305_suffix");
+
+        let mode = EncryptionMode::AeadXChaCha20Poly1305Rtpsize;
+        let unspecified = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 40404));
+        assert_eq!(
+            SelectProtocol::builder(unspecified, mode).build(),
+            Err(BuildError::new(BuildErrorType::UnspecifiedAddress))
+        );
+        let no_port = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        assert_eq!(
+            SelectProtocol::builder(no_port, mode).build(),
+            Err(BuildError::new(BuildErrorType::UnspecifiedPort))
+        );
+    }
+
+    #[test]
+    fn speaking_has_no_user() {
+        let speaking = Speaking::builder(5)
+            .with_state(SpeakingState::MICROPHONE)
+            .build();
+
+        assert_eq!(speaking.delay, Some(0));
+        assert_eq!(speaking.speaking, SpeakingState::MICROPHONE);
+        assert_eq!(speaking.ssrc, 5);
+        assert_eq!(speaking.user_id, None);
+    }
+}
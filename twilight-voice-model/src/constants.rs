@@ -1,4 +1,4 @@
 //! A set of constants used by the library.
 
 /// Gateway version of the Voice API which this library encodes.
-pub const GATEWAY_VERSION: u8 = 4;
+pub const GATEWAY_VERSION: u8 = 8;
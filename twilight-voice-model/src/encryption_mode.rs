@@ -0,0 +1,49 @@
+use std::fmt::{self, Display};
+
+use serde::{Deserialize, Serialize};
+
+/// Encryption modes for RTP payloads known to be offered by Discord's voice servers.
+///
+/// Modes are sent as strings in [`Ready`] and [`ProtocolData`], so that offers of modes missing
+/// here can still be read.
+///
+/// [`ProtocolData`]: crate::ProtocolData
+/// [`Ready`]: crate::payload::Ready
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
+#[non_exhaustive]
+pub enum EncryptionMode {
+    /// AES256-GCM, with a nonce appended to each packet.
+    #[serde(rename = "aead_aes256_gcm_rtpsize")]
+    AeadAes256GcmRtpsize,
+    /// XChaCha20-Poly1305, with a nonce appended to each packet.
+    #[serde(rename = "aead_xchacha20_poly1305_rtpsize")]
+    AeadXChaCha20Poly1305Rtpsize,
+    /// XSalsa20-Poly1305, keyed by the RTP header.
+    #[serde(rename = "xsalsa20_poly1305")]
+    XSalsa20Poly1305,
+    /// XSalsa20-Poly1305, with a random nonce appended to each packet.
+    #[serde(rename = "xsalsa20_poly1305_suffix")]
+    XSalsa20Poly1305Suffix,
+    /// XSalsa20-Poly1305, with an incrementing 4 byte nonce appended to each packet.
+    #[serde(rename = "xsalsa20_poly1305_lite")]
+    XSalsa20Poly1305Lite,
+}
+
+impl EncryptionMode {
+    /// Name of the mode as sent to and from the voice gateway.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::AeadAes256GcmRtpsize => "aead_aes256_gcm_rtpsize",
+            Self::AeadXChaCha20Poly1305Rtpsize => "aead_xchacha20_poly1305_rtpsize",
+            Self::XSalsa20Poly1305 => "xsalsa20_poly1305",
+            Self::XSalsa20Poly1305Suffix => "xsalsa20_poly1305_suffix",
+            Self::XSalsa20Poly1305Lite => "xsalsa20_poly1305_lite",
+        }
+    }
+}
+
+impl Display for EncryptionMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
@@ -8,6 +8,7 @@ use super::Event;
 use crate::payload::*;
 use crate::protocol_data::ProtocolData;
 use crate::speaking_state::SpeakingState;
+use crate::transport_protocol::TransportProtocol;
 
 #[test]
 fn deserialize_identify_json() {
@@ -50,7 +51,7 @@ fn deserialize_select_protocol_json() {
     let event = serde_json::from_str(json_data);
 
     let proto = SelectProtocol {
-        protocol: "udp".into(),
+        protocol: TransportProtocol::Udp,
         data: ProtocolData {
             address: Ipv4Addr::new(127, 0, 0, 1).into(),
             port: 1337,
@@ -286,7 +287,7 @@ fn serialize_identify() {
 #[test]
 fn serialize_select_protocol() {
     let value: Event = SelectProtocol {
-        protocol: "udp".into(),
+        protocol: TransportProtocol::Udp,
         data: ProtocolData {
             address: Ipv4Addr::new(192, 168, 0, 141).into(),
             port: 40404,
@@ -322,7 +323,10 @@ fn serialize_select_protocol() {
             Token::U16(40404),
             Token::StructEnd,
             Token::Str("protocol"),
-            Token::Str("udp"),
+            Token::UnitVariant {
+                name: "TransportProtocol",
+                variant: "udp",
+            },
             Token::StructEnd,
             Token::StructEnd,
         ],
@@ -2,16 +2,23 @@
 //! (de)serialisation.
 #![deny(rustdoc::broken_intra_doc_links)]
 
+pub mod builder;
 mod close_code;
 pub mod constants;
+mod encryption_mode;
 mod event;
 pub mod payload;
 mod protocol_data;
 mod speaking_state;
+mod transport_protocol;
+// Serde helpers for IDs sent as strings, which no payload uses yet.
+#[allow(dead_code)]
 mod util;
 
 pub use twilight_model::voice::OpCode;
 
 pub use self::{
-    close_code::CloseCode, event::Event, protocol_data::ProtocolData, speaking_state::SpeakingState,
+    close_code::CloseCode, encryption_mode::EncryptionMode, event::Event,
+    protocol_data::ProtocolData, speaking_state::SpeakingState,
+    transport_protocol::TransportProtocol,
 };
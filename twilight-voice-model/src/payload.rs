@@ -8,6 +8,7 @@ use twilight_model::id::Id;
 
 use crate::protocol_data::ProtocolData;
 use crate::speaking_state::SpeakingState;
+use crate::transport_protocol::TransportProtocol;
 
 /// Message indicating that another user has connected to the voice channel.
 ///
@@ -100,9 +101,7 @@ pub struct SelectProtocol {
     /// Client's response to encryption/connection negotiation.
     pub data: ProtocolData,
     /// Transport protocol.
-    ///
-    /// Currently, `"udp"` is the only known accepted value.
-    pub protocol: String,
+    pub protocol: TransportProtocol,
 }
 
 /// Server's confirmation of a negotiated encryption scheme.
@@ -0,0 +1,29 @@
+use std::fmt::{self, Display};
+
+use serde::{Deserialize, Serialize};
+
+/// Transport protocol to carry voice over, selected with
+/// [`SelectProtocol`](crate::payload::SelectProtocol).
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq, Deserialize, Serialize)]
+#[non_exhaustive]
+#[serde(rename_all = "lowercase")]
+pub enum TransportProtocol {
+    /// RTP over UDP, the only protocol Discord currently accepts.
+    #[default]
+    Udp,
+}
+
+impl TransportProtocol {
+    /// Name of the protocol as sent to the voice gateway.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Udp => "udp",
+        }
+    }
+}
+
+impl Display for TransportProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
@@ -1,3 +1,6 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::{broadcast, watch};
 use twilight_model::{
     gateway::payload::incoming::VoiceServerUpdate,
     id::{
@@ -5,11 +8,27 @@ use twilight_model::{
         Id,
     },
 };
+use twilight_voice_model::SpeakingState;
 
-use crate::{gateway::DiscordVoiceClient, Error, Result};
+use crate::{
+    config::Config,
+    driver::{Driver, Playback, Session},
+    events::VoiceEvent,
+    gateway::DiscordVoiceClient,
+    input::AudioSource,
+    queue::TrackQueue,
+    receive::{MixedAudio, ReceivedAudio},
+    record::RecordingOptions,
+    rtcp::RtcpStats,
+    stats::ConnectionStats,
+    track::{Track, TrackHandle},
+    Error, Result,
+};
 
+/// Sends voice state updates through the Discord gateway, to join a channel and to request a
+/// new session when one cannot be resumed.
 #[async_trait::async_trait]
-pub trait VoiceUpdate {
+pub trait VoiceUpdate: Send + Sync {
     /// Send a voice update message to the inner shard handle.
     async fn update_voice_state(
         &self,
@@ -20,19 +39,45 @@ pub trait VoiceUpdate {
     ) -> Result<()>;
 }
 
+#[derive(Clone, Debug)]
 pub struct PartialVoiceStateUpdate {
     pub session_id: String,
     pub channel_id: Option<Id<ChannelMarker>>,
     pub user_id: Id<UserMarker>,
 }
 
+/// Progress of a [`VoiceClient`] towards being able to send and receive audio.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ConnectionState {
+    /// Not in a voice channel, either before joining or after the connection was lost for good.
+    Disconnected,
+    /// Joining, and waiting for the Discord gateway to send the voice server and our voice
+    /// state, noting which have arrived.
+    AwaitingVoiceUpdates {
+        server_received: bool,
+        state_received: bool,
+    },
+    /// Connecting to the voice gateway and identifying.
+    ConnectingGateway,
+    /// Running IP discovery and agreeing on encryption over the voice gateway.
+    NegotiatingUdp,
+    /// Audio can be sent and received.
+    Connected,
+    /// The connection was lost, and attempt number `attempt` to start a new session is waiting
+    /// or underway.
+    Reconnecting { attempt: u32 },
+    /// The connection was lost, and attempt number `attempt` to resume the session is underway.
+    Resuming { attempt: u32 },
+}
+
 enum Connection {
     Handshaking {
         server: Option<VoiceServerUpdate>,
         state: Option<PartialVoiceStateUpdate>,
     },
     Establishing,
-    Connected(DiscordVoiceClient),
+    Connected(Driver),
     Disconnected,
 }
 
@@ -47,29 +92,43 @@ impl Connection {
         )
     }
 
-    pub fn is_connected(&self) -> bool {
-        matches!(self, Self::Connected(_))
-    }
-
     pub fn is_disconnected(&self) -> bool {
         matches!(self, Self::Disconnected)
     }
+
+    /// The state of a connection which is still handshaking.
+    fn awaiting(&self) -> Option<ConnectionState> {
+        match self {
+            Self::Handshaking { server, state } => Some(ConnectionState::AwaitingVoiceUpdates {
+                server_received: server.is_some(),
+                state_received: state.is_some(),
+            }),
+            _ => None,
+        }
+    }
 }
 
 pub struct VoiceClient<D: VoiceUpdate> {
-    driver: D,
+    driver: Arc<D>,
     guild_id: Id<GuildMarker>,
     channel_id: Id<ChannelMarker>,
     connection: Connection,
+    playback: Playback,
 }
 
-impl<D: VoiceUpdate> VoiceClient<D> {
-    pub fn new(driver: D, guild_id: Id<GuildMarker>, channel_id: Id<ChannelMarker>) -> Self {
+impl<D: VoiceUpdate + 'static> VoiceClient<D> {
+    pub fn new(
+        driver: D,
+        guild_id: Id<GuildMarker>,
+        channel_id: Id<ChannelMarker>,
+        config: Config,
+    ) -> Self {
         Self {
-            driver,
+            driver: Arc::new(driver),
             guild_id,
             channel_id,
             connection: Connection::Disconnected,
+            playback: Playback::new(guild_id, config),
         }
     }
 
@@ -81,9 +140,24 @@ impl<D: VoiceUpdate> VoiceClient<D> {
             state: Some(voice_state),
         } = connection
         {
-            self.connection = Connection::Connected(
-                DiscordVoiceClient::connect(voice_server, voice_state).await?,
-            );
+            self.playback.set_state(ConnectionState::ConnectingGateway);
+            let config = self.playback.config.clone();
+            let gateway = match DiscordVoiceClient::connect(voice_server, voice_state, config).await
+            {
+                Ok(gateway) => gateway,
+                Err(error) => {
+                    self.connection = Connection::Disconnected;
+                    self.playback.set_state(ConnectionState::Disconnected);
+                    return Err(error);
+                }
+            };
+            let session = Session {
+                voice_update: self.driver.clone(),
+                guild_id: self.guild_id,
+                channel_id: self.channel_id,
+            };
+            self.connection =
+                Connection::Connected(Driver::spawn(gateway, self.playback.clone(), session));
 
             Ok(())
         } else {
@@ -91,40 +165,53 @@ impl<D: VoiceUpdate> VoiceClient<D> {
         }
     }
 
+    /// Handle a `VoiceServerUpdate` for this guild.
+    ///
+    /// Once connected, this moves the connection to the new voice server.
     pub async fn on_voice_server_update(&mut self, data: VoiceServerUpdate) -> Result<()> {
-        if let Connection::Handshaking { server, .. } = &mut self.connection {
-            if server.is_some() {
-                todo!("Handle duplicate connecting.")
-            } else {
-                *server = Some(data);
-
-                if self.connection.is_ready() {
-                    self.establish_connection().await?;
-                }
+        if let Connection::Connected(driver) = &self.connection {
+            driver.update_server(data);
+        } else if let Connection::Handshaking { server, .. } = &mut self.connection {
+            // A repeated update replaces the earlier one.
+            *server = Some(data);
+
+            self.update_awaiting();
+            if self.connection.is_ready() {
+                self.establish_connection().await?;
             }
         }
 
         Ok(())
     }
 
+    /// Handle a `VoiceStateUpdate` for our own user in this guild.
     pub async fn on_voice_state_update(&mut self, data: PartialVoiceStateUpdate) -> Result<()> {
-        if let Connection::Handshaking { state, .. } = &mut self.connection {
-            if state.is_some() {
-                todo!("Handle duplicate connecting.")
-            } else {
-                *state = Some(data);
-
-                if self.connection.is_ready() {
-                    self.establish_connection().await?;
-                }
+        if let Connection::Connected(driver) = &self.connection {
+            driver.update_state(data);
+        } else if data.channel_id.is_none() {
+            // Removed from the channel before the connection was established.
+            self.connection = Connection::Disconnected;
+            self.playback.set_state(ConnectionState::Disconnected);
+        } else if let Connection::Handshaking { state, .. } = &mut self.connection {
+            *state = Some(data);
+
+            self.update_awaiting();
+            if self.connection.is_ready() {
+                self.establish_connection().await?;
             }
         }
 
         Ok(())
     }
 
+    fn update_awaiting(&self) {
+        if let Some(state) = self.connection.awaiting() {
+            self.playback.set_state(state);
+        }
+    }
+
     pub async fn join(&mut self, self_deaf: bool, self_mute: bool) -> Result<()> {
-        if !self.connection.is_disconnected() {
+        if self.is_joined() {
             return Err(Error::AlreadyJoined);
         }
 
@@ -132,9 +219,353 @@ impl<D: VoiceUpdate> VoiceClient<D> {
             server: None,
             state: None,
         };
+        self.playback.mute.set_deaf(self_deaf);
+        self.playback.mute.set_mute(self_mute);
+        self.update_awaiting();
+        self.update_voice_state().await
+    }
+
+    /// Whether joining or in a channel, rather than never joined or disconnected for good.
+    fn is_joined(&self) -> bool {
+        !self.connection.is_disconnected()
+            && self.connection_state() != ConnectionState::Disconnected
+    }
+
+    async fn update_voice_state(&self) -> Result<()> {
+        let mute = &self.playback.mute;
         self.driver
-            .update_voice_state(self.guild_id, Some(self.channel_id), self_deaf, self_mute)
+            .update_voice_state(
+                self.guild_id,
+                Some(self.channel_id),
+                mute.is_deaf(),
+                mute.is_mute(),
+            )
+            .await
+    }
+
+    pub fn is_mute(&self) -> bool {
+        self.playback.mute.is_mute()
+    }
+
+    /// Mute or unmute ourselves, keeping the connection.
+    ///
+    /// While muted no audio is sent, and speaking is cleared. Tracks carry on playing without
+    /// being heard. Before joining this only sets the state to join with.
+    pub async fn set_mute(&self, mute: bool) -> Result<()> {
+        self.playback.mute.set_mute(mute);
+        if !self.is_joined() {
+            return Ok(());
+        }
+
+        self.update_voice_state().await
+    }
+
+    pub fn is_deaf(&self) -> bool {
+        self.playback.mute.is_deaf()
+    }
+
+    /// Deafen or undeafen ourselves, keeping the connection.
+    ///
+    /// While deafened received audio is neither decoded nor recorded. Before joining this only
+    /// sets the state to join with.
+    pub async fn set_deaf(&self, deaf: bool) -> Result<()> {
+        self.playback.mute.set_deaf(deaf);
+        if !self.is_joined() {
+            return Ok(());
+        }
+
+        self.update_voice_state().await
+    }
+
+    /// Current state of the connection.
+    pub fn connection_state(&self) -> ConnectionState {
+        *self.playback.state.borrow()
+    }
+
+    /// Watch the state of the connection, to be notified whenever it changes.
+    pub fn watch_connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.playback.state.subscribe()
+    }
+
+    /// Start playing `source` immediately, ending the current track. The rest of the queue
+    /// plays after it.
+    ///
+    /// PCM which is not already 48 kHz stereo is converted with the default
+    /// [`ResampleQuality`].
+    ///
+    /// [`ResampleQuality`]: crate::input::ResampleQuality
+    pub fn play(&self, source: impl AudioSource + 'static) -> TrackHandle {
+        self.playback.queue.play_now(source)
+    }
+
+    /// Play `source` alongside the queue and any other tracks, mixing them together.
+    ///
+    /// Useful for sound effects over background music. The track is not part of the queue, and
+    /// is controlled only through the returned handle.
+    pub fn overlay(&self, source: impl AudioSource + 'static) -> TrackHandle {
+        let track = Track::new(source);
+        let handle = track.handle.clone();
+        self.playback.pending.push(track);
+
+        handle
+    }
+
+    /// Stop the current track and clear the queue.
+    pub fn stop(&self) {
+        self.playback.queue.stop();
+    }
+
+    /// Queue of tracks played over this connection.
+    pub fn queue(&self) -> &TrackQueue {
+        &self.playback.queue
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.playback.master.get()
+    }
+
+    /// Scale all audio sent over this connection by `volume`, where 1.0 leaves it unchanged.
+    ///
+    /// The change is ramped in over a few milliseconds to avoid clicks. Any volume other than
+    /// 1.0 requires Opus tracks to be decoded and re-encoded.
+    pub fn set_volume(&self, volume: f32) {
+        self.playback.master.set(volume);
+    }
+
+    /// Flags announced to Discord while audio is being sent.
+    pub fn speaking_state(&self) -> SpeakingState {
+        self.playback.speaking.get()
+    }
+
+    /// Announce audio with `state` rather than the default [`SpeakingState::MICROPHONE`], such
+    /// as [`SpeakingState::SOUNDSHARE`] to play without a speaking indicator, or with
+    /// [`SpeakingState::PRIORITY`] added to lower other speakers.
+    ///
+    /// Speaking is announced automatically when audio starts, and cleared after it stops.
+    pub fn set_speaking_state(&self, state: SpeakingState) {
+        self.playback.speaking.set(state);
+    }
+
+    /// Subscribe to audio received from other users, decoded to 48 kHz stereo PCM.
+    ///
+    /// Each user's audio arrives in 20 ms frames, in order and at a steady pace, see
+    /// [`VoiceClient::set_jitter_delay`].
+    ///
+    /// Received audio is only decrypted and decoded while there is a subscriber. Decoding
+    /// requires the `opus` feature.
+    pub fn receive(&self) -> broadcast::Receiver<ReceivedAudio> {
+        self.playback.receive.subscribe()
+    }
+
+    /// Subscribe to audio received from all users, mixed into one 20 ms frame at a time.
+    ///
+    /// Frames are sent at a steady pace while connected, and are silent while nobody speaks.
+    /// Decoding requires the `opus` feature.
+    pub fn receive_mixed(&self) -> broadcast::Receiver<MixedAudio> {
+        self.playback.receive.subscribe_mixed()
+    }
+
+    /// Drop the decoder of a user who has not spoken for `timeout`, defaulting to
+    /// [`DEFAULT_SPEAKER_TIMEOUT`]. Decoders are always dropped when a user leaves.
+    ///
+    /// [`DEFAULT_SPEAKER_TIMEOUT`]: crate::receive::DEFAULT_SPEAKER_TIMEOUT
+    pub fn set_speaker_timeout(&self, timeout: Duration) {
+        self.playback.receive.set_speaker_timeout(timeout);
+    }
+
+    /// Hold back between `min` and `max` of each user's audio to smooth out network jitter,
+    /// defaulting to 40 to 200 ms.
+    ///
+    /// Received packets are put back in order and played out every 20 ms, after a delay which
+    /// adapts to how much their arrival varies. Packets arriving after their turn are dropped.
+    pub fn set_jitter_delay(&self, min: Duration, max: Duration) {
+        self.playback.receive.set_jitter_delay(min, max);
+    }
+
+    /// Start recording the audio received from every user to files, as described by
+    /// `options`.
+    ///
+    /// The recording carries on across reconnects until [`VoiceClient::stop_recording`] is
+    /// called. Only one recording can be in progress at a time.
+    pub async fn start_recording(&self, options: RecordingOptions) -> Result<()> {
+        self.playback.recorder.start(options).await
+    }
+
+    /// Stop recording, finishing all files before returning. Does nothing if not recording.
+    ///
+    /// Returns any error the recording ran into while writing.
+    pub async fn stop_recording(&self) -> Result<()> {
+        self.playback.recorder.stop().await
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.playback.recorder.is_recording()
+    }
+
+    /// Packet loss, jitter and round trip time of the audio sent and received, from RTCP.
+    pub fn rtcp_stats(&self) -> RtcpStats {
+        self.playback.rtcp.stats().clone()
+    }
+
+    /// Snapshot of the statistics of this connection, including those from RTCP.
+    pub fn stats(&self) -> ConnectionStats {
+        self.playback.stats.snapshot(self.rtcp_stats())
+    }
+
+    /// Send an RTCP sender report every 5 seconds while sending audio, disabled by default.
+    ///
+    /// Sender reports let the voice server measure the round trip time, which is then
+    /// reported in [`RtcpStats::outbound`].
+    pub fn set_sender_reports(&self, enabled: bool) {
+        self.playback.rtcp.set_sender_reports(enabled);
+    }
+
+    /// Subscribe to events about playback and reconnects on this connection.
+    pub fn events(&self) -> broadcast::Receiver<VoiceEvent> {
+        self.playback.events.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time;
+    use twilight_model::id::Id;
+    use twilight_voice_model::{CloseCode, OpCode};
+
+    use super::{ConnectionState, VoiceClient};
+    use crate::{
+        config::Config,
+        test_util::{GatewayEvent, MockVoiceServer, MockVoiceUpdate, VoiceStateRequest},
+        Error,
+    };
+
+    fn client(server: &MockVoiceServer) -> (VoiceClient<MockVoiceUpdate>, MockVoiceUpdate) {
+        let gateway = MockVoiceUpdate::new(Id::new(3), server.endpoint());
+        gateway.set_auto_respond(false);
+        let config = server.configure(Config::default());
+        let client = VoiceClient::new(gateway.clone(), Id::new(1), Id::new(2), config);
+
+        (client, gateway)
+    }
+
+    async fn identified(server: &mut MockVoiceServer) -> bool {
+        time::timeout(Duration::from_secs(5), async {
+            loop {
+                match server.next_event().await {
+                    Some(event) if event.kind() == OpCode::Identify => return true,
+                    Some(_) => {}
+                    None => return false,
+                }
+            }
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    #[tokio::test]
+    async fn joins_once() -> anyhow::Result<()> {
+        let server = MockVoiceServer::start().await?;
+        let (mut client, gateway) = client(&server);
+
+        client.join(true, false).await?;
+        assert!(matches!(
+            client.join(true, false).await,
+            Err(Error::AlreadyJoined)
+        ));
+        assert_eq!(
+            gateway.requests(),
+            [VoiceStateRequest {
+                guild_id: Id::new(1),
+                channel_id: Some(Id::new(2)),
+                self_deaf: true,
+                self_mute: false,
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replaces_duplicate_updates() -> anyhow::Result<()> {
+        let mut server = MockVoiceServer::start().await?;
+        let (mut client, gateway) = client(&server);
+
+        client.join(false, false).await?;
+        gateway.respond(gateway.requests()[0]);
+        let state = gateway.try_next_event().unwrap();
+        let voice_server = gateway.try_next_event().unwrap();
+        assert!(matches!(state, GatewayEvent::VoiceStateUpdate(_)));
+
+        state.clone().dispatch(&mut client).await?;
+        state.dispatch(&mut client).await?;
+        voice_server.dispatch(&mut client).await?;
+        assert!(identified(&mut server).await);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn abandons_join_when_disconnected() -> anyhow::Result<()> {
+        let mut server = MockVoiceServer::start().await?;
+        let (mut client, gateway) = client(&server);
+
+        client.join(false, false).await?;
+        gateway.disconnect();
+        // A late server update is ignored once disconnected.
+        gateway.change_server(Id::new(1), server.endpoint());
+        while let Some(event) = gateway.try_next_event() {
+            event.dispatch(&mut client).await?;
+        }
+
+        // Joining again starts over.
+        client.join(false, false).await?;
+        assert_eq!(gateway.requests().len(), 2);
+        gateway.respond(gateway.requests()[1]);
+        while let Some(event) = gateway.try_next_event() {
+            event.dispatch(&mut client).await?;
+        }
+        assert!(identified(&mut server).await);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_connection_state() -> anyhow::Result<()> {
+        let server = MockVoiceServer::start().await?;
+        let (mut client, gateway) = client(&server);
+        let mut state = client.watch_connection_state();
+        assert_eq!(*state.borrow(), ConnectionState::Disconnected);
+
+        client.join(false, false).await?;
+        gateway.respond(gateway.requests()[0]);
+        gateway
+            .try_next_event()
+            .unwrap()
+            .dispatch(&mut client)
             .await?;
+        assert_eq!(
+            client.connection_state(),
+            ConnectionState::AwaitingVoiceUpdates {
+                server_received: false,
+                state_received: true,
+            }
+        );
+
+        gateway
+            .try_next_event()
+            .unwrap()
+            .dispatch(&mut client)
+            .await?;
+        let connected = state.wait_for(|state| *state == ConnectionState::Connected);
+        time::timeout(Duration::from_secs(5), connected).await??;
+
+        // The default reconnect policy gives up once removed from the channel.
+        server.close(Some(CloseCode::Disconnected));
+        let disconnected = state.wait_for(|state| *state == ConnectionState::Disconnected);
+        time::timeout(Duration::from_secs(5), disconnected).await??;
+        client.join(false, false).await?;
 
         Ok(())
     }
@@ -0,0 +1,199 @@
+//! Opus encoding and decoding.
+//!
+//! The [`OpusEncoder`] and [`OpusDecoder`] are backed by libopus and require the `opus` feature.
+//! Without it, Opus audio can still be passed through untouched, but any attempt to encode or
+//! decode returns [`Error::OpusUnavailable`].
+//!
+//! [`Error::OpusUnavailable`]: crate::Error::OpusUnavailable
+
+use crate::{constants, Result};
+
+/// An Opus packet holding one frame of digital silence.
+pub const SILENCE_FRAME: [u8; 3] = [0xF8, 0xFF, 0xFE];
+
+/// Largest Opus packet the encoder will produce.
+pub const MAX_PACKET_SIZE: usize = 1275;
+
+/// Number of 48 kHz samples per channel held in an Opus packet, read from its TOC byte.
+///
+/// Returns `None` for malformed packets.
+pub fn packet_samples(packet: &[u8]) -> Option<usize> {
+    let toc = *packet.first()?;
+    let config = toc >> 3;
+
+    // Frame lengths in units of 2.5 ms.
+    let frame = match config {
+        0..=11 => [4, 8, 16, 24][usize::from(config & 3)],
+        12..=15 => [4, 8][usize::from(config & 1)],
+        _ => [1, 2, 4, 8][usize::from(config & 3)],
+    };
+    let frames = match toc & 3 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => usize::from(*packet.get(1)? & 0x3F),
+    };
+
+    let samples = frames * frame * (constants::SAMPLE_RATE as usize / 400);
+    // Packets may hold at most 120 ms of audio.
+    (frames > 0 && samples <= constants::SAMPLE_RATE as usize * 3 / 25).then_some(samples)
+}
+
+#[cfg(feature = "opus")]
+mod imp {
+    use audiopus::{
+        coder::{Decoder, Encoder},
+        packet::Packet,
+        Application, Channels, MutSignals, SampleRate,
+    };
+
+    use super::MAX_PACKET_SIZE;
+    use crate::{constants, Error, Result};
+
+    /// Encoder producing 20 ms stereo Opus packets from 48 kHz PCM.
+    pub struct OpusEncoder(Encoder);
+
+    impl OpusEncoder {
+        pub fn new() -> Result<Self> {
+            Ok(Self(Encoder::new(
+                SampleRate::Hz48000,
+                Channels::Stereo,
+                Application::Audio,
+            )?))
+        }
+
+        /// Encode one frame of interleaved stereo samples into `out`.
+        pub fn encode(&mut self, pcm: &[f32], out: &mut Vec<u8>) -> Result<()> {
+            out.resize(MAX_PACKET_SIZE, 0);
+            let len = self.0.encode_float(pcm, out)?;
+            out.truncate(len);
+
+            Ok(())
+        }
+    }
+
+    /// Decoder producing 48 kHz PCM from Opus packets.
+    pub struct OpusDecoder {
+        inner: Decoder,
+        channels: usize,
+    }
+
+    impl OpusDecoder {
+        pub fn new(channels: u16) -> Result<Self> {
+            let layout = match channels {
+                1 => Channels::Mono,
+                2 => Channels::Stereo,
+                _ => {
+                    return Err(Error::UnsupportedFormat(format!(
+                        "Opus stream with {channels} channels"
+                    )))
+                }
+            };
+
+            Ok(Self {
+                inner: Decoder::new(SampleRate::Hz48000, layout)?,
+                channels: channels.into(),
+            })
+        }
+
+        /// Decode `packet` into interleaved samples.
+        ///
+        /// Passing `None` conceals a lost packet of `samples` samples per channel. With `fec`,
+        /// `packet` must be the packet *following* the lost one, whose forward error correction
+        /// data is then used to rebuild the loss.
+        pub fn decode(
+            &mut self,
+            packet: Option<&[u8]>,
+            samples: usize,
+            fec: bool,
+        ) -> Result<Vec<f32>> {
+            let samples = samples.min(constants::SAMPLE_RATE as usize * 3 / 25);
+            let mut out = vec![0.0; samples * self.channels];
+            let packet = packet.map(Packet::try_from).transpose()?;
+            let len = self
+                .inner
+                .decode_float(packet, MutSignals::try_from(&mut out)?, fec)?;
+            out.truncate(len * self.channels);
+
+            Ok(out)
+        }
+    }
+}
+
+#[cfg(not(feature = "opus"))]
+mod imp {
+    use crate::{Error, Result};
+
+    /// Encoder producing 20 ms stereo Opus packets from 48 kHz PCM.
+    pub struct OpusEncoder(());
+
+    impl OpusEncoder {
+        pub fn new() -> Result<Self> {
+            Err(Error::OpusUnavailable)
+        }
+
+        /// Encode one frame of interleaved stereo samples into `out`.
+        pub fn encode(&mut self, _pcm: &[f32], _out: &mut Vec<u8>) -> Result<()> {
+            Err(Error::OpusUnavailable)
+        }
+    }
+
+    /// Decoder producing 48 kHz PCM from Opus packets.
+    pub struct OpusDecoder(());
+
+    impl OpusDecoder {
+        pub fn new(_channels: u16) -> Result<Self> {
+            Err(Error::OpusUnavailable)
+        }
+
+        /// Decode `packet` into interleaved samples.
+        ///
+        /// Passing `None` conceals a lost packet of `samples` samples per channel. With `fec`,
+        /// `packet` must be the packet *following* the lost one, whose forward error correction
+        /// data is then used to rebuild the loss.
+        pub fn decode(
+            &mut self,
+            _packet: Option<&[u8]>,
+            _samples: usize,
+            _fec: bool,
+        ) -> Result<Vec<f32>> {
+            Err(Error::OpusUnavailable)
+        }
+    }
+}
+
+pub use self::imp::{OpusDecoder, OpusEncoder};
+
+/// Convenience wrapper for encoders which are only needed once PCM audio turns up.
+pub(crate) fn encoder(slot: &mut Option<OpusEncoder>) -> Result<&mut OpusEncoder> {
+    match slot {
+        Some(encoder) => Ok(encoder),
+        None => Ok(slot.insert(OpusEncoder::new()?)),
+    }
+}
+
+/// Like [`encoder`], for stereo decoders.
+pub(crate) fn decoder(slot: &mut Option<OpusDecoder>) -> Result<&mut OpusDecoder> {
+    match slot {
+        Some(decoder) => Ok(decoder),
+        None => Ok(slot.insert(OpusDecoder::new(constants::CHANNELS)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{packet_samples, SILENCE_FRAME};
+
+    #[test]
+    fn packet_durations() {
+        assert_eq!(packet_samples(&SILENCE_FRAME), Some(960));
+        // SILK 60 ms, one frame.
+        assert_eq!(packet_samples(&[0x18]), Some(2880));
+        // CELT 2.5 ms, two frames.
+        assert_eq!(packet_samples(&[0x81]), Some(240));
+        // CELT 20 ms, code 3 with three frames.
+        assert_eq!(packet_samples(&[0xFB, 0x03]), Some(2880));
+        // Code 3 exceeding 120 ms.
+        assert_eq!(packet_samples(&[0xFB, 0x07]), None);
+        assert_eq!(packet_samples(&[]), None);
+    }
+}
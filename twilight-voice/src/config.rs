@@ -0,0 +1,259 @@
+//! Settings of a voice connection, fixed when a [`VoiceClient`] is created.
+//!
+//! [`VoiceClient`]: crate::client::VoiceClient
+
+use std::{fmt, net::IpAddr, sync::Arc, time::Duration};
+
+use twilight_voice_model::{constants::GATEWAY_VERSION, CloseCode};
+
+use crate::{crypto::CryptoMode, Error};
+
+/// Decides whether and when to retry after a voice connection is lost.
+///
+/// Implement this to replace the default [`ReconnectPolicy`].
+pub trait ReconnectStrategy: fmt::Debug + Send + Sync {
+    /// Time to wait before the attempt numbered `attempt`, counting from 0, to recover from
+    /// `error`, or `None` to give up.
+    fn delay(&self, attempt: u32, error: &Error) -> Option<Duration>;
+}
+
+/// Retry a limited number of times with exponential backoff and jitter, giving up straight
+/// away on close codes which another attempt would not fix.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReconnectPolicy {
+    max_attempts: u32,
+    initial_delay: Duration,
+    max_delay: Duration,
+    give_up_on: Vec<CloseCode>,
+}
+
+impl ReconnectPolicy {
+    /// Give up on a lost connection straight away.
+    pub fn never() -> Self {
+        Self::new(0, Duration::ZERO, Duration::ZERO)
+    }
+
+    /// Make up to `max_attempts` attempts to reconnect, waiting around `initial_delay` before
+    /// the second and doubling the wait after each failure up to `max_delay`.
+    ///
+    /// Each wait is randomly shortened by up to half, so that many connections lost at once do
+    /// not all retry at once.
+    pub fn new(max_attempts: u32, initial_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            max_attempts,
+            initial_delay,
+            max_delay: max_delay.max(initial_delay),
+            give_up_on: vec![
+                CloseCode::Disconnected,
+                CloseCode::UnknownProtocol,
+                CloseCode::UnknownEncryptionMode,
+            ],
+        }
+    }
+
+    /// Give up when the voice gateway closes with any of `codes`, defaulting to
+    /// [`CloseCode::Disconnected`], [`CloseCode::UnknownProtocol`] and
+    /// [`CloseCode::UnknownEncryptionMode`].
+    pub fn with_give_up_on(mut self, codes: impl IntoIterator<Item = CloseCode>) -> Self {
+        self.give_up_on = codes.into_iter().collect();
+        self
+    }
+
+    /// Longest time to wait before the attempt numbered `attempt`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        match attempt {
+            0 => Duration::ZERO,
+            _ => self
+                .initial_delay
+                .saturating_mul(2u32.saturating_pow(attempt - 1))
+                .min(self.max_delay),
+        }
+    }
+}
+
+impl ReconnectStrategy for ReconnectPolicy {
+    fn delay(&self, attempt: u32, error: &Error) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        match error {
+            Error::GatewayClosed(Some(code)) if self.give_up_on.contains(code) => return None,
+            Error::NoCompatibleCryptoMode(_) => return None,
+            _ => {}
+        }
+
+        let backoff = self.backoff(attempt);
+        let jitter = backoff.mul_f64(rand::random::<f64>() / 2.0);
+
+        Some(backoff - jitter)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    /// Five attempts, waiting up to 1, 2, 4 and 8 seconds between them.
+    fn default() -> Self {
+        Self::new(5, Duration::from_secs(1), Duration::from_secs(30))
+    }
+}
+
+/// Settings of a voice connection.
+///
+/// Every setting has a default suited to most bots, so only those which need changing have to
+/// be set.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub(crate) gateway_version: u8,
+    pub(crate) connect_timeout: Duration,
+    pub(crate) ip_discovery_timeout: Duration,
+    pub(crate) heartbeat_interval: Duration,
+    pub(crate) bind_address: Option<IpAddr>,
+    pub(crate) crypto_modes: Vec<CryptoMode>,
+    pub(crate) max_message_size: Option<usize>,
+    pub(crate) max_packet_size: usize,
+    pub(crate) event_capacity: usize,
+    pub(crate) reconnect: Arc<dyn ReconnectStrategy>,
+    /// Whether to connect to the voice gateway over TLS, only turned off for the mock server.
+    pub(crate) gateway_tls: bool,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Version of the voice gateway to connect to, defaulting to [`GATEWAY_VERSION`].
+    ///
+    /// Other versions may send payloads this library does not understand.
+    pub fn with_gateway_version(mut self, version: u8) -> Self {
+        self.gateway_version = version;
+        self
+    }
+
+    /// Time allowed to open the voice gateway websocket and receive `Hello`, defaulting to
+    /// 10 seconds.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Time allowed for the voice server to answer IP discovery, defaulting to 5 seconds.
+    pub fn with_ip_discovery_timeout(mut self, timeout: Duration) -> Self {
+        self.ip_discovery_timeout = timeout;
+        self
+    }
+
+    /// Interval to heartbeat at if the voice gateway does not request a valid one, defaulting
+    /// to 5 seconds and at least 1 millisecond.
+    pub fn with_heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval.max(Duration::from_millis(1));
+        self
+    }
+
+    /// Local address to bind the UDP socket to.
+    ///
+    /// By default the unspecified address of the voice server's family is used, so `0.0.0.0`
+    /// for an IPv4 server and `::` for an IPv6 one. The port is always chosen by the system.
+    pub fn with_bind_address(mut self, address: IpAddr) -> Self {
+        self.bind_address = Some(address);
+        self
+    }
+
+    /// Encryption modes to accept, from most to least preferred, defaulting to
+    /// [`CryptoMode::ALL`].
+    pub fn with_crypto_modes(mut self, modes: impl IntoIterator<Item = CryptoMode>) -> Self {
+        self.crypto_modes = modes.into_iter().collect();
+        self
+    }
+
+    /// Largest websocket message accepted from the voice gateway, defaulting to 1 MiB. `None`
+    /// removes the limit.
+    pub fn with_max_message_size(mut self, size: Option<usize>) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Largest UDP packet accepted from the voice server, defaulting to 1500 bytes.
+    pub fn with_max_packet_size(mut self, size: usize) -> Self {
+        self.max_packet_size = size;
+        self
+    }
+
+    /// Number of events buffered for each subscriber to [`VoiceClient::events`] before the
+    /// oldest are dropped, defaulting to 64.
+    ///
+    /// [`VoiceClient::events`]: crate::client::VoiceClient::events
+    pub fn with_event_capacity(mut self, capacity: usize) -> Self {
+        self.event_capacity = capacity.max(1);
+        self
+    }
+
+    /// How to recover when the connection to the voice server is lost, defaulting to
+    /// [`ReconnectPolicy::default`].
+    pub fn with_reconnect_strategy(mut self, strategy: impl ReconnectStrategy + 'static) -> Self {
+        self.reconnect = Arc::new(strategy);
+        self
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            gateway_version: GATEWAY_VERSION,
+            connect_timeout: Duration::from_secs(10),
+            ip_discovery_timeout: Duration::from_secs(5),
+            heartbeat_interval: Duration::from_secs(5),
+            bind_address: None,
+            crypto_modes: CryptoMode::ALL.to_vec(),
+            max_message_size: Some(1 << 20),
+            max_packet_size: 1500,
+            event_capacity: 64,
+            reconnect: Arc::new(ReconnectPolicy::default()),
+            gateway_tls: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use twilight_voice_model::CloseCode;
+
+    use super::{Config, ReconnectPolicy, ReconnectStrategy};
+    use crate::Error;
+
+    #[test]
+    fn backs_off_with_jitter() {
+        let policy = ReconnectPolicy::new(5, Duration::from_secs(1), Duration::from_secs(5));
+        let error = Error::GatewayClosed(None);
+
+        for (attempt, max) in [(0, 0), (1, 1000), (2, 2000), (3, 4000), (4, 5000)] {
+            let delay = policy.delay(attempt, &error).unwrap().as_millis();
+            assert!(
+                max / 2 <= delay && delay <= max,
+                "{delay} for attempt {attempt}"
+            );
+        }
+        assert_eq!(policy.delay(5, &error), None);
+    }
+
+    #[test]
+    fn heartbeats_at_least_every_millisecond() {
+        let config = Config::default().with_heartbeat_interval(Duration::ZERO);
+        assert_eq!(config.heartbeat_interval, Duration::from_millis(1));
+    }
+
+    #[test]
+    fn gives_up_on_close_codes() {
+        let policy = ReconnectPolicy::default();
+
+        let kicked = Error::GatewayClosed(Some(CloseCode::Disconnected));
+        assert_eq!(policy.delay(0, &kicked), None);
+        let crashed = Error::GatewayClosed(Some(CloseCode::VoiceServerCrash));
+        assert!(policy.delay(0, &crashed).is_some());
+
+        let policy = policy.with_give_up_on([CloseCode::VoiceServerCrash]);
+        assert!(policy.delay(0, &kicked).is_some());
+        assert_eq!(policy.delay(0, &crashed), None);
+    }
+}
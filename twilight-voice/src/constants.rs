@@ -0,0 +1,25 @@
+//! A set of audio constants used by the library.
+
+use std::time::Duration;
+
+/// Sample rate of all audio exchanged with Discord.
+pub const SAMPLE_RATE: u32 = 48_000;
+
+/// Number of channels of all audio exchanged with Discord.
+pub const CHANNELS: u16 = 2;
+
+/// Length of a single Opus frame sent or received over RTP.
+pub const FRAME_DURATION: Duration = Duration::from_millis(20);
+
+/// Number of frames in one second of audio.
+pub const FRAMES_PER_SECOND: u32 = 1000 / FRAME_DURATION.as_millis() as u32;
+
+/// Number of samples in one frame of a single channel.
+pub const MONO_FRAME_SIZE: usize = (SAMPLE_RATE / FRAMES_PER_SECOND) as usize;
+
+/// Number of interleaved samples in one stereo frame.
+pub const STEREO_FRAME_SIZE: usize = MONO_FRAME_SIZE * CHANNELS as usize;
+
+/// Number of silent frames sent after audio stops, so that receivers do not interpolate across
+/// the gap.
+pub const SILENCE_FRAMES: u8 = 5;
@@ -0,0 +1,317 @@
+//! Encryption of RTP payloads exchanged with Discord's voice servers.
+
+use std::{fmt, str::FromStr};
+
+use chacha20poly1305::XChaCha20Poly1305;
+use crypto_secretbox::{
+    aead::{AeadInPlace, KeyInit},
+    Nonce, Tag, XSalsa20Poly1305,
+};
+use rand::RngCore;
+use twilight_voice_model::EncryptionMode;
+
+use crate::{Error, Result};
+
+/// Length of the fixed RTP header which prefixes every voice packet.
+pub const RTP_HEADER_LEN: usize = 12;
+/// Length of the RTCP header left unencrypted at the start of each RTCP packet.
+pub const RTCP_HEADER_LEN: usize = 8;
+
+const TAG_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const LITE_NONCE_LEN: usize = 4;
+
+/// Voice packet encryption scheme negotiated with the voice server.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum CryptoMode {
+    /// XSalsa20Poly1305 keyed by the RTP header.
+    Normal,
+    /// XSalsa20Poly1305 with a random nonce appended to each packet.
+    Suffix,
+    /// XSalsa20Poly1305 with an incrementing 32-bit nonce appended to each packet.
+    Lite,
+    /// AEAD XChaCha20Poly1305 authenticating the RTP header, with an incrementing 32-bit nonce
+    /// appended to each packet.
+    XChaCha20Poly1305Rtpsize,
+}
+
+impl CryptoMode {
+    /// All supported modes, from most to least preferred.
+    pub const ALL: [Self; 4] = [
+        Self::XChaCha20Poly1305Rtpsize,
+        Self::Lite,
+        Self::Suffix,
+        Self::Normal,
+    ];
+
+    /// Name of this mode in voice gateway payloads.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Normal => "xsalsa20_poly1305",
+            Self::Suffix => "xsalsa20_poly1305_suffix",
+            Self::Lite => "xsalsa20_poly1305_lite",
+            Self::XChaCha20Poly1305Rtpsize => "aead_xchacha20_poly1305_rtpsize",
+        }
+    }
+
+    /// Choose the most preferred of `preferred` which the server offers in `offered`.
+    pub fn negotiate(preferred: &[Self], offered: &[String]) -> Result<Self> {
+        preferred
+            .iter()
+            .copied()
+            .find(|mode| offered.iter().any(|name| name == mode.name()))
+            .ok_or_else(|| Error::NoCompatibleCryptoMode(offered.to_vec()))
+    }
+
+    /// Number of bytes appended to each payload to carry its nonce.
+    const fn nonce_len(self) -> usize {
+        match self {
+            Self::Normal => 0,
+            Self::Suffix => NONCE_LEN,
+            Self::Lite | Self::XChaCha20Poly1305Rtpsize => LITE_NONCE_LEN,
+        }
+    }
+
+    /// Number of bytes added to each payload by encryption.
+    pub const fn overhead(self) -> usize {
+        TAG_LEN + self.nonce_len()
+    }
+}
+
+impl fmt::Display for CryptoMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl From<CryptoMode> for EncryptionMode {
+    fn from(mode: CryptoMode) -> Self {
+        match mode {
+            CryptoMode::Normal => Self::XSalsa20Poly1305,
+            CryptoMode::Suffix => Self::XSalsa20Poly1305Suffix,
+            CryptoMode::Lite => Self::XSalsa20Poly1305Lite,
+            CryptoMode::XChaCha20Poly1305Rtpsize => Self::AeadXChaCha20Poly1305Rtpsize,
+        }
+    }
+}
+
+impl FromStr for CryptoMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|mode| mode.name() == s)
+            .ok_or_else(|| Error::NoCompatibleCryptoMode(vec![s.to_owned()]))
+    }
+}
+
+enum Key {
+    XSalsa20(Box<XSalsa20Poly1305>),
+    XChaCha20(Box<XChaCha20Poly1305>),
+}
+
+/// Session cipher used to seal outgoing and open incoming RTP packets.
+pub struct Cipher {
+    mode: CryptoMode,
+    key: Key,
+    nonce: u32,
+}
+
+impl Cipher {
+    pub fn new(mode: CryptoMode, secret_key: &[u8]) -> Result<Self> {
+        let key = match mode {
+            CryptoMode::XChaCha20Poly1305Rtpsize => Key::XChaCha20(Box::new(
+                XChaCha20Poly1305::new_from_slice(secret_key).map_err(|_| Error::Crypto)?,
+            )),
+            _ => Key::XSalsa20(Box::new(
+                XSalsa20Poly1305::new_from_slice(secret_key).map_err(|_| Error::Crypto)?,
+            )),
+        };
+
+        Ok(Self {
+            mode,
+            key,
+            nonce: 0,
+        })
+    }
+
+    pub fn mode(&self) -> CryptoMode {
+        self.mode
+    }
+
+    /// Encrypt the payload following the RTP header at the start of `packet`, in place.
+    pub fn encrypt(&mut self, packet: &mut Vec<u8>) -> Result<()> {
+        self.seal(packet, RTP_HEADER_LEN)
+    }
+
+    /// Encrypt the body following the header of the RTCP packet in `packet`, in place.
+    pub fn encrypt_rtcp(&mut self, packet: &mut Vec<u8>) -> Result<()> {
+        self.seal(packet, RTCP_HEADER_LEN)
+    }
+
+    fn seal(&mut self, packet: &mut Vec<u8>, header_len: usize) -> Result<()> {
+        let mut nonce = Nonce::default();
+        let (header, payload) = packet.split_at(header_len);
+        let mut buffer = payload.to_vec();
+
+        match self.mode {
+            CryptoMode::Normal => nonce[..header_len].copy_from_slice(header),
+            CryptoMode::Suffix => rand::thread_rng().fill_bytes(&mut nonce),
+            CryptoMode::Lite | CryptoMode::XChaCha20Poly1305Rtpsize => {
+                nonce[..LITE_NONCE_LEN].copy_from_slice(&self.nonce.to_be_bytes());
+                self.nonce = self.nonce.wrapping_add(1);
+            }
+        }
+
+        let tag = match &self.key {
+            Key::XSalsa20(key) => key.encrypt_in_place_detached(&nonce, b"", &mut buffer),
+            Key::XChaCha20(key) => key.encrypt_in_place_detached(&nonce, header, &mut buffer),
+        }
+        .map_err(|_| Error::Crypto)?;
+
+        packet.truncate(header_len);
+        packet.reserve(buffer.len() + self.mode.overhead());
+        match self.mode {
+            // Secretbox places the tag before the ciphertext, AEAD modes after it.
+            CryptoMode::XChaCha20Poly1305Rtpsize => {
+                packet.extend_from_slice(&buffer);
+                packet.extend_from_slice(&tag);
+            }
+            _ => {
+                packet.extend_from_slice(&tag);
+                packet.extend_from_slice(&buffer);
+            }
+        }
+        packet.extend_from_slice(&nonce[..self.mode.nonce_len()]);
+
+        Ok(())
+    }
+
+    /// Decrypt an RTP packet whose unencrypted header spans `header_len` bytes, returning its
+    /// payload.
+    ///
+    /// For `rtpsize` modes, `header_len` must include the 4-byte RTP extension header if one is
+    /// present, and the returned payload then begins with the extension body.
+    pub fn decrypt(&self, packet: &[u8], header_len: usize) -> Result<Vec<u8>> {
+        let header_len = match self.mode {
+            CryptoMode::XChaCha20Poly1305Rtpsize => header_len,
+            _ => RTP_HEADER_LEN,
+        };
+
+        self.open(packet, header_len)
+    }
+
+    /// Decrypt an RTCP packet, returning the body following its header.
+    pub fn decrypt_rtcp(&self, packet: &[u8]) -> Result<Vec<u8>> {
+        self.open(packet, RTCP_HEADER_LEN)
+    }
+
+    fn open(&self, packet: &[u8], header_len: usize) -> Result<Vec<u8>> {
+        let mode = self.mode;
+        if packet.len() < header_len + mode.overhead() {
+            return Err(Error::Crypto);
+        }
+
+        let (header, rest) = packet.split_at(header_len);
+        let (body, nonce_bytes) = rest.split_at(rest.len() - mode.nonce_len());
+
+        let mut nonce = Nonce::default();
+        match mode {
+            CryptoMode::Normal => nonce[..header_len].copy_from_slice(header),
+            _ => nonce[..nonce_bytes.len()].copy_from_slice(nonce_bytes),
+        }
+
+        let (tag, mut buffer) = match mode {
+            CryptoMode::XChaCha20Poly1305Rtpsize => {
+                let (ciphertext, tag) = body.split_at(body.len() - TAG_LEN);
+                (Tag::clone_from_slice(tag), ciphertext.to_vec())
+            }
+            _ => {
+                let (tag, ciphertext) = body.split_at(TAG_LEN);
+                (Tag::clone_from_slice(tag), ciphertext.to_vec())
+            }
+        };
+
+        match &self.key {
+            Key::XSalsa20(key) => key.decrypt_in_place_detached(&nonce, b"", &mut buffer, &tag),
+            Key::XChaCha20(key) => key.decrypt_in_place_detached(&nonce, header, &mut buffer, &tag),
+        }
+        .map_err(|_| Error::Crypto)?;
+
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use twilight_voice_model::EncryptionMode;
+
+    use super::{Cipher, CryptoMode, RTCP_HEADER_LEN, RTP_HEADER_LEN};
+    use crate::Error;
+
+    #[test]
+    fn negotiate_prefers_first_offered() {
+        let offered = vec![
+            "xsalsa20_poly1305".to_owned(),
+            "xsalsa20_poly1305_lite".to_owned(),
+        ];
+
+        assert_eq!(
+            CryptoMode::negotiate(&CryptoMode::ALL, &offered).unwrap(),
+            CryptoMode::Lite
+        );
+        assert!(matches!(
+            CryptoMode::negotiate(&[CryptoMode::Suffix], &offered),
+            Err(Error::NoCompatibleCryptoMode(_))
+        ));
+        assert_eq!(
+            "xsalsa20_poly1305".parse::<CryptoMode>().unwrap(),
+            CryptoMode::Normal
+        );
+    }
+
+    #[test]
+    fn names_match_encryption_modes() {
+        for mode in CryptoMode::ALL {
+            assert_eq!(EncryptionMode::from(mode).name(), mode.name());
+        }
+    }
+
+    #[test]
+    fn round_trip_all_modes() {
+        let payload = [0xF8, 0xFF, 0xFE, 1, 2, 3];
+
+        for mode in CryptoMode::ALL {
+            let mut cipher = Cipher::new(mode, &[7; 32]).unwrap();
+            let mut packet = vec![0x80, 0x78, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+            packet.extend_from_slice(&payload);
+
+            cipher.encrypt(&mut packet).unwrap();
+            assert_eq!(
+                packet.len(),
+                RTP_HEADER_LEN + payload.len() + mode.overhead()
+            );
+            assert_eq!(cipher.decrypt(&packet, RTP_HEADER_LEN).unwrap(), payload);
+
+            packet[RTP_HEADER_LEN + 1] ^= 1;
+            assert!(cipher.decrypt(&packet, RTP_HEADER_LEN).is_err());
+        }
+    }
+
+    #[test]
+    fn round_trip_rtcp() {
+        let body = [0, 0, 0, 1, 2, 3, 4, 5];
+
+        for mode in CryptoMode::ALL {
+            let mut cipher = Cipher::new(mode, &[7; 32]).unwrap();
+            let mut packet = vec![0x80, 0xC9, 0, 3, 0, 0, 0, 1];
+            packet.extend_from_slice(&body);
+
+            cipher.encrypt_rtcp(&mut packet).unwrap();
+            assert_eq!(&packet[..RTCP_HEADER_LEN], [0x80, 0xC9, 0, 3, 0, 0, 0, 1]);
+            assert_eq!(cipher.decrypt_rtcp(&packet).unwrap(), body);
+        }
+    }
+}
@@ -0,0 +1,973 @@
+//! Background task driving an established voice connection.
+//!
+//! The driver owns the voice gateway websocket and the UDP socket, keeps the gateway alive with
+//! heartbeats, negotiates encryption, and every 20 ms sends one frame of audio mixed from the
+//! current track of the [`TrackQueue`] and any tracks playing alongside it.
+//!
+//! Audio received from other users is decoded and handed to subscribers of
+//! [`VoiceClient::receive`], if there are any, and to the recording started with
+//! [`VoiceClient::start_recording`].
+//!
+//! While connected but idle, keepalives hold the UDP socket open. If the socket stops working,
+//! IP discovery is run again on a new one.
+//!
+//! When the connection to the voice server is lost, the driver resumes the session if the
+//! voice gateway allows, and otherwise requests a new one through [`VoiceUpdate`], retrying as
+//! the [`ReconnectStrategy`] allows. A new voice server sent while connected is moved to
+//! straight away.
+//!
+//! RTCP receiver reports from the voice server are kept as statistics, and every few seconds
+//! reception of the audio we receive is measured and optionally sent in a sender report.
+//!
+//! Speaking is announced before the first frame of audio is sent. Once audio stops, a few
+//! frames of silence are sent before speaking is cleared again.
+//!
+//! While self-muted, tracks play on without their audio being sent. While self-deafened,
+//! received audio is neither decrypted nor decoded.
+//!
+//! [`VoiceClient::receive`]: crate::client::VoiceClient::receive
+//! [`VoiceClient::start_recording`]: crate::client::VoiceClient::start_recording
+//! [`VoiceUpdate`]: crate::client::VoiceUpdate
+//! [`ReconnectStrategy`]: crate::config::ReconnectStrategy
+
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use futures_util::StreamExt;
+use tokio::{
+    sync::{broadcast, mpsc, watch},
+    task::JoinHandle,
+    time::{self, Interval, MissedTickBehavior},
+};
+use twilight_model::{
+    gateway::payload::incoming::VoiceServerUpdate,
+    id::{
+        marker::{ChannelMarker, GuildMarker},
+        Id,
+    },
+};
+use twilight_voice_model::{payload::Ready, Event, SpeakingState};
+
+use crate::{
+    client::{ConnectionState, PartialVoiceStateUpdate, VoiceUpdate},
+    codec::SILENCE_FRAME,
+    config::Config,
+    constants,
+    crypto::{Cipher, CryptoMode},
+    events::VoiceEvent,
+    gateway::DiscordVoiceClient,
+    input::{AudioFormat, AudioFrame},
+    mixer::{MasterVolume, Mixer},
+    queue::TrackQueue,
+    receive::{AudioReceiver, Speakers},
+    record::Recorder,
+    rtcp::{self, Reception, Rtcp},
+    stats::Stats,
+    track::{PendingTracks, Track, TrackHandle, TrackState},
+    voice::{DiscordVoiceConnection, KEEPALIVE_INTERVAL},
+    Error, Result,
+};
+
+/// Flags announced with `Speaking` while audio is sent, shared between a [`VoiceClient`] and
+/// its driver.
+///
+/// [`VoiceClient`]: crate::client::VoiceClient
+#[derive(Clone)]
+pub(crate) struct SpeakingFlags(Arc<AtomicU8>);
+
+impl SpeakingFlags {
+    pub fn get(&self) -> SpeakingState {
+        SpeakingState::from_bits_truncate(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, state: SpeakingState) {
+        self.0.store(state.bits(), Ordering::Relaxed);
+    }
+}
+
+impl Default for SpeakingFlags {
+    fn default() -> Self {
+        Self(Arc::new(AtomicU8::new(SpeakingState::MICROPHONE.bits())))
+    }
+}
+
+/// Whether we are self-muted and self-deafened, shared between a [`VoiceClient`] and its
+/// driver.
+///
+/// [`VoiceClient`]: crate::client::VoiceClient
+#[derive(Clone, Default)]
+pub(crate) struct MuteFlags {
+    mute: Arc<AtomicBool>,
+    deaf: Arc<AtomicBool>,
+}
+
+impl MuteFlags {
+    pub fn is_mute(&self) -> bool {
+        self.mute.load(Ordering::Relaxed)
+    }
+
+    pub fn set_mute(&self, mute: bool) {
+        self.mute.store(mute, Ordering::Relaxed);
+    }
+
+    pub fn is_deaf(&self) -> bool {
+        self.deaf.load(Ordering::Relaxed)
+    }
+
+    pub fn set_deaf(&self, deaf: bool) {
+        self.deaf.store(deaf, Ordering::Relaxed);
+    }
+}
+
+/// Playback state owned by a [`VoiceClient`], which outlives any one driver.
+///
+/// [`VoiceClient`]: crate::client::VoiceClient
+#[derive(Clone)]
+pub(crate) struct Playback {
+    pub queue: TrackQueue,
+    /// Tracks to play alongside the queue.
+    pub pending: PendingTracks,
+    pub master: MasterVolume,
+    pub speaking: SpeakingFlags,
+    pub mute: MuteFlags,
+    pub events: broadcast::Sender<VoiceEvent>,
+    pub receive: AudioReceiver,
+    pub recorder: Recorder,
+    pub rtcp: Rtcp,
+    pub stats: Stats,
+    pub state: watch::Sender<ConnectionState>,
+    pub config: Arc<Config>,
+}
+
+impl Playback {
+    pub fn new(guild_id: Id<GuildMarker>, config: Config) -> Self {
+        Self {
+            queue: TrackQueue::default(),
+            pending: PendingTracks::default(),
+            master: MasterVolume::default(),
+            speaking: SpeakingFlags::default(),
+            mute: MuteFlags::default(),
+            events: broadcast::channel(config.event_capacity).0,
+            receive: AudioReceiver::default(),
+            recorder: Recorder::default(),
+            rtcp: Rtcp::default(),
+            stats: Stats::new(guild_id),
+            state: watch::channel(ConnectionState::Disconnected).0,
+            config: Arc::new(config),
+        }
+    }
+
+    /// Move the connection to `state`, notifying watchers if it changed.
+    pub fn set_state(&self, state: ConnectionState) {
+        self.state.send_if_modified(|current| {
+            let changed = *current != state;
+            *current = state;
+            changed
+        });
+    }
+}
+
+/// What the driver needs to request a new session when the current one cannot be resumed.
+pub(crate) struct Session {
+    pub voice_update: Arc<dyn VoiceUpdate>,
+    pub guild_id: Id<GuildMarker>,
+    pub channel_id: Id<ChannelMarker>,
+}
+
+/// Update from the Discord gateway forwarded to a running driver.
+enum SessionUpdate {
+    Server(VoiceServerUpdate),
+    State(PartialVoiceStateUpdate),
+}
+
+/// Handle to a running driver task, which is stopped when the handle is dropped.
+pub(crate) struct Driver {
+    task: JoinHandle<()>,
+    updates: mpsc::UnboundedSender<SessionUpdate>,
+}
+
+impl Driver {
+    pub fn spawn(gateway: DiscordVoiceClient, playback: Playback, session: Session) -> Self {
+        let max_packet_size = playback.config.max_packet_size;
+        playback
+            .stats
+            .set_endpoint(gateway.endpoint().map(Into::into));
+        let (updates, updates_rx) = mpsc::unbounded_channel();
+        let runner = Runner {
+            gateway,
+            session,
+            updates: updates_rx,
+            attempts: 0,
+            ready: None,
+            udp: None,
+            playback,
+            current: None,
+            tracks: Vec::new(),
+            mixer: Mixer::default(),
+            speaking: SpeakingState::empty(),
+            silence_frames: 0,
+            speakers: Speakers::default(),
+            reception: Reception::default(),
+            buffer: vec![0; max_packet_size],
+        };
+
+        let task = tokio::spawn(runner.run());
+
+        Self { task, updates }
+    }
+
+    /// Forward a `VoiceServerUpdate` received while connected, which moves the connection to
+    /// a new voice server.
+    pub fn update_server(&self, voice_server: VoiceServerUpdate) {
+        // The driver only stops once it has given up on the connection.
+        let _ = self.updates.send(SessionUpdate::Server(voice_server));
+    }
+
+    /// Forward a `VoiceStateUpdate` received while connected, for the next new session.
+    pub fn update_state(&self, voice_state: PartialVoiceStateUpdate) {
+        let _ = self.updates.send(SessionUpdate::State(voice_state));
+    }
+}
+
+impl Drop for Driver {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Whether the gateway session may be resumed after `error` ended the websocket connection.
+fn is_resumable(error: &Error) -> bool {
+    match error {
+        Error::WebSocket(_) | Error::GatewayClosed(None) => true,
+        Error::GatewayClosed(Some(code)) => code.can_resume(),
+        _ => false,
+    }
+}
+
+/// Wait for the next update forwarded from the Discord gateway.
+async fn next_update(updates: &mut mpsc::UnboundedReceiver<SessionUpdate>) -> SessionUpdate {
+    match updates.recv().await {
+        Some(update) => update,
+        // The handle is only dropped along with the task.
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait for a packet on the UDP socket, once there is one.
+async fn recv(udp: &Option<DiscordVoiceConnection>, buffer: &mut [u8]) -> Result<usize> {
+    match udp {
+        Some(udp) => udp.recv(buffer).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn heartbeat_interval(gateway: &DiscordVoiceClient) -> Interval {
+    let mut heartbeat = time::interval(gateway.heartbeat_interval());
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    heartbeat
+}
+
+/// Position of a playing track within the driver.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Slot {
+    /// The current track of the queue.
+    Queue,
+    /// A track playing alongside the queue, by index.
+    Overlay(usize),
+}
+
+/// A frame read from a playing track.
+struct Frame {
+    slot: Slot,
+    audio: AudioFrame,
+    /// Volume to play the frame at.
+    volume: f32,
+    /// Playback time covered by the frame.
+    duration: Duration,
+}
+
+impl Frame {
+    fn new(slot: Slot, audio: AudioFrame, handle: &TrackHandle) -> Self {
+        let duration = match &audio {
+            AudioFrame::Pcm(samples) => AudioFormat::DISCORD.duration_of(samples.len()),
+            AudioFrame::Opus(_) => constants::FRAME_DURATION,
+        };
+
+        Self {
+            slot,
+            audio,
+            volume: handle.advance_volume(),
+            duration,
+        }
+    }
+}
+
+struct Runner {
+    gateway: DiscordVoiceClient,
+    session: Session,
+    updates: mpsc::UnboundedReceiver<SessionUpdate>,
+    /// Attempts made to reconnect since the voice gateway last confirmed a session.
+    attempts: u32,
+    /// The voice server to send audio to, from the last `Ready`.
+    ready: Option<Ready>,
+    udp: Option<DiscordVoiceConnection>,
+    playback: Playback,
+    current: Option<Track>,
+    tracks: Vec<Track>,
+    mixer: Mixer,
+    /// Flags last announced with `Speaking`, empty while not sending audio.
+    speaking: SpeakingState,
+    /// Silent frames left to send after audio stopped.
+    silence_frames: u8,
+    speakers: Speakers,
+    reception: Reception,
+    /// Buffer for packets received over UDP.
+    buffer: Vec<u8>,
+}
+
+impl Runner {
+    /// Drive the connection, reconnecting whenever it is lost until the reconnect strategy
+    /// gives up.
+    async fn run(mut self) {
+        loop {
+            let Err(error) = self.run_session().await;
+            if let Err(error) = self.reconnect(error).await {
+                tracing::warn!(%error, "voice driver stopped");
+                self.playback.set_state(ConnectionState::Disconnected);
+                self.emit(VoiceEvent::Disconnected {
+                    error: Arc::new(error),
+                });
+                return;
+            }
+        }
+    }
+
+    /// Drive the current session until it fails.
+    async fn run_session(&mut self) -> Result<Infallible> {
+        let mut heartbeat = heartbeat_interval(&self.gateway);
+        let mut frames = time::interval(constants::FRAME_DURATION);
+        let mut reports = time::interval(rtcp::REPORT_INTERVAL);
+        let mut keepalive = time::interval(KEEPALIVE_INTERVAL / 5);
+
+        loop {
+            tokio::select! {
+                _ = heartbeat.tick() => self.gateway.send_heartbeat().await?,
+                message = self.gateway.websocket.next() => {
+                    if let Some(event) = self.gateway.process(message)? {
+                        self.handle_event(event).await?;
+                    }
+                }
+                update = next_update(&mut self.updates) => match update {
+                    // Repeated updates are no reason to reconnect.
+                    SessionUpdate::Server(voice_server)
+                        if voice_server == *self.gateway.voice_server() => {}
+                    SessionUpdate::Server(voice_server) => {
+                        tracing::info!("voice server changed, reconnecting");
+                        self.connect(voice_server).await?;
+                        heartbeat = heartbeat_interval(&self.gateway);
+                    }
+                    SessionUpdate::State(voice_state) => self.set_state(voice_state),
+                },
+                _ = frames.tick() => {
+                    self.send_frame().await?;
+                    self.playback.queue.buffer_next();
+                    self.play_received();
+                }
+                _ = reports.tick() => self.report().await?,
+                _ = keepalive.tick() => self.keepalive().await?,
+                len = recv(&self.udp, &mut self.buffer) => self.receive(len),
+            }
+        }
+    }
+
+    /// Recover from `error` ending the session, by resuming it if the voice gateway allows
+    /// and otherwise starting a new one, retrying as the reconnect strategy allows.
+    ///
+    /// Returns once the voice gateway was reached again. Attempts only count as successful
+    /// once it confirms the session with `Ready` or `Resumed`, so that a session closed
+    /// straight away does not start the count over.
+    async fn reconnect(&mut self, error: Error) -> Result<()> {
+        let strategy = self.playback.config.reconnect.clone();
+        tracing::info!(%error, "voice connection lost, reconnecting");
+
+        let mut error = error;
+        loop {
+            let attempt = self.attempts;
+            let Some(delay) = strategy.delay(attempt, &error) else {
+                return Err(error);
+            };
+            self.emit(VoiceEvent::Reconnecting { attempt, delay });
+            self.playback
+                .set_state(ConnectionState::Reconnecting { attempt });
+            time::sleep(delay).await;
+            self.attempts += 1;
+
+            let result = if is_resumable(&error) {
+                self.playback
+                    .set_state(ConnectionState::Resuming { attempt });
+                self.gateway.resume().await
+            } else {
+                self.new_session().await
+            };
+            match result {
+                Ok(()) => return Ok(()),
+                Err(reconnect_error) => {
+                    tracing::debug!(error = %reconnect_error, attempt, "failed to reconnect");
+                    error = reconnect_error;
+                }
+            }
+        }
+    }
+
+    /// Request a new session through the Discord gateway, and connect to the voice server it
+    /// assigns.
+    async fn new_session(&mut self) -> Result<()> {
+        // Anything sent before the request describes the old session.
+        while let Ok(update) = self.updates.try_recv() {
+            if let SessionUpdate::State(voice_state) = update {
+                self.set_state(voice_state);
+            }
+        }
+
+        let session = &self.session;
+        let mute = &self.playback.mute;
+        session
+            .voice_update
+            .update_voice_state(
+                session.guild_id,
+                Some(session.channel_id),
+                mute.is_deaf(),
+                mute.is_mute(),
+            )
+            .await?;
+
+        let voice_server = time::timeout(self.playback.config.connect_timeout, async {
+            loop {
+                match next_update(&mut self.updates).await {
+                    SessionUpdate::Server(voice_server) => break voice_server,
+                    SessionUpdate::State(voice_state) => self.set_state(voice_state),
+                }
+            }
+        })
+        .await
+        .map_err(|_| Error::ConnectTimeout)?;
+
+        self.connect(voice_server).await
+    }
+
+    /// Note our voice state, as sent by the Discord gateway.
+    fn set_state(&mut self, voice_state: PartialVoiceStateUpdate) {
+        // Without a channel we left or were removed, which the voice gateway reports too.
+        if let Some(channel_id) = voice_state.channel_id {
+            self.session.channel_id = channel_id;
+            self.gateway.set_voice_state(voice_state);
+        }
+    }
+
+    /// Start a new session with the voice server described by `voice_server`.
+    async fn connect(&mut self, voice_server: VoiceServerUpdate) -> Result<()> {
+        self.playback.set_state(ConnectionState::ConnectingGateway);
+        let voice_state = self.gateway.voice_state().clone();
+        let config = self.playback.config.clone();
+        self.gateway = DiscordVoiceClient::connect(voice_server, voice_state, config).await?;
+
+        self.playback
+            .stats
+            .set_endpoint(self.gateway.endpoint().map(Into::into));
+        self.ready = None;
+        self.udp = None;
+        self.speakers = Speakers::default();
+
+        Ok(())
+    }
+
+    /// Count the session confirmed by the voice gateway as a successful reconnect, if it
+    /// followed one.
+    fn confirm_session(&mut self) {
+        if self.attempts > 0 {
+            self.attempts = 0;
+            self.playback.stats.reconnected();
+            self.emit(VoiceEvent::Reconnected);
+        }
+    }
+
+    async fn handle_event(&mut self, event: Event) -> Result<()> {
+        match event {
+            Event::Ready(ready) => {
+                self.confirm_session();
+                self.ready = Some(ready);
+                self.connect_udp().await?;
+            }
+            Event::SessionDescription(description) => {
+                let udp = self.udp.as_mut().ok_or(Error::NotConnected)?;
+                let mode = description.mode.parse()?;
+
+                udp.set_cipher(Cipher::new(mode, &description.secret_key)?);
+                self.playback.stats.set_crypto_mode(mode);
+                self.playback.set_state(ConnectionState::Connected);
+            }
+            Event::Speaking(speaking) => {
+                if let Some(user_id) = speaking.user_id {
+                    self.speakers.set_user(speaking.ssrc, user_id);
+                }
+            }
+            Event::ClientDisconnect(disconnect) => self.speakers.remove_user(disconnect.user_id),
+            Event::HeartbeatAck(ack) => {
+                if let Some(rtt) = self.gateway.ack_heartbeat(ack.t) {
+                    self.playback.stats.heartbeat_acked(rtt);
+                }
+            }
+            Event::Resumed => {
+                tracing::debug!("voice gateway resumed");
+                self.confirm_session();
+                if self.udp.as_ref().is_some_and(|udp| udp.is_ready()) {
+                    self.playback.set_state(ConnectionState::Connected);
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Open a UDP socket to the voice server and select it as the protocol to use, after which
+    /// the gateway sends the session key in `SessionDescription`.
+    async fn connect_udp(&mut self) -> Result<()> {
+        let ready = self.ready.as_ref().ok_or(Error::NotConnected)?;
+        self.playback.set_state(ConnectionState::NegotiatingUdp);
+        let config = &self.playback.config;
+        let stats = self.playback.stats.clone();
+        let udp = DiscordVoiceConnection::connect(
+            ready.ip,
+            ready.port,
+            ready.ssrc,
+            config.bind_address,
+            stats,
+        )
+        .await?;
+        let address = udp.ip_discovery(config.ip_discovery_timeout).await?;
+        let mode = CryptoMode::negotiate(&config.crypto_modes, &ready.modes)?;
+
+        self.gateway.send_select_protocol(address, mode).await?;
+        self.playback.stats.set_ssrc(ready.ssrc);
+        self.udp = Some(udp);
+        self.reception.clear();
+        self.speaking = SpeakingState::empty();
+        self.silence_frames = 0;
+
+        Ok(())
+    }
+
+    /// Keep the UDP socket open while idle, replacing it if it has died.
+    async fn keepalive(&mut self) -> Result<()> {
+        let Some(udp) = &mut self.udp else {
+            return Ok(());
+        };
+
+        if udp.is_dead() {
+            tracing::info!("voice UDP socket stopped working, rerunning IP discovery");
+            self.udp = None;
+            self.playback.stats.reconnected();
+            return self.connect_udp().await;
+        }
+        if let Err(error) = udp.keepalive().await {
+            tracing::debug!(%error, "failed to send UDP keepalive");
+        }
+
+        Ok(())
+    }
+
+    /// Handle a packet of `len` bytes received over UDP, buffering its audio if anyone is
+    /// listening or recording.
+    fn receive(&mut self, len: Result<usize>) {
+        let Some(udp) = &mut self.udp else {
+            return;
+        };
+        udp.received(&len);
+        let len = match len {
+            Ok(len) => len,
+            Err(error) => {
+                tracing::debug!(%error, "failed to receive over UDP");
+                return;
+            }
+        };
+        let packet = &self.buffer[..len];
+
+        if rtcp::is_rtcp(packet) {
+            match udp.open_rtcp(packet) {
+                Ok(reports) => self.receive_rtcp(&reports),
+                Err(error) => tracing::debug!(%error, "dropped received RTCP packet"),
+            }
+            return;
+        }
+
+        self.reception.push(packet, Instant::now());
+        if self.playback.mute.is_deaf()
+            || !self.playback.receive.is_listening() && !self.playback.recorder.is_recording()
+        {
+            return;
+        }
+
+        let packet = match udp.open(packet) {
+            Ok(Some(packet)) => packet,
+            Ok(None) => return,
+            Err(error) => {
+                tracing::debug!(%error, "dropped received packet");
+                return;
+            }
+        };
+        if !self.speakers.push(packet, Instant::now()) {
+            tracing::trace!("dropped late voice packet");
+            self.playback.stats.packet_dropped();
+        }
+    }
+
+    fn receive_rtcp(&mut self, reports: &[rtcp::Report]) {
+        let Some(udp) = &self.udp else {
+            return;
+        };
+        let now = Instant::now();
+
+        for report in reports {
+            if let Some(sent_at) = report.sent_at {
+                self.reception.sender_report(report.ssrc, sent_at, now);
+            }
+        }
+        self.playback
+            .rtcp
+            .receive(reports, udp.ssrc(), rtcp::ntp_now());
+    }
+
+    /// Measure reception of the audio we receive, and send a sender report if enabled.
+    async fn report(&mut self) -> Result<()> {
+        let Some(udp) = &mut self.udp else {
+            return Ok(());
+        };
+
+        let blocks = self
+            .reception
+            .report(Instant::now(), self.playback.receive.speaker_timeout());
+        let rtcp = &self.playback.rtcp;
+        rtcp.set_inbound(&blocks);
+
+        if rtcp.sender_reports() && udp.is_ready() && udp.has_sent() {
+            match udp.send_sender_report(&blocks).await {
+                Ok(()) => rtcp.sent_sender_report(),
+                Err(error) => tracing::debug!(%error, "failed to send RTCP sender report"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decode the next frame of everyone speaking, and drop those who went quiet.
+    fn play_received(&mut self) {
+        let receive = &self.playback.receive;
+        self.speakers
+            .prune(Instant::now(), receive.speaker_timeout());
+
+        // Only while connected, so that the mixed stream's silence does not cover gaps in
+        // the connection.
+        let recorder = &self.playback.recorder;
+        let listening = receive.is_listening();
+        let recording = recorder.is_recording();
+        if !(listening || recording) || self.udp.is_none() || self.playback.mute.is_deaf() {
+            return;
+        }
+
+        let limits = receive.jitter_limits();
+        let frames = self
+            .speakers
+            .tick(limits, listening || recorder.needs_pcm());
+        let now = Instant::now();
+        let mixed = (receive.is_mixing() || recorder.needs_mixed())
+            .then(|| self.speakers.mix(&frames, now, limits));
+        if recording {
+            recorder.record(now, &frames, mixed.as_ref());
+        }
+        if listening {
+            receive.send(&frames, mixed);
+        }
+    }
+
+    fn emit(&self, event: VoiceEvent) {
+        // Nobody listening is not an error.
+        let _ = self.playback.events.send(event);
+    }
+
+    fn track(&mut self, slot: Slot) -> Option<&mut Track> {
+        match slot {
+            Slot::Queue => self.current.as_mut(),
+            Slot::Overlay(index) => self.tracks.get_mut(index),
+        }
+    }
+
+    /// Remove the track in `slot` and report that it ended, failing with `error` if given.
+    ///
+    /// Removing an overlay shifts those after it down by one.
+    fn end_track(&mut self, slot: Slot, error: Option<Arc<Error>>) {
+        let track = match slot {
+            Slot::Queue => self.current.take(),
+            Slot::Overlay(index) => Some(self.tracks.remove(index)),
+        };
+        let Some(track) = track else {
+            return;
+        };
+
+        track.handle.stop();
+        self.playback.queue.finish(&track.handle);
+        if let Some(error) = error {
+            tracing::warn!(%error, track = %track.handle.id(), "track failed");
+            self.emit(VoiceEvent::TrackError {
+                track: track.handle.clone(),
+                error,
+            });
+        }
+        self.emit(VoiceEvent::TrackEnd(track.handle));
+    }
+
+    /// Read the next frame of the track in `slot`, starting it over if it ends with loops left.
+    async fn read_track(&mut self, slot: Slot) -> Result<Option<Frame>> {
+        let Some(track) = self.track(slot) else {
+            return Ok(None);
+        };
+
+        let mut looped = false;
+        let audio = loop {
+            match track.read_frame().await? {
+                Some(audio) => break Some(audio),
+                // Only loop once per frame, in case the source has no audio at all.
+                None if !looped && track.handle.take_loop() => {
+                    track.restart().await?;
+                    looped = true;
+                }
+                None => break None,
+            }
+        };
+
+        let frame = audio.map(|audio| Frame::new(slot, audio, &track.handle));
+        if looped {
+            let handle = track.handle.clone();
+            self.emit(VoiceEvent::TrackLoop(handle));
+        }
+
+        Ok(frame)
+    }
+
+    /// Pull the next frame from the current track, moving through the queue as tracks end.
+    async fn next_queued_frame(&mut self) -> Option<Frame> {
+        loop {
+            if self.current.is_none() {
+                let track = self.playback.queue.next()?;
+                self.emit(VoiceEvent::TrackStart(track.handle.clone()));
+                self.current = Some(track);
+            }
+            let track = self.current.as_ref()?;
+
+            // Let a track fading out finish alongside the queue, so that the next one starts
+            // while it fades.
+            if track.handle.is_fading_out() {
+                let track = self.current.take()?;
+                self.playback.queue.finish(&track.handle);
+                self.tracks.push(track);
+                continue;
+            }
+
+            match track.handle.state() {
+                TrackState::Playing => {}
+                TrackState::Paused => return None,
+                TrackState::Ended => {
+                    self.end_track(Slot::Queue, None);
+                    continue;
+                }
+            }
+
+            match self.read_track(Slot::Queue).await {
+                Ok(Some(frame)) => return Some(frame),
+                Ok(None) => self.end_track(Slot::Queue, None),
+                Err(error) => self.end_track(Slot::Queue, Some(Arc::new(error))),
+            }
+        }
+    }
+
+    /// Pull the next frame from every playing track.
+    async fn read_frames(&mut self) -> Vec<Frame> {
+        for track in self.playback.pending.take() {
+            self.emit(VoiceEvent::TrackStart(track.handle.clone()));
+            self.tracks.push(track);
+        }
+
+        let mut frames = Vec::new();
+        if let Some(frame) = self.next_queued_frame().await {
+            frames.push(frame);
+        }
+
+        let mut index = 0;
+        while let Some(track) = self.tracks.get(index) {
+            let slot = Slot::Overlay(index);
+            match track.handle.state() {
+                TrackState::Playing => {}
+                TrackState::Paused => {
+                    index += 1;
+                    continue;
+                }
+                TrackState::Ended => {
+                    self.end_track(slot, None);
+                    continue;
+                }
+            }
+
+            match self.read_track(slot).await {
+                Ok(Some(frame)) => {
+                    frames.push(frame);
+                    index += 1;
+                }
+                Ok(None) => self.end_track(slot, None),
+                Err(error) => self.end_track(slot, Some(Arc::new(error))),
+            }
+        }
+
+        frames
+    }
+
+    async fn send_frame(&mut self) -> Result<()> {
+        if !self.udp.as_ref().is_some_and(|udp| udp.is_ready()) {
+            return Ok(());
+        }
+
+        let frames = self.read_frames().await;
+        // Tracks carry on while muted, without being heard.
+        if self.playback.mute.is_mute() {
+            for frame in &frames {
+                if let Some(track) = self.track(frame.slot) {
+                    track.handle.advance_position(frame.duration);
+                }
+            }
+            return self.send_silence().await;
+        }
+
+        let master = self.playback.master.get();
+        let (packet, played) = match frames.as_slice() {
+            [] => (Vec::new(), Vec::new()),
+            // A lone Opus track at full volume needs no mixing, so skip re-encoding it.
+            [Frame {
+                slot,
+                audio: AudioFrame::Opus(packet),
+                volume,
+                duration,
+            }] if *volume == 1.0 && self.mixer.is_unity(master) => match self.track(*slot) {
+                Some(track) if track.gain.unwrap_or(1.0) == 1.0 => {
+                    (packet.clone(), vec![(track.handle.clone(), *duration)])
+                }
+                _ => self.mix(frames, master),
+            },
+            _ => self.mix(frames, master),
+        };
+
+        if played.is_empty() {
+            return self.send_silence().await;
+        }
+
+        self.set_speaking(self.playback.speaking.get()).await?;
+        self.send_opus(&packet).await;
+        self.silence_frames = constants::SILENCE_FRAMES;
+        for (handle, duration) in played {
+            handle.advance_position(duration);
+        }
+
+        Ok(())
+    }
+
+    /// Send a packet of audio. Failures are left for the keepalive to act on if they persist.
+    async fn send_opus(&mut self, packet: &[u8]) {
+        if let Some(udp) = &mut self.udp {
+            if let Err(error) = udp.send_opus(packet).await {
+                tracing::debug!(%error, "failed to send voice packet");
+                self.playback.stats.frame_dropped();
+            }
+        }
+    }
+
+    /// Send one of the silent frames which follow the end of audio, announcing that speaking
+    /// has stopped after the last.
+    async fn send_silence(&mut self) -> Result<()> {
+        if self.silence_frames == 0 {
+            return Ok(());
+        }
+
+        self.send_opus(&SILENCE_FRAME).await;
+        self.silence_frames -= 1;
+        if self.silence_frames == 0 {
+            self.set_speaking(SpeakingState::empty()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Announce `state` over the gateway, unless it already was.
+    async fn set_speaking(&mut self, state: SpeakingState) -> Result<()> {
+        if self.speaking == state {
+            return Ok(());
+        }
+
+        let ssrc = self.udp.as_ref().ok_or(Error::NotConnected)?.ssrc();
+        self.gateway.send_speaking(state, ssrc).await?;
+        self.speaking = state;
+
+        Ok(())
+    }
+
+    /// Mix and encode `frames`, ending any tracks which could not be mixed.
+    ///
+    /// Returns the packet to send, and the tracks it holds audio from with the duration of
+    /// their frames.
+    fn mix(&mut self, frames: Vec<Frame>, master: f32) -> (Vec<u8>, Vec<(TrackHandle, Duration)>) {
+        let mut failed = Vec::new();
+        let mut played = Vec::new();
+
+        self.mixer.clear();
+        for frame in frames {
+            let Some(track) = self.track(frame.slot) else {
+                continue;
+            };
+            match track.decode(frame.audio) {
+                Ok(samples) => {
+                    let from = track.gain.replace(frame.volume).unwrap_or(frame.volume);
+                    played.push((frame.slot, track.handle.clone(), frame.duration));
+                    self.mixer.add(&samples, from, frame.volume);
+                }
+                Err(error) => failed.push((frame.slot, Arc::new(error))),
+            }
+        }
+
+        let packet = match self.mixer.encode(master) {
+            Ok(packet) => packet.to_vec(),
+            Err(error) => {
+                // Without an encoder none of the tracks can be played.
+                let error = Arc::new(error);
+                failed.extend(played.drain(..).map(|(slot, ..)| (slot, error.clone())));
+                Vec::new()
+            }
+        };
+
+        // End overlays from the back, so that the indices of the others stay valid.
+        failed.sort_by_key(|(slot, _)| std::cmp::Reverse(*slot));
+        for (slot, error) in failed {
+            self.end_track(slot, Some(error));
+        }
+
+        let played = played
+            .into_iter()
+            .map(|(_, handle, duration)| (handle, duration))
+            .collect();
+
+        (packet, played)
+    }
+}
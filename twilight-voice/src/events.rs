@@ -0,0 +1,32 @@
+//! Notifications about playback on a voice connection, and about the connection itself.
+
+use std::{sync::Arc, time::Duration};
+
+use crate::{track::TrackHandle, Error};
+
+/// An event emitted by a [`VoiceClient`], received through [`VoiceClient::events`].
+///
+/// [`VoiceClient`]: crate::client::VoiceClient
+/// [`VoiceClient::events`]: crate::client::VoiceClient::events
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum VoiceEvent {
+    /// A track began playing.
+    TrackStart(TrackHandle),
+    /// A track reached its end and started over, with loops left.
+    TrackLoop(TrackHandle),
+    /// A track finished, or was stopped or skipped.
+    TrackEnd(TrackHandle),
+    /// A track was ended because its audio could not be read or encoded.
+    TrackError {
+        track: TrackHandle,
+        error: Arc<Error>,
+    },
+    /// The connection to the voice server was lost, and attempt number `attempt`, counting
+    /// from 0, to restore it starts after `delay`.
+    Reconnecting { attempt: u32, delay: Duration },
+    /// The connection was restored, by resuming the session or starting a new one.
+    Reconnected,
+    /// The connection was lost for good, after the reconnect strategy gave up.
+    Disconnected { error: Arc<Error> },
+}
@@ -1,38 +1,104 @@
+use std::{net::SocketAddr, sync::Arc};
+
 use futures_util::{SinkExt, StreamExt};
-use tokio::{net::TcpStream, time::Duration};
+use serde::Deserialize;
+use tokio::{
+    net::TcpStream,
+    time::{self, Duration, Instant},
+};
 use tokio_tungstenite::{
-    tungstenite::{protocol::WebSocketConfig, Message},
+    tungstenite::{self, protocol::WebSocketConfig, Message},
     MaybeTlsStream, WebSocketStream,
 };
 
 use twilight_model::gateway::payload::incoming::VoiceServerUpdate;
 use twilight_voice_model::{
-    payload::{Heartbeat, Identify},
-    Event,
+    payload::{Heartbeat, Identify, Resume, SelectProtocol, Speaking},
+    CloseCode, Event, SpeakingState,
 };
 
-use crate::{client::PartialVoiceStateUpdate, Result};
+use crate::{client::PartialVoiceStateUpdate, config::Config, crypto::CryptoMode, Error, Result};
+
+/// Sequence number attached to numbered messages from the gateway.
+#[derive(Deserialize)]
+struct Sequence {
+    seq: Option<i64>,
+}
 
 pub struct DiscordVoiceClient {
     pub websocket: WebSocketStream<MaybeTlsStream<TcpStream>>,
-    pub seq: i64,
+    /// Sequence number of the last numbered message received.
+    pub seq: Option<i64>,
     heartbeat_interval: Option<f64>,
+    /// Nonce and send time of the last heartbeat not yet acknowledged.
+    heartbeat: Option<(u64, Instant)>,
     voice_server: VoiceServerUpdate,
     voice_state: PartialVoiceStateUpdate,
+    config: Arc<Config>,
 }
 
 impl DiscordVoiceClient {
     pub async fn connect(
         voice_server: VoiceServerUpdate,
         voice_state: PartialVoiceStateUpdate,
+        config: Arc<Config>,
     ) -> Result<Self> {
-        let uri = format!("wss://{}/?v=8", voice_server.endpoint.clone().unwrap(),);
+        let timeout = config.connect_timeout;
+        let connect = async {
+            let websocket = Self::open(&voice_server, &config).await?;
+
+            let mut client = Self {
+                websocket,
+                seq: None,
+                heartbeat_interval: None,
+                heartbeat: None,
+                voice_server,
+                voice_state,
+                config,
+            };
+            client.wait_for_hello().await?;
+
+            Ok::<_, Error>(client)
+        };
+
+        let mut client = time::timeout(timeout, connect)
+            .await
+            .map_err(|_| Error::ConnectTimeout)??;
+        client.send_identify().await?;
+
+        Ok(client)
+    }
+
+    /// Reconnect after the websocket was lost, and resume the existing session.
+    ///
+    /// The UDP connection and session key stay valid, so playback continues once the gateway
+    /// replies with `Resumed`.
+    pub async fn resume(&mut self) -> Result<()> {
+        let timeout = self.config.connect_timeout;
+        let reconnect = async {
+            self.websocket = Self::open(&self.voice_server, &self.config).await?;
+            self.wait_for_hello().await
+        };
+
+        time::timeout(timeout, reconnect)
+            .await
+            .map_err(|_| Error::ConnectTimeout)??;
+        self.send_resume().await
+    }
+
+    async fn open(
+        voice_server: &VoiceServerUpdate,
+        config: &Config,
+    ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+        let endpoint = voice_server.endpoint.as_deref().ok_or(Error::NoEndpoint)?;
+        let scheme = if config.gateway_tls { "wss" } else { "ws" };
+        let uri = format!("{scheme}://{endpoint}/?v={}", config.gateway_version);
 
         let (websocket, _) = tokio_tungstenite::connect_async_tls_with_config(
             uri,
             Some(WebSocketConfig {
-                max_message_size: None,
-                max_frame_size: None,
+                max_message_size: config.max_message_size,
+                max_frame_size: config.max_message_size,
                 ..Default::default()
             }),
             true,
@@ -40,87 +106,153 @@ impl DiscordVoiceClient {
         )
         .await?;
 
-        let mut client = Self {
-            websocket,
-            seq: -1,
-            heartbeat_interval: None,
-            voice_server,
-            voice_state,
-        };
+        Ok(websocket)
+    }
 
-        client.poll().await?;
+    /// Voice server endpoint, without the scheme.
+    pub fn endpoint(&self) -> Option<&str> {
+        self.voice_server.endpoint.as_deref()
+    }
 
-        Ok(client)
+    pub fn voice_server(&self) -> &VoiceServerUpdate {
+        &self.voice_server
     }
 
-    pub async fn run(&mut self) -> Result<()> {
-        loop {
-            tokio::select! {
-                _ = tokio::time::sleep(
-                    Duration::from_millis(
-                        self.heartbeat_interval.unwrap_or(1000.0) as u64
-                    )
-                ) => {
-                    self.send_heartbeat().await?;
-                }
-                result = self.poll() => {
-                    if let Err(e) = result {
-                        return Err(e);
-                    }
-                }
-            }
+    pub fn voice_state(&self) -> &PartialVoiceStateUpdate {
+        &self.voice_state
+    }
+
+    /// Replace the voice state to identify with when connecting again, as the session ID
+    /// changes when the Discord gateway reconnects.
+    pub fn set_voice_state(&mut self, voice_state: PartialVoiceStateUpdate) {
+        self.voice_state = voice_state;
+    }
+
+    /// Wait for the gateway to tell us how often to heartbeat, which it does before anything
+    /// else.
+    async fn wait_for_hello(&mut self) -> Result<()> {
+        self.heartbeat_interval = None;
+        while self.heartbeat_interval.is_none() {
+            self.next_event().await?;
         }
+
+        Ok(())
     }
 
-    pub async fn poll(&mut self) -> Result<()> {
-        let Some(message) = self.websocket.next().await else {
-            return Ok(());
-        };
+    /// Interval at which heartbeats must be sent, as requested in `Hello`.
+    ///
+    /// The configured interval is used instead of one which is not a positive number of
+    /// milliseconds that fits in a [`Duration`].
+    pub fn heartbeat_interval(&self) -> Duration {
+        self.heartbeat_interval
+            .and_then(|interval| Duration::try_from_secs_f64(interval / 1000.0).ok())
+            .filter(|interval| !interval.is_zero())
+            .unwrap_or(self.config.heartbeat_interval)
+    }
 
-        println!("{:?}", message);
+    /// Wait for the next event from the gateway.
+    ///
+    /// This is not cancel safe: use [`DiscordVoiceClient::process`] on messages read from the
+    /// websocket when racing against other futures.
+    pub async fn next_event(&mut self) -> Result<Option<Event>> {
+        let message = self.websocket.next().await;
 
-        if let Message::Text(data) = message? {
-            println!("{}", data);
-            let event: Event = serde_json::from_str(&data)?;
+        self.process(message)
+    }
 
-            match event {
-                Event::Hello(data) => {
-                    self.heartbeat_interval = Some(data.heartbeat_interval);
-                    self.send_identify().await?;
-                }
-                Event::Ready(data) => {
-                }
-                _ => {}
+    /// Handle a message read from the websocket.
+    ///
+    /// Messages needing no further action, and events this library does not model, yield
+    /// `None`. A closed websocket is returned as [`Error::GatewayClosed`].
+    pub fn process(
+        &mut self,
+        message: Option<std::result::Result<Message, tungstenite::Error>>,
+    ) -> Result<Option<Event>> {
+        let data = match message.ok_or(Error::GatewayClosed(None))?? {
+            Message::Text(data) => data,
+            Message::Close(frame) => {
+                let code = frame.and_then(|frame| CloseCode::try_from(u16::from(frame.code)).ok());
+                return Err(Error::GatewayClosed(code));
             }
+            _ => return Ok(None),
+        };
+
+        if let Ok(Sequence { seq: Some(seq) }) = serde_json::from_str(&data) {
+            self.seq = Some(seq);
+        }
+
+        let Ok(event) = serde_json::from_str::<Event>(&data) else {
+            return Ok(None);
+        };
+
+        if let Event::Hello(data) = &event {
+            self.heartbeat_interval = Some(data.heartbeat_interval);
         }
 
+        Ok(Some(event))
+    }
+
+    pub async fn send(&mut self, event: &Event) -> Result<()> {
+        self.websocket
+            .send(Message::Text(serde_json::to_string(event)?))
+            .await?;
         Ok(())
     }
 
     pub async fn send_identify(&mut self) -> Result<()> {
-        let identify = Event::Identify(Identify {
+        let identify = Identify::builder(
+            self.voice_server.guild_id,
+            self.voice_state.user_id,
+            self.voice_state.session_id.clone(),
+            self.voice_server.token.clone(),
+        )
+        .build()?;
+        let identify = Event::Identify(identify);
+        self.send(&identify).await
+    }
+
+    pub async fn send_resume(&mut self) -> Result<()> {
+        let resume = Event::Resume(Resume {
             server_id: self.voice_server.guild_id,
             session_id: self.voice_state.session_id.clone(),
             token: self.voice_server.token.clone(),
-            user_id: self.voice_state.user_id,
         });
-        self.websocket
-            .send(Message::Text(serde_json::to_string(&identify)?))
-            .await?;
-        Ok(())
+        self.send(&resume).await
     }
 
     pub async fn send_heartbeat(&mut self) -> Result<()> {
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
             .as_millis() as u64;
-        let heartbeat = Event::Heartbeat(Heartbeat {
-            t: now,
-            seq_ack: self.seq,
-        });
-        self.websocket
-            .send(Message::Text(serde_json::to_string(&heartbeat)?))
-            .await?;
-        Ok(())
+        let heartbeat = Event::Heartbeat(Heartbeat::builder(now).with_seq_ack(self.seq).build());
+        self.heartbeat = Some((now, Instant::now()));
+        self.send(&heartbeat).await
+    }
+
+    /// Match an acknowledgement to the last heartbeat, returning its round trip time.
+    pub fn ack_heartbeat(&mut self, nonce: u64) -> Option<Duration> {
+        match self.heartbeat {
+            Some((sent, at)) if sent == nonce => {
+                self.heartbeat = None;
+                Some(at.elapsed())
+            }
+            _ => None,
+        }
+    }
+
+    /// Announce that audio from `ssrc` is being sent as `state`, or has stopped if it is empty.
+    pub async fn send_speaking(&mut self, state: SpeakingState, ssrc: u32) -> Result<()> {
+        let speaking = Event::Speaking(Speaking::builder(ssrc).with_state(state).build());
+        self.send(&speaking).await
+    }
+
+    /// Tell the server where to send audio, and how it should be encrypted.
+    pub async fn send_select_protocol(
+        &mut self,
+        address: SocketAddr,
+        mode: CryptoMode,
+    ) -> Result<()> {
+        let select = Event::SelectProtocol(SelectProtocol::builder(address, mode.into()).build()?);
+        self.send(&select).await
     }
 }
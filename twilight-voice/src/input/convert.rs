@@ -0,0 +1,515 @@
+use std::{f64::consts::PI, time::Duration};
+
+use super::{AudioFormat, AudioFrame, AudioSource};
+use crate::{constants, Result};
+
+/// Number of sub-sample offsets at which the resampling filter is tabulated.
+const PHASES: usize = 256;
+
+/// Trade-off between resampling quality and CPU usage.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum ResampleQuality {
+    /// Linear interpolation, which is cheap but dulls and aliases high frequencies.
+    Fast,
+    /// Windowed-sinc filter over 16 input samples, transparent for most speech and music.
+    #[default]
+    Balanced,
+    /// Windowed-sinc filter over 64 input samples.
+    Best,
+}
+
+impl ResampleQuality {
+    /// Number of input frames on either side of the interpolation point which contribute to
+    /// each output frame.
+    const fn half_width(self) -> usize {
+        match self {
+            Self::Fast => 1,
+            Self::Balanced => 8,
+            Self::Best => 32,
+        }
+    }
+
+    /// Weight of an input frame `distance` frames away from the interpolation point, for a
+    /// filter passing frequencies below `cutoff` times the input Nyquist frequency.
+    fn weight(self, distance: f64, cutoff: f64) -> f64 {
+        let half_width = self.half_width() as f64;
+        if distance.abs() >= half_width {
+            return 0.0;
+        }
+
+        match self {
+            Self::Fast => 1.0 - distance.abs(),
+            Self::Balanced | Self::Best => {
+                let x = PI * cutoff * distance;
+                let sinc = if x == 0.0 { 1.0 } else { x.sin() / x };
+                let window = 0.42
+                    + 0.5 * (PI * distance / half_width).cos()
+                    + 0.08 * (2.0 * PI * distance / half_width).cos();
+
+                sinc * window
+            }
+        }
+    }
+}
+
+/// Gains from each input channel to the left and right outputs, in WAVE channel order.
+fn mix_matrix(channels: u16) -> Vec<[f32; 2]> {
+    const C: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+    let matrix = match channels {
+        1 => vec![[1.0, 1.0]],
+        2 => vec![[1.0, 0.0], [0.0, 1.0]],
+        // L, R, C
+        3 => vec![[1.0, 0.0], [0.0, 1.0], [C, C]],
+        // L, R, Ls, Rs
+        4 => vec![[1.0, 0.0], [0.0, 1.0], [C, 0.0], [0.0, C]],
+        // L, R, C, Ls, Rs
+        5 => vec![[1.0, 0.0], [0.0, 1.0], [C, C], [C, 0.0], [0.0, C]],
+        // L, R, C, LFE, Ls, Rs, with the LFE channel dropped as in the ITU downmix.
+        6 => vec![
+            [1.0, 0.0],
+            [0.0, 1.0],
+            [C, C],
+            [0.0, 0.0],
+            [C, 0.0],
+            [0.0, C],
+        ],
+        // L, R, C, LFE, Lb, Rb, Ls, Rs
+        8 => vec![
+            [1.0, 0.0],
+            [0.0, 1.0],
+            [C, C],
+            [0.0, 0.0],
+            [C, 0.0],
+            [0.0, C],
+            [C, 0.0],
+            [0.0, C],
+        ],
+        // Unknown layouts alternate between left and right.
+        n => (0..n)
+            .map(|i| if i % 2 == 0 { [1.0, 0.0] } else { [0.0, 1.0] })
+            .collect(),
+    };
+
+    // Scale down so that full-scale audio on every channel cannot clip.
+    let peak = [0, 1]
+        .into_iter()
+        .map(|side| matrix.iter().map(|gains| gains[side]).sum::<f32>())
+        .fold(1.0, f32::max);
+
+    matrix
+        .into_iter()
+        .map(|[left, right]| [left / peak, right / peak])
+        .collect()
+}
+
+/// Polyphase resampler converting stereo frames to 48 kHz.
+///
+/// The position of each output frame is tracked exactly as a whole input frame plus a fraction
+/// in units of `1 / 48000`, so that no drift accumulates over long streams.
+struct Resampler {
+    sample_rate: u32,
+    half_width: usize,
+    /// Filter weights for `PHASES + 1` evenly spaced sub-sample offsets, `2 * half_width` each.
+    kernel: Vec<f32>,
+    /// Frames not yet consumed, preceded by those still needed as history.
+    input: Vec<[f32; 2]>,
+    /// Index within `input` of the frame at or before the next output frame.
+    index: usize,
+    /// Offset of the next output frame past `index`, in units of `1 / 48000` of a frame.
+    fraction: u32,
+}
+
+impl Resampler {
+    fn new(sample_rate: u32, quality: ResampleQuality) -> Self {
+        let half_width = quality.half_width();
+        // Lower the cutoff below the output Nyquist frequency when downsampling, leaving room
+        // for the filter's transition band.
+        let cutoff = (f64::from(constants::SAMPLE_RATE) / f64::from(sample_rate)).min(1.0) * 0.95;
+
+        let mut kernel = Vec::with_capacity((PHASES + 1) * 2 * half_width);
+        for phase in 0..=PHASES {
+            let offset = phase as f64 / PHASES as f64;
+            let row = (0..2 * half_width)
+                .map(|tap| quality.weight(tap as f64 + 1.0 - half_width as f64 - offset, cutoff))
+                .collect::<Vec<_>>();
+            // Normalise each phase to unity gain, so that DC passes through unchanged.
+            let sum = row.iter().sum::<f64>();
+            kernel.extend(row.into_iter().map(|weight| (weight / sum) as f32));
+        }
+
+        Self {
+            sample_rate,
+            half_width,
+            kernel,
+            input: Vec::new(),
+            index: half_width - 1,
+            fraction: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.input.clear();
+        self.index = self.half_width - 1;
+        self.fraction = 0;
+    }
+
+    /// Interpolate the output frame at the current position.
+    fn interpolate(&self) -> [f32; 2] {
+        let taps = 2 * self.half_width;
+        let phase = f64::from(self.fraction) * PHASES as f64 / f64::from(constants::SAMPLE_RATE);
+        let row = phase as usize;
+        let blend = (phase - row as f64) as f32;
+
+        let before = &self.kernel[row * taps..][..taps];
+        let after = &self.kernel[(row + 1).min(PHASES) * taps..][..taps];
+        let window = &self.input[self.index + 1 - self.half_width..][..taps];
+
+        let mut frame = [0.0; 2];
+        for ((&[left, right], &a), &b) in window.iter().zip(before).zip(after) {
+            let weight = a + (b - a) * blend;
+            frame[0] += left * weight;
+            frame[1] += right * weight;
+        }
+
+        frame
+    }
+
+    fn advance(&mut self) {
+        self.fraction += self.sample_rate;
+        self.index += (self.fraction / constants::SAMPLE_RATE) as usize;
+        self.fraction %= constants::SAMPLE_RATE;
+    }
+
+    fn process(&mut self, mut frames: impl Iterator<Item = [f32; 2]>, out: &mut Vec<f32>) {
+        // Extend the first frame of a stream backwards, rather than filtering in a step from
+        // silence.
+        if self.input.is_empty() {
+            let Some(first) = frames.next() else {
+                return;
+            };
+            self.input.resize(self.half_width, first);
+        }
+        self.input.extend(frames);
+
+        while self.index + self.half_width < self.input.len() {
+            out.extend(self.interpolate());
+            self.advance();
+        }
+
+        // Drop the frames which no later output depends on, keeping at least one to extend.
+        let consumed = (self.index + 1)
+            .saturating_sub(self.half_width)
+            .min(self.input.len() - 1);
+        self.input.drain(..consumed);
+        self.index -= consumed;
+    }
+
+    fn flush(&mut self, out: &mut Vec<f32>) {
+        if let Some(&last) = self.input.last() {
+            let end = self.input.len();
+            self.input.resize(end + self.half_width, last);
+
+            while self.index < end {
+                out.extend(self.interpolate());
+                self.advance();
+            }
+        }
+
+        self.reset();
+    }
+}
+
+/// Streaming conversion of interleaved PCM in any format to 48 kHz stereo.
+///
+/// Channels are mixed down (or mono duplicated) to stereo before resampling. Input may be passed
+/// in chunks of any size, and output is produced as soon as the resampling filter allows.
+pub struct Converter {
+    format: AudioFormat,
+    mix: Vec<[f32; 2]>,
+    resampler: Option<Resampler>,
+}
+
+impl Converter {
+    pub fn new(format: AudioFormat, quality: ResampleQuality) -> Self {
+        let resampler = (format.sample_rate != constants::SAMPLE_RATE)
+            .then(|| Resampler::new(format.sample_rate, quality));
+
+        Self {
+            format,
+            mix: mix_matrix(format.channels),
+            resampler,
+        }
+    }
+
+    /// Format of the audio this converter accepts.
+    pub fn format(&self) -> AudioFormat {
+        self.format
+    }
+
+    /// Convert `samples`, appending the resulting 48 kHz stereo samples to `out`.
+    ///
+    /// A trailing partial frame in `samples` is ignored.
+    pub fn process(&mut self, samples: &[f32], out: &mut Vec<f32>) {
+        let channels = usize::from(self.format.channels.max(1));
+        let frames = samples.chunks_exact(channels).map(|frame| {
+            frame
+                .iter()
+                .zip(&self.mix)
+                .fold([0.0; 2], |[left, right], (sample, gains)| {
+                    [left + sample * gains[0], right + sample * gains[1]]
+                })
+        });
+
+        match &mut self.resampler {
+            Some(resampler) => resampler.process(frames, out),
+            None => out.extend(frames.flatten()),
+        }
+    }
+
+    /// Emit the audio still held back by the resampling filter, at the end of a stream.
+    pub fn flush(&mut self, out: &mut Vec<f32>) {
+        if let Some(resampler) = &mut self.resampler {
+            resampler.flush(out);
+        }
+    }
+
+    /// Discard any buffered audio, as after seeking.
+    pub fn reset(&mut self) {
+        if let Some(resampler) = &mut self.resampler {
+            resampler.reset();
+        }
+    }
+}
+
+/// Adapts the PCM produced by any [`AudioSource`] to 48 kHz stereo, ready for Opus encoding.
+///
+/// Opus frames are passed through unchanged, and the format of the source is checked for every
+/// frame of PCM. [`VoiceClient::play`] wraps every source with [`ResampleQuality::default`];
+/// wrap them explicitly to choose another quality.
+///
+/// [`VoiceClient::play`]: crate::client::VoiceClient::play
+pub struct ConvertedSource<S> {
+    source: S,
+    quality: ResampleQuality,
+    converter: Option<Converter>,
+    /// Converted audio which has not yet filled a whole frame.
+    pcm: Vec<f32>,
+    /// Opus packet read from the source, held back until the PCM before it has been returned.
+    opus: Option<Vec<u8>>,
+    finished: bool,
+}
+
+impl<S: AudioSource> ConvertedSource<S> {
+    pub fn new(source: S, quality: ResampleQuality) -> Self {
+        Self {
+            source,
+            quality,
+            converter: None,
+            pcm: Vec::new(),
+            opus: None,
+            finished: false,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+
+    fn convert(&mut self, samples: &[f32]) {
+        let format = self.source.format();
+        let converter = match &mut self.converter {
+            Some(converter) if converter.format() == format => converter,
+            slot => {
+                // The source changed format mid-stream; finish off audio in the old one first.
+                if let Some(previous) = slot {
+                    previous.flush(&mut self.pcm);
+                }
+                slot.insert(Converter::new(format, self.quality))
+            }
+        };
+
+        converter.process(samples, &mut self.pcm);
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: AudioSource> AudioSource for ConvertedSource<S> {
+    fn format(&self) -> AudioFormat {
+        AudioFormat::DISCORD
+    }
+
+    async fn read_frame(&mut self) -> Result<Option<AudioFrame>> {
+        while self.pcm.len() < constants::STEREO_FRAME_SIZE && !self.finished && self.opus.is_none()
+        {
+            match self.source.read_frame().await? {
+                Some(AudioFrame::Pcm(samples)) => self.convert(&samples),
+                Some(AudioFrame::Opus(packet)) => {
+                    // Finish off the PCM before the packet, rather than playing it after.
+                    if let Some(mut converter) = self.converter.take() {
+                        converter.flush(&mut self.pcm);
+                    }
+                    self.opus = Some(packet);
+                }
+                None => {
+                    if let Some(converter) = &mut self.converter {
+                        converter.flush(&mut self.pcm);
+                    }
+                    self.finished = true;
+                }
+            }
+        }
+
+        if self.pcm.is_empty() {
+            return Ok(self.opus.take().map(AudioFrame::Opus));
+        }
+
+        let len = self.pcm.len().min(constants::STEREO_FRAME_SIZE);
+
+        Ok(Some(AudioFrame::Pcm(self.pcm.drain(..len).collect())))
+    }
+
+    fn is_seekable(&self) -> bool {
+        self.source.is_seekable()
+    }
+
+    async fn seek(&mut self, position: Duration) -> Result<Duration> {
+        let reached = self.source.seek(position).await?;
+        if let Some(converter) = &mut self.converter {
+            converter.reset();
+        }
+        self.pcm.clear();
+        self.opus = None;
+        self.finished = false;
+
+        Ok(reached)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, f32::consts::PI};
+
+    use super::{ConvertedSource, Converter, ResampleQuality};
+    use crate::{
+        constants,
+        input::{AudioFormat, AudioFrame, AudioSource, MemorySource},
+        Result,
+    };
+
+    fn sine(frequency: f32, sample_rate: u32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|n| (2.0 * PI * frequency * n as f32 / sample_rate as f32).sin())
+            .collect()
+    }
+
+    #[test]
+    fn mixes_channels_to_stereo() {
+        let mut out = Vec::new();
+
+        Converter::new(AudioFormat::new(48_000, 1), ResampleQuality::Fast)
+            .process(&[0.5, -0.5], &mut out);
+        assert_eq!(out, [0.5, 0.5, -0.5, -0.5]);
+
+        out.clear();
+        Converter::new(AudioFormat::DISCORD, ResampleQuality::Fast)
+            .process(&[0.25, -0.25], &mut out);
+        assert_eq!(out, [0.25, -0.25]);
+
+        // Full-scale 5.1 must not clip, and the LFE channel is dropped.
+        out.clear();
+        let mut surround = Converter::new(AudioFormat::new(48_000, 6), ResampleQuality::Fast);
+        surround.process(&[1.0; 6], &mut out);
+        surround.process(&[0.0, 0.0, 0.0, 1.0, 0.0, 0.0], &mut out);
+        assert!(out[..2].iter().all(|s| (s - 1.0).abs() < 1e-6));
+        assert_eq!(out[2..], [0.0, 0.0]);
+    }
+
+    #[test]
+    fn resamples_to_48khz() {
+        let input = sine(1_000.0, 44_100, 44_100);
+        let expected = sine(1_000.0, 48_000, 48_000);
+
+        for (quality, tolerance) in [
+            (ResampleQuality::Fast, 5e-3),
+            (ResampleQuality::Balanced, 1e-3),
+            (ResampleQuality::Best, 1e-3),
+        ] {
+            let mut converter = Converter::new(AudioFormat::new(44_100, 1), quality);
+            let mut out = Vec::new();
+            for chunk in input.chunks(441) {
+                converter.process(chunk, &mut out);
+            }
+            converter.flush(&mut out);
+
+            assert_eq!(out.len(), 2 * 48_000, "{quality:?}");
+            // Compare the left channel, away from the edges where the filter sees silence.
+            let error = out
+                .iter()
+                .step_by(2)
+                .zip(&expected)
+                .skip(100)
+                .take(47_800)
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0, f32::max);
+            assert!(error < tolerance, "{quality:?}: {error}");
+        }
+    }
+
+    #[tokio::test]
+    async fn converts_source_frames() -> anyhow::Result<()> {
+        let format = AudioFormat::new(22_050, 1);
+        let source = MemorySource::pcm(vec![0.5; 22_050], format);
+        let mut source = ConvertedSource::new(source, ResampleQuality::default());
+
+        assert_eq!(source.format(), AudioFormat::DISCORD);
+
+        let mut samples = 0;
+        while let Some(AudioFrame::Pcm(frame)) = source.read_frame().await? {
+            assert!(frame.len() == constants::STEREO_FRAME_SIZE || samples >= 94_000);
+            samples += frame.len();
+        }
+        assert_eq!(samples, 2 * 48_000);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn plays_pcm_before_following_opus() -> anyhow::Result<()> {
+        struct Frames(VecDeque<AudioFrame>);
+
+        #[async_trait::async_trait]
+        impl AudioSource for Frames {
+            fn format(&self) -> AudioFormat {
+                AudioFormat::DISCORD
+            }
+
+            async fn read_frame(&mut self) -> Result<Option<AudioFrame>> {
+                Ok(self.0.pop_front())
+            }
+        }
+
+        let frames = [
+            AudioFrame::Pcm(vec![0.5; 960]),
+            AudioFrame::Opus(vec![0xF8]),
+            AudioFrame::Pcm(vec![0.25; 1920]),
+        ];
+        let mut source = ConvertedSource::new(Frames(frames.into()), ResampleQuality::Fast);
+
+        assert_eq!(
+            source.read_frame().await?,
+            Some(AudioFrame::Pcm(vec![0.5; 960]))
+        );
+        assert_eq!(
+            source.read_frame().await?,
+            Some(AudioFrame::Opus(vec![0xF8]))
+        );
+        assert_eq!(
+            source.read_frame().await?,
+            Some(AudioFrame::Pcm(vec![0.25; 1920]))
+        );
+        assert_eq!(source.read_frame().await?, None);
+
+        Ok(())
+    }
+}
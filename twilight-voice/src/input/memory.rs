@@ -0,0 +1,137 @@
+use std::{sync::Arc, time::Duration};
+
+use super::{AudioFormat, AudioFrame, AudioSource};
+use crate::{constants, Result};
+
+enum Contents {
+    Pcm(Arc<[f32]>),
+    Opus(Arc<[Vec<u8>]>),
+}
+
+/// Audio held entirely in memory.
+///
+/// Contents are reference counted, so cloning a source to play it several times is cheap.
+pub struct MemorySource {
+    contents: Contents,
+    format: AudioFormat,
+    /// Index of the next sample or packet to be read.
+    position: usize,
+}
+
+impl MemorySource {
+    /// Play back interleaved PCM samples of the given format.
+    pub fn pcm(samples: impl Into<Arc<[f32]>>, format: AudioFormat) -> Self {
+        Self {
+            contents: Contents::Pcm(samples.into()),
+            format,
+            position: 0,
+        }
+    }
+
+    /// Play back a sequence of 20 ms Opus packets without re-encoding them.
+    pub fn opus(packets: impl Into<Arc<[Vec<u8>]>>) -> Self {
+        Self {
+            contents: Contents::Opus(packets.into()),
+            format: AudioFormat::DISCORD,
+            position: 0,
+        }
+    }
+}
+
+impl Clone for MemorySource {
+    /// Create a new source over the same audio, starting from the beginning.
+    fn clone(&self) -> Self {
+        let contents = match &self.contents {
+            Contents::Pcm(samples) => Contents::Pcm(samples.clone()),
+            Contents::Opus(packets) => Contents::Opus(packets.clone()),
+        };
+
+        Self {
+            contents,
+            format: self.format,
+            position: 0,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl AudioSource for MemorySource {
+    fn format(&self) -> AudioFormat {
+        self.format
+    }
+
+    async fn read_frame(&mut self) -> Result<Option<AudioFrame>> {
+        let frame = match &self.contents {
+            Contents::Pcm(samples) => {
+                let rest = samples.get(self.position..).unwrap_or_default();
+                let frame = &rest[..rest.len().min(self.format.frame_size())];
+                self.position += frame.len();
+
+                (!frame.is_empty()).then(|| AudioFrame::Pcm(frame.to_vec()))
+            }
+            Contents::Opus(packets) => {
+                let packet = packets.get(self.position).cloned();
+                self.position += 1;
+
+                packet.map(AudioFrame::Opus)
+            }
+        };
+
+        Ok(frame)
+    }
+
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    async fn seek(&mut self, position: Duration) -> Result<Duration> {
+        match &self.contents {
+            Contents::Pcm(samples) => {
+                let channels = self.format.channels.max(1) as usize;
+                let frames = samples.len() / channels;
+                let target = (self.format.samples_in(position) as usize).min(frames);
+                self.position = target * channels;
+
+                Ok(self.format.duration_of(self.position))
+            }
+            Contents::Opus(packets) => {
+                let target = (position.as_nanos() / constants::FRAME_DURATION.as_nanos()) as usize;
+                self.position = target.min(packets.len());
+
+                Ok(constants::FRAME_DURATION * self.position as u32)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::MemorySource;
+    use crate::input::{AudioFormat, AudioFrame, AudioSource};
+
+    #[tokio::test]
+    async fn seeks_pcm_and_opus() -> anyhow::Result<()> {
+        let mut pcm = MemorySource::pcm(vec![0.0; 48_000 * 2], AudioFormat::DISCORD);
+        assert_eq!(
+            pcm.seek(Duration::from_millis(990)).await?,
+            Duration::from_millis(990)
+        );
+        assert!(matches!(pcm.read_frame().await?, Some(AudioFrame::Pcm(s)) if s.len() == 960));
+        assert!(pcm.read_frame().await?.is_none());
+
+        let mut opus = MemorySource::opus(vec![vec![1], vec![2], vec![3]]);
+        assert_eq!(
+            opus.seek(Duration::from_millis(45)).await?,
+            Duration::from_millis(40)
+        );
+        assert_eq!(opus.read_frame().await?, Some(AudioFrame::Opus(vec![3])));
+        assert_eq!(
+            opus.clone().read_frame().await?,
+            Some(AudioFrame::Opus(vec![1]))
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+//! Audio inputs which can be played over a voice connection.
+//!
+//! Every input implements [`AudioSource`], which hands out audio one frame at a time, either as
+//! interleaved PCM or as packets which are already Opus-encoded.
+
+mod convert;
+mod memory;
+mod ogg;
+mod process;
+mod raw;
+#[cfg(feature = "symphonia")]
+mod symphonia;
+mod wav;
+
+use std::{
+    io::SeekFrom,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf};
+
+use crate::{constants, Error, Result};
+
+pub use self::{
+    convert::{ConvertedSource, Converter, ResampleQuality},
+    memory::MemorySource,
+    ogg::OggOpusSource,
+    process::{ChildSource, ProcessOutput},
+    raw::{RawPcmSource, SampleFormat},
+    wav::WavSource,
+};
+
+#[cfg(feature = "symphonia")]
+pub use self::symphonia::SymphoniaSource;
+
+/// Sample rate and channel layout of an audio stream.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct AudioFormat {
+    /// Number of samples per second, per channel.
+    pub sample_rate: u32,
+    /// Number of interleaved channels.
+    pub channels: u16,
+}
+
+impl AudioFormat {
+    /// 48 kHz stereo, the format Discord expects.
+    pub const DISCORD: Self = Self::new(constants::SAMPLE_RATE, constants::CHANNELS);
+
+    pub const fn new(sample_rate: u32, channels: u16) -> Self {
+        Self {
+            sample_rate,
+            channels,
+        }
+    }
+
+    /// Number of interleaved samples making up one 20 ms frame in this format.
+    pub const fn frame_size(&self) -> usize {
+        (self.sample_rate / constants::FRAMES_PER_SECOND) as usize * self.channels as usize
+    }
+
+    /// Playback time covered by `samples` interleaved samples in this format.
+    pub fn duration_of(&self, samples: usize) -> Duration {
+        let per_channel = (samples / self.channels.max(1) as usize) as u64;
+
+        Duration::from_nanos(per_channel * 1_000_000_000 / u64::from(self.sample_rate.max(1)))
+    }
+
+    /// Number of samples per channel which are played within `duration`.
+    pub fn samples_in(&self, duration: Duration) -> u64 {
+        (duration.as_nanos() * u128::from(self.sample_rate) / 1_000_000_000) as u64
+    }
+}
+
+/// A single frame of audio produced by an [`AudioSource`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum AudioFrame {
+    /// Interleaved PCM samples in the range `[-1.0, 1.0]`.
+    ///
+    /// Holds 20 ms of audio in the source's [`AudioFormat`], except for the last frame of a
+    /// stream which may be shorter.
+    Pcm(Vec<f32>),
+    /// A single Opus packet holding 20 ms of 48 kHz audio, sent without re-encoding.
+    Opus(Vec<u8>),
+}
+
+/// A source of audio which can be played over a voice connection.
+#[async_trait::async_trait]
+pub trait AudioSource: Send {
+    /// Format of the PCM frames produced by this source.
+    fn format(&self) -> AudioFormat;
+
+    /// Pull the next frame of audio, or `None` once the source is exhausted.
+    async fn read_frame(&mut self) -> Result<Option<AudioFrame>>;
+
+    /// Whether [`AudioSource::seek`] is supported by this source.
+    fn is_seekable(&self) -> bool {
+        false
+    }
+
+    /// Move playback to `position`, returning the position which was actually reached.
+    ///
+    /// Seeking past the end of a source leaves it exhausted.
+    async fn seek(&mut self, position: Duration) -> Result<Duration> {
+        let _ = position;
+
+        Err(Error::NotSeekable)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: AudioSource + ?Sized> AudioSource for Box<S> {
+    fn format(&self) -> AudioFormat {
+        (**self).format()
+    }
+
+    async fn read_frame(&mut self) -> Result<Option<AudioFrame>> {
+        (**self).read_frame().await
+    }
+
+    fn is_seekable(&self) -> bool {
+        (**self).is_seekable()
+    }
+
+    async fn seek(&mut self, position: Duration) -> Result<Duration> {
+        (**self).seek(position).await
+    }
+}
+
+pub(crate) trait SeekableReader: AsyncRead + AsyncSeek + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncSeek + Send + Unpin> SeekableReader for T {}
+
+/// Byte stream backing a file-like source, which may or may not support seeking.
+pub(crate) enum Reader {
+    Seekable(Box<dyn SeekableReader>),
+    Stream(Box<dyn AsyncRead + Send + Unpin>),
+}
+
+impl Reader {
+    pub fn seekable(reader: impl AsyncRead + AsyncSeek + Send + Unpin + 'static) -> Self {
+        Self::Seekable(Box::new(reader))
+    }
+
+    pub fn stream(reader: impl AsyncRead + Send + Unpin + 'static) -> Self {
+        Self::Stream(Box::new(reader))
+    }
+
+    pub fn is_seekable(&self) -> bool {
+        matches!(self, Self::Seekable(_))
+    }
+
+    pub async fn seek(&mut self, position: SeekFrom) -> Result<u64> {
+        match self {
+            Self::Seekable(reader) => Ok(reader.seek(position).await?),
+            Self::Stream(_) => Err(Error::NotSeekable),
+        }
+    }
+
+    /// Fill `buf` as far as possible, stopping early only at the end of the stream.
+    pub async fn read_full(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut filled = 0;
+
+        while filled < buf.len() {
+            match self.read(&mut buf[filled..]).await? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+
+        Ok(filled)
+    }
+
+    /// Discard the next `len` bytes of the stream.
+    pub async fn skip(&mut self, len: u64) -> Result<()> {
+        match self {
+            Self::Seekable(reader) => {
+                reader.seek(SeekFrom::Current(len as i64)).await?;
+            }
+            Self::Stream(reader) => {
+                tokio::io::copy(&mut reader.take(len), &mut tokio::io::sink()).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl AsyncRead for Reader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Seekable(reader) => Pin::new(reader).poll_read(cx, buf),
+            Self::Stream(reader) => Pin::new(reader).poll_read(cx, buf),
+        }
+    }
+}
@@ -0,0 +1,672 @@
+use std::{collections::VecDeque, io::SeekFrom, path::Path, time::Duration};
+
+use tokio::io::{AsyncRead, AsyncSeek};
+
+use super::{AudioFormat, AudioFrame, AudioSource, Reader};
+use crate::{
+    codec::{self, OpusDecoder},
+    constants, Error, Result,
+};
+
+const HEADER_CONTINUED: u8 = 0x01;
+const HEADER_EOS: u8 = 0x04;
+
+/// Fields of the `OpusHead` identification header which affect playback.
+#[derive(Clone, Copy, Debug)]
+struct OpusHead {
+    channels: u16,
+    pre_skip: u64,
+    mapping_family: u8,
+}
+
+impl OpusHead {
+    fn parse(packet: &[u8]) -> Result<Self> {
+        if packet.len() < 19 || &packet[..8] != b"OpusHead" {
+            return Err(Error::MalformedAudio("missing OpusHead header".into()));
+        }
+
+        Ok(Self {
+            channels: packet[9].into(),
+            pre_skip: u16::from_le_bytes([packet[10], packet[11]]).into(),
+            mapping_family: packet[18],
+        })
+    }
+
+    /// Whether packets can be sent to Discord as-is, which accepts mono or stereo Opus.
+    fn is_passthrough(&self) -> bool {
+        self.mapping_family == 0 && (1..=2).contains(&self.channels)
+    }
+}
+
+/// Header of an Ogg page, limited to the fields needed to read one logical stream.
+struct PageHeader {
+    header_type: u8,
+    granule: i64,
+    serial: u32,
+    lacing: Vec<u8>,
+}
+
+impl PageHeader {
+    async fn read(reader: &mut Reader) -> Result<Option<Self>> {
+        let mut header = [0; 27];
+        match reader.read_full(&mut header).await? {
+            0 => return Ok(None),
+            27 => {}
+            _ => return Err(Error::MalformedAudio("truncated Ogg page".into())),
+        }
+
+        if &header[..4] != b"OggS" {
+            return Err(Error::MalformedAudio("missing Ogg capture pattern".into()));
+        }
+
+        let mut granule = [0; 8];
+        granule.copy_from_slice(&header[6..14]);
+        let serial = u32::from_le_bytes([header[14], header[15], header[16], header[17]]);
+
+        let mut lacing = vec![0; header[26].into()];
+        if reader.read_full(&mut lacing).await? != lacing.len() {
+            return Err(Error::MalformedAudio("truncated Ogg page".into()));
+        }
+
+        Ok(Some(Self {
+            header_type: header[5],
+            granule: i64::from_le_bytes(granule),
+            serial,
+            lacing,
+        }))
+    }
+
+    fn body_len(&self) -> usize {
+        self.lacing.iter().map(|v| usize::from(*v)).sum()
+    }
+}
+
+/// A single Ogg page.
+struct Page {
+    header: PageHeader,
+    /// Packet lengths in this page, with `false` for a packet continuing onto the next page.
+    segments: Vec<(usize, bool)>,
+    body: Vec<u8>,
+}
+
+impl Page {
+    async fn read(reader: &mut Reader) -> Result<Option<Self>> {
+        let Some(header) = PageHeader::read(reader).await? else {
+            return Ok(None);
+        };
+        let lacing = &header.lacing;
+
+        // Lacing values of 255 continue a packet, anything shorter ends it.
+        let mut segments = Vec::new();
+        let mut len = 0;
+        for value in lacing {
+            len += usize::from(*value);
+            if *value < 255 {
+                segments.push((len, true));
+                len = 0;
+            }
+        }
+        if lacing.last() == Some(&255) {
+            segments.push((len, false));
+        }
+
+        let mut body = vec![0; header.body_len()];
+        if reader.read_full(&mut body).await? != body.len() {
+            return Err(Error::MalformedAudio("truncated Ogg page".into()));
+        }
+
+        Ok(Some(Self {
+            header,
+            segments,
+            body,
+        }))
+    }
+}
+
+/// Splits an Ogg bitstream into the packets of its first logical stream.
+struct PacketReader {
+    reader: Reader,
+    serial: Option<u32>,
+    packets: VecDeque<Vec<u8>>,
+    partial: Vec<u8>,
+    /// Whether to drop data until the end of the current packet, whose start was never read.
+    skip_partial: bool,
+    /// Granule position of the most recently read page.
+    granule: i64,
+    eos: bool,
+}
+
+impl PacketReader {
+    fn new(reader: Reader) -> Self {
+        Self {
+            reader,
+            serial: None,
+            packets: VecDeque::new(),
+            partial: Vec::new(),
+            skip_partial: false,
+            granule: 0,
+            eos: false,
+        }
+    }
+
+    async fn next_packet(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(packet) = self.packets.pop_front() {
+                return Ok(Some(packet));
+            }
+
+            if self.eos {
+                return Ok(None);
+            }
+
+            let Some(page) = Page::read(&mut self.reader).await? else {
+                self.eos = true;
+                continue;
+            };
+
+            let header = &page.header;
+            if *self.serial.get_or_insert(header.serial) != header.serial {
+                continue;
+            }
+
+            // The tail of a packet whose start we never saw (e.g. after a seek, or a page lost
+            // from a stream) is dropped.
+            if header.header_type & HEADER_CONTINUED == 0 {
+                self.partial.clear();
+                self.skip_partial = false;
+            } else if self.partial.is_empty() {
+                self.skip_partial = true;
+            }
+
+            let mut offset = 0;
+            for (len, complete) in page.segments {
+                if self.skip_partial {
+                    self.skip_partial = !complete;
+                } else {
+                    self.partial
+                        .extend_from_slice(&page.body[offset..offset + len]);
+                    if complete {
+                        self.packets.push_back(std::mem::take(&mut self.partial));
+                    }
+                }
+                offset += len;
+            }
+
+            if header.granule >= 0 {
+                self.granule = header.granule;
+            }
+            self.eos = header.header_type & HEADER_EOS != 0;
+        }
+    }
+
+    /// Forget any buffered packets, after the reader was moved to the start of a page.
+    fn reset(&mut self) {
+        self.packets.clear();
+        self.partial.clear();
+        self.skip_partial = true;
+        self.eos = false;
+    }
+}
+
+/// Location of a page of the Opus stream, used to seek by granule position.
+struct PageInfo {
+    offset: u64,
+    /// Granule position at the end of the page, or -1 if no packet ends on it.
+    granule: i64,
+    /// Whether the page begins with the remainder of a packet from the previous page.
+    continued: bool,
+}
+
+/// Playback time of `samples` 48 kHz samples.
+fn samples_duration(samples: u64) -> Duration {
+    Duration::from_nanos(samples * 1_000_000_000 / u64::from(constants::SAMPLE_RATE))
+}
+
+/// Opus audio held in an Ogg container, sent to Discord without re-encoding where possible.
+///
+/// Mono and stereo streams made of 20 ms packets are passed through as-is. Streams using
+/// other packet durations are decoded to PCM instead, which requires the `opus` feature.
+///
+/// Seekable readers support seeking to the start of the page holding the requested position,
+/// using an index of page granule positions built on the first seek.
+pub struct OggOpusSource {
+    packets: PacketReader,
+    head: OpusHead,
+    /// Byte offset of the first audio page, if the reader can seek.
+    data_offset: Option<u64>,
+    index: Option<Vec<PageInfo>>,
+    decoder: Option<OpusDecoder>,
+    /// Decoded samples which have not yet filled a whole frame.
+    pcm: Vec<f32>,
+    /// Number of 48 kHz samples per channel handed out so far, including any pre-skip.
+    samples_read: u64,
+}
+
+impl OggOpusSource {
+    /// Stream an Ogg Opus file from a reader which cannot seek.
+    pub async fn new(reader: impl AsyncRead + Send + Unpin + 'static) -> Result<Self> {
+        Self::from_reader(Reader::stream(reader)).await
+    }
+
+    /// Read an Ogg Opus file from a seekable reader, such as a file or an in-memory cursor.
+    pub async fn seekable(
+        reader: impl AsyncRead + AsyncSeek + Send + Unpin + 'static,
+    ) -> Result<Self> {
+        Self::from_reader(Reader::seekable(reader)).await
+    }
+
+    /// Open an `.ogg` or `.opus` file.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let file = tokio::fs::File::open(path).await?;
+
+        Self::seekable(file).await
+    }
+
+    pub(crate) async fn from_reader(reader: Reader) -> Result<Self> {
+        let mut packets = PacketReader::new(reader);
+
+        let head = match packets.next_packet().await? {
+            Some(packet) => OpusHead::parse(&packet)?,
+            None => return Err(Error::MalformedAudio("empty Ogg stream".into())),
+        };
+        // The comment header carries nothing needed for playback.
+        if packets.next_packet().await?.is_none() {
+            return Err(Error::MalformedAudio("missing OpusTags header".into()));
+        }
+
+        // The comment header ends its page, so audio starts at the next one.
+        let data_offset = match packets.reader.is_seekable() && packets.packets.is_empty() {
+            true => Some(packets.reader.seek(SeekFrom::Current(0)).await?),
+            false => None,
+        };
+
+        let mut source = Self {
+            packets,
+            head,
+            data_offset,
+            index: None,
+            decoder: None,
+            pcm: Vec::new(),
+            samples_read: 0,
+        };
+
+        if !head.is_passthrough() {
+            source.start_transcoding()?;
+        }
+
+        Ok(source)
+    }
+
+    /// Whether packets are being sent without re-encoding.
+    pub fn is_passthrough(&self) -> bool {
+        self.decoder.is_none()
+    }
+
+    fn start_transcoding(&mut self) -> Result<()> {
+        if self.head.mapping_family != 0 {
+            return Err(Error::UnsupportedFormat(format!(
+                "Opus channel mapping family {}",
+                self.head.mapping_family
+            )));
+        }
+
+        self.decoder = Some(OpusDecoder::new(self.head.channels)?);
+
+        Ok(())
+    }
+
+    /// Scan the headers of all pages following `data_offset`.
+    async fn build_index(&mut self, data_offset: u64) -> Result<Vec<PageInfo>> {
+        let reader = &mut self.packets.reader;
+        let serial = self.packets.serial;
+        let mut index = Vec::new();
+        let mut offset = reader.seek(SeekFrom::Start(data_offset)).await?;
+
+        while let Some(header) = PageHeader::read(reader).await? {
+            let body_len = header.body_len() as u64;
+            if serial.is_none_or(|serial| serial == header.serial) {
+                index.push(PageInfo {
+                    offset,
+                    granule: header.granule,
+                    continued: header.header_type & HEADER_CONTINUED != 0,
+                });
+            }
+
+            reader.skip(body_len).await?;
+            offset += 27 + header.lacing.len() as u64 + body_len;
+        }
+
+        Ok(index)
+    }
+
+    async fn read_transcoded(&mut self) -> Result<Option<AudioFrame>> {
+        let channels = usize::from(self.head.channels);
+        let frame_size = self.format().frame_size();
+
+        while self.pcm.len() < frame_size {
+            let Some(packet) = self.packets.next_packet().await? else {
+                break;
+            };
+            let Some(samples) = codec::packet_samples(&packet) else {
+                continue;
+            };
+
+            let decoder = self
+                .decoder
+                .as_mut()
+                .expect("decoder exists while transcoding");
+            let mut pcm = decoder.decode(Some(&packet), samples, false)?;
+
+            let start = self.samples_read;
+            self.samples_read += (pcm.len() / channels) as u64;
+
+            // The encoder's priming samples at the start of the stream are not audio.
+            let skip = self.head.pre_skip.saturating_sub(start) as usize;
+            pcm.drain(..(skip * channels).min(pcm.len()));
+
+            // The last page's granule position marks where audio actually ends.
+            if self.packets.eos && self.packets.packets.is_empty() {
+                let end = (self.packets.granule.max(0) as u64).max(self.head.pre_skip);
+                let excess = self.samples_read.saturating_sub(end) as usize;
+                pcm.truncate(pcm.len().saturating_sub(excess * channels));
+            }
+
+            self.pcm.extend_from_slice(&pcm);
+        }
+
+        if self.pcm.is_empty() {
+            return Ok(None);
+        }
+
+        let len = self.pcm.len().min(frame_size);
+
+        Ok(Some(AudioFrame::Pcm(self.pcm.drain(..len).collect())))
+    }
+}
+
+#[async_trait::async_trait]
+impl AudioSource for OggOpusSource {
+    fn format(&self) -> AudioFormat {
+        if self.is_passthrough() {
+            AudioFormat::DISCORD
+        } else {
+            AudioFormat::new(constants::SAMPLE_RATE, self.head.channels)
+        }
+    }
+
+    async fn read_frame(&mut self) -> Result<Option<AudioFrame>> {
+        if !self.is_passthrough() {
+            return self.read_transcoded().await;
+        }
+
+        let Some(packet) = self.packets.next_packet().await? else {
+            return Ok(None);
+        };
+
+        match codec::packet_samples(&packet) {
+            Some(constants::MONO_FRAME_SIZE) => {
+                self.samples_read += constants::MONO_FRAME_SIZE as u64;
+
+                Ok(Some(AudioFrame::Opus(packet)))
+            }
+            // Discord expects one packet per 20 ms, so anything else has to be re-encoded.
+            _ => {
+                self.start_transcoding()?;
+                self.packets.packets.push_front(packet);
+
+                self.read_transcoded().await
+            }
+        }
+    }
+
+    fn is_seekable(&self) -> bool {
+        self.data_offset.is_some()
+    }
+
+    async fn seek(&mut self, position: Duration) -> Result<Duration> {
+        let data_offset = self.data_offset.ok_or(Error::NotSeekable)?;
+        if self.index.is_none() {
+            self.index = Some(self.build_index(data_offset).await?);
+        }
+        let index = self.index.as_deref().unwrap_or_default();
+
+        let pre_skip = self.head.pre_skip;
+        let target = pre_skip + AudioFormat::DISCORD.samples_in(position);
+
+        // Start from the last page at or before the target which begins with a whole packet,
+        // whose first sample follows the end of the page before it.
+        let mut start = (data_offset, 0);
+        let mut end = 0;
+        for page in index {
+            if end > target {
+                break;
+            }
+            if !page.continued {
+                start = (page.offset, end);
+            }
+            if page.granule >= 0 {
+                end = page.granule as u64;
+            }
+        }
+
+        self.packets.reset();
+        self.pcm.clear();
+        if self.decoder.is_some() {
+            self.decoder = Some(OpusDecoder::new(self.head.channels)?);
+        }
+
+        let (offset, granule) = if target >= end {
+            // Past the end, so leave the stream exhausted.
+            self.packets.eos = true;
+            (data_offset, end)
+        } else {
+            start
+        };
+        self.packets.reader.seek(SeekFrom::Start(offset)).await?;
+        self.samples_read = granule;
+
+        Ok(samples_duration(granule.saturating_sub(pre_skip)))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        io::{Cursor, SeekFrom},
+        time::Duration,
+    };
+
+    use super::OggOpusSource;
+    use crate::{
+        codec::SILENCE_FRAME,
+        input::{AudioFrame, AudioSource},
+        Error,
+    };
+
+    /// Build an Ogg page holding `packets`, where the last packet may continue onto the next.
+    fn page(
+        header_type: u8,
+        granule: i64,
+        sequence: u32,
+        packets: &[&[u8]],
+        open: bool,
+    ) -> Vec<u8> {
+        let mut lacing = Vec::new();
+        let mut body = Vec::new();
+        for (i, packet) in packets.iter().enumerate() {
+            let mut len = packet.len();
+            while len >= 255 {
+                lacing.push(255);
+                len -= 255;
+            }
+            if !(open && i == packets.len() - 1) {
+                lacing.push(len as u8);
+            }
+            body.extend_from_slice(packet);
+        }
+
+        let mut out = b"OggS\0".to_vec();
+        out.push(header_type);
+        out.extend_from_slice(&granule.to_le_bytes());
+        out.extend_from_slice(&1u32.to_le_bytes());
+        out.extend_from_slice(&sequence.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.push(lacing.len() as u8);
+        out.extend_from_slice(&lacing);
+        out.extend_from_slice(&body);
+        out
+    }
+
+    pub(crate) fn opus_head(channels: u8, mapping_family: u8) -> Vec<u8> {
+        let mut head = b"OpusHead\x01".to_vec();
+        head.push(channels);
+        head.extend_from_slice(&312u16.to_le_bytes());
+        head.extend_from_slice(&48_000u32.to_le_bytes());
+        head.extend_from_slice(&[0, 0, mapping_family]);
+        head
+    }
+
+    /// Build an Ogg Opus stream with one 20 ms packet per page.
+    pub(crate) fn ogg_opus(packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = page(0x02, 0, 0, &[&opus_head(2, 0)], false);
+        out.extend(page(0, 0, 1, &[b"OpusTags\0\0\0\0\0\0\0\0"], false));
+        for (i, packet) in packets.iter().enumerate() {
+            let header_type = if i == packets.len() - 1 { 0x04 } else { 0 };
+            let granule = 960 * (i as i64 + 1);
+            out.extend(page(header_type, granule, i as u32 + 2, &[packet], false));
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn passes_packets_through() -> anyhow::Result<()> {
+        let mut long = vec![0xF8];
+        long.resize(600, 0xAB);
+        let data = ogg_opus(&[SILENCE_FRAME.to_vec(), long.clone()]);
+        let mut source = OggOpusSource::new(Cursor::new(data)).await?;
+
+        assert!(source.is_passthrough());
+        assert_eq!(
+            source.read_frame().await?,
+            Some(AudioFrame::Opus(SILENCE_FRAME.to_vec()))
+        );
+        assert_eq!(source.read_frame().await?, Some(AudioFrame::Opus(long)));
+        assert_eq!(source.read_frame().await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn joins_packets_across_pages() -> anyhow::Result<()> {
+        let mut packet = vec![0xF8];
+        packet.resize(700, 0x11);
+
+        let mut data = page(0x02, 0, 0, &[&opus_head(1, 0)], false);
+        data.extend(page(0, 0, 1, &[b"OpusTags\0\0\0\0\0\0\0\0"], false));
+        data.extend(page(0, -1, 2, &[&SILENCE_FRAME, &packet[..510]], true));
+        data.extend(page(0x05, 1920, 3, &[&packet[510..]], false));
+
+        let mut source = OggOpusSource::new(Cursor::new(data)).await?;
+
+        assert_eq!(
+            source.read_frame().await?,
+            Some(AudioFrame::Opus(SILENCE_FRAME.to_vec()))
+        );
+        assert_eq!(source.read_frame().await?, Some(AudioFrame::Opus(packet)));
+        assert_eq!(source.read_frame().await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn seeks_to_page_start() -> anyhow::Result<()> {
+        let packets: Vec<_> = (0..10u8).map(|i| vec![0xF8, i]).collect();
+        let data = ogg_opus(&packets);
+
+        let stream = OggOpusSource::new(Cursor::new(data.clone())).await?;
+        assert!(!stream.is_seekable());
+
+        let mut source = OggOpusSource::seekable(Cursor::new(data)).await?;
+        assert!(source.is_seekable());
+        source.read_frame().await?;
+
+        // 100 ms past the pre-skip falls within the page of the sixth packet.
+        let reached = source.seek(Duration::from_millis(100)).await?;
+        assert_eq!(reached, Duration::from_micros(93_500));
+        assert_eq!(
+            source.read_frame().await?,
+            Some(AudioFrame::Opus(vec![0xF8, 5]))
+        );
+
+        assert_eq!(source.seek(Duration::ZERO).await?, Duration::ZERO);
+        assert_eq!(
+            source.read_frame().await?,
+            Some(AudioFrame::Opus(vec![0xF8, 0]))
+        );
+
+        source.seek(Duration::from_secs(1)).await?;
+        assert_eq!(source.read_frame().await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drops_packet_tails_without_start() -> anyhow::Result<()> {
+        let mut spanning = vec![0xF8];
+        spanning.resize(700, 0x22);
+
+        let mut data = page(0x02, 0, 0, &[&opus_head(2, 0)], false);
+        data.extend(page(0, 0, 1, &[b"OpusTags\0\0\0\0\0\0\0\0"], false));
+        let audio = data.len();
+        data.extend(page(0, 960, 2, &[&[0xF8, 0], &spanning[..510]], true));
+        let continued = data.len();
+        data.extend(page(0x01, 2880, 3, &[&spanning[510..], &[0xF8, 2]], false));
+        data.extend(page(0x04, 3840, 4, &[&[0xF8, 3]], false));
+
+        // Seeking into the packet spanning both pages starts from its first page.
+        let mut source = OggOpusSource::seekable(Cursor::new(data.clone())).await?;
+        assert_eq!(
+            source.seek(Duration::from_millis(20)).await?,
+            Duration::ZERO
+        );
+        assert_eq!(
+            source.read_frame().await?,
+            Some(AudioFrame::Opus(vec![0xF8, 0]))
+        );
+        assert_eq!(source.read_frame().await?, Some(AudioFrame::Opus(spanning)));
+
+        // Landing on the second page skips the tail rather than sending it as a packet.
+        source.packets.reset();
+        source
+            .packets
+            .reader
+            .seek(SeekFrom::Start(continued as u64))
+            .await?;
+        assert_eq!(
+            source.read_frame().await?,
+            Some(AudioFrame::Opus(vec![0xF8, 2]))
+        );
+
+        // As does a stream missing the page before.
+        let mut joined = data[..audio].to_vec();
+        joined.extend_from_slice(&data[continued..]);
+        let mut source = OggOpusSource::new(Cursor::new(joined)).await?;
+        assert_eq!(
+            source.read_frame().await?,
+            Some(AudioFrame::Opus(vec![0xF8, 2]))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_surround_streams() {
+        let mut data = page(0x02, 0, 0, &[&opus_head(6, 1)], false);
+        data.extend(page(0, 0, 1, &[b"OpusTags\0\0\0\0\0\0\0\0"], false));
+
+        assert!(matches!(
+            OggOpusSource::new(Cursor::new(data)).await,
+            Err(Error::UnsupportedFormat(_))
+        ));
+    }
+}
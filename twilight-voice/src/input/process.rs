@@ -0,0 +1,247 @@
+use std::{process::Stdio, time::Duration};
+
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::{Child, Command},
+    task::JoinHandle,
+    time,
+};
+
+use super::{AudioFormat, AudioFrame, AudioSource, OggOpusSource, RawPcmSource, SampleFormat};
+use crate::{Error, Result};
+
+/// Time allowed for a process to exit by itself once its output ends or cannot be read.
+const EXIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Format of the audio a child process writes to its stdout.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProcessOutput {
+    /// Headerless PCM, as produced by e.g. `ffmpeg -f s16le`.
+    Pcm {
+        format: AudioFormat,
+        sample_format: SampleFormat,
+    },
+    /// An Ogg Opus stream, as produced by e.g. `ffmpeg -c:a libopus -f ogg`.
+    OggOpus,
+}
+
+/// Audio read from the stdout of a child process, such as `ffmpeg` or `yt-dlp`.
+///
+/// Anything the process writes to stderr is forwarded to the log. The process is killed when
+/// the source is stopped or dropped, and a non-zero exit status is reported as
+/// [`Error::ProcessExit`] once its output has been consumed. A stopped source simply ends, as
+/// does one whose process keeps running after closing its stdout, which is killed.
+pub struct ChildSource {
+    inner: Box<dyn AudioSource>,
+    child: Child,
+    stderr: Option<JoinHandle<()>>,
+    stopped: bool,
+}
+
+impl ChildSource {
+    /// Spawn `command` and read audio of the given format from its stdout.
+    ///
+    /// Stdin is closed, while stdout and stderr are replaced with pipes.
+    pub async fn spawn(mut command: Command, output: ProcessOutput) -> Result<Self> {
+        let program = command
+            .as_std()
+            .get_program()
+            .to_string_lossy()
+            .into_owned();
+
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().map(|stderr| {
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    tracing::debug!(program, "{line}");
+                }
+            })
+        });
+
+        let inner: Box<dyn AudioSource> = match output {
+            ProcessOutput::Pcm {
+                format,
+                sample_format,
+            } => Box::new(RawPcmSource::new(stdout, format, sample_format)),
+            ProcessOutput::OggOpus => match OggOpusSource::new(stdout).await {
+                Ok(source) => Box::new(source),
+                Err(error) => {
+                    // A process which failed before writing anything is the more useful error,
+                    // while one still running is writing something else and has to be killed.
+                    match time::timeout(EXIT_TIMEOUT, child.wait()).await {
+                        Ok(status) => {
+                            let status = status?;
+                            if !status.success() {
+                                return Err(Error::ProcessExit(status));
+                            }
+                        }
+                        Err(_) => child.kill().await?,
+                    }
+                    return Err(error);
+                }
+            },
+        };
+
+        Ok(Self {
+            inner,
+            child,
+            stderr,
+            stopped: false,
+        })
+    }
+
+    /// Kill the child process, ending the source.
+    pub async fn stop(&mut self) -> Result<()> {
+        self.stopped = true;
+        self.child.kill().await?;
+
+        Ok(())
+    }
+}
+
+impl Drop for ChildSource {
+    fn drop(&mut self) {
+        if let Some(stderr) = self.stderr.take() {
+            stderr.abort();
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl AudioSource for ChildSource {
+    fn format(&self) -> AudioFormat {
+        self.inner.format()
+    }
+
+    async fn read_frame(&mut self) -> Result<Option<AudioFrame>> {
+        if self.stopped {
+            return Ok(None);
+        }
+
+        match self.inner.read_frame().await? {
+            Some(frame) => Ok(Some(frame)),
+            None => match time::timeout(EXIT_TIMEOUT, self.child.wait()).await {
+                Ok(status) => {
+                    let status = status?;
+                    if status.success() {
+                        Ok(None)
+                    } else {
+                        Err(Error::ProcessExit(status))
+                    }
+                }
+                Err(_) => {
+                    tracing::debug!("killing process still running after closing its output");
+                    self.stop().await?;
+
+                    Ok(None)
+                }
+            },
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::process::Command;
+
+    use super::{ChildSource, ProcessOutput};
+    use crate::{
+        input::{AudioFormat, AudioFrame, AudioSource, SampleFormat},
+        Error,
+    };
+
+    const PCM: ProcessOutput = ProcessOutput::Pcm {
+        format: AudioFormat::DISCORD,
+        sample_format: SampleFormat::I16,
+    };
+
+    fn shell(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    #[tokio::test]
+    async fn reads_pcm_from_stdout() -> anyhow::Result<()> {
+        // One and a half frames of silence.
+        let mut source = ChildSource::spawn(shell("head -c 5760 /dev/zero"), PCM).await?;
+
+        assert_eq!(
+            source.read_frame().await?,
+            Some(AudioFrame::Pcm(vec![0.0; 1920]))
+        );
+        assert_eq!(
+            source.read_frame().await?,
+            Some(AudioFrame::Pcm(vec![0.0; 960]))
+        );
+        assert_eq!(source.read_frame().await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_failed_exit() -> anyhow::Result<()> {
+        let script = "head -c 3840 /dev/zero; echo 'something broke' >&2; exit 3";
+        let mut source = ChildSource::spawn(shell(script), PCM).await?;
+
+        assert!(source.read_frame().await?.is_some());
+        assert!(matches!(
+            source.read_frame().await,
+            Err(Error::ProcessExit(status)) if status.code() == Some(3)
+        ));
+
+        assert!(matches!(
+            ChildSource::spawn(shell("exit 1"), ProcessOutput::OggOpus).await,
+            Err(Error::ProcessExit(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stop_kills_process() -> anyhow::Result<()> {
+        let mut source = ChildSource::spawn(shell("exec cat /dev/zero"), PCM).await?;
+
+        assert!(source.read_frame().await?.is_some());
+        source.stop().await?;
+
+        // Being killed on request is not a failure.
+        assert_eq!(source.read_frame().await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn kills_process_writing_garbage() {
+        let script = "head -c 64 /dev/zero; exec sleep 30";
+        let spawn = ChildSource::spawn(shell(script), ProcessOutput::OggOpus);
+
+        assert!(matches!(
+            tokio::time::timeout(Duration::from_secs(10), spawn).await,
+            Ok(Err(Error::MalformedAudio(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn kills_process_lingering_after_output() -> anyhow::Result<()> {
+        let script = "head -c 3840 /dev/zero; exec sleep 30 >&-";
+        let mut source = ChildSource::spawn(shell(script), PCM).await?;
+
+        assert!(source.read_frame().await?.is_some());
+        let end = tokio::time::timeout(Duration::from_secs(10), source.read_frame()).await?;
+        assert_eq!(end?, None);
+        assert_eq!(source.read_frame().await?, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,272 @@
+use std::{io::SeekFrom, path::Path, time::Duration};
+
+use tokio::io::{AsyncRead, AsyncSeek};
+
+use super::{AudioFormat, AudioFrame, AudioSource, Reader};
+use crate::{Error, Result};
+
+/// Encoding of a single little-endian PCM sample.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SampleFormat {
+    /// Unsigned 8-bit integer, centred on 128.
+    U8,
+    /// Signed 16-bit integer.
+    I16,
+    /// Signed 24-bit integer, packed into three bytes.
+    I24,
+    /// Signed 32-bit integer.
+    I32,
+    /// 32-bit IEEE float.
+    F32,
+}
+
+impl SampleFormat {
+    pub const fn bytes_per_sample(self) -> usize {
+        match self {
+            Self::U8 => 1,
+            Self::I16 => 2,
+            Self::I24 => 3,
+            Self::I32 | Self::F32 => 4,
+        }
+    }
+
+    /// Convert packed samples into floats, ignoring any trailing partial sample.
+    pub fn decode(self, bytes: &[u8], out: &mut Vec<f32>) {
+        let chunks = bytes.chunks_exact(self.bytes_per_sample());
+        out.reserve(chunks.len());
+
+        match self {
+            Self::U8 => out.extend(chunks.map(|b| (f32::from(b[0]) - 128.0) / 128.0)),
+            Self::I16 => {
+                out.extend(chunks.map(|b| f32::from(i16::from_le_bytes([b[0], b[1]])) / 32_768.0))
+            }
+            Self::I24 => out
+                .extend(chunks.map(|b| {
+                    (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8_388_608.0
+                })),
+            Self::I32 => out
+                .extend(chunks.map(|b| {
+                    i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2_147_483_648.0
+                })),
+            Self::F32 => out.extend(chunks.map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))),
+        }
+    }
+}
+
+/// Headerless PCM read from a file or any other byte stream.
+pub struct RawPcmSource {
+    reader: Reader,
+    format: AudioFormat,
+    sample_format: SampleFormat,
+    /// Offset of the first sample within `reader`.
+    start: u64,
+    /// Number of bytes of audio data, if bounded.
+    len: Option<u64>,
+    /// Number of bytes of audio data consumed so far.
+    read: u64,
+    buffer: Vec<u8>,
+}
+
+impl RawPcmSource {
+    /// Stream PCM from a reader which cannot seek, such as a pipe or socket.
+    pub fn new(
+        reader: impl AsyncRead + Send + Unpin + 'static,
+        format: AudioFormat,
+        sample_format: SampleFormat,
+    ) -> Self {
+        Self::from_reader(Reader::stream(reader), format, sample_format)
+    }
+
+    /// Read PCM from a seekable reader, such as a file or an in-memory cursor.
+    pub fn seekable(
+        reader: impl AsyncRead + AsyncSeek + Send + Unpin + 'static,
+        format: AudioFormat,
+        sample_format: SampleFormat,
+    ) -> Self {
+        Self::from_reader(Reader::seekable(reader), format, sample_format)
+    }
+
+    /// Open a file holding headerless PCM.
+    pub async fn open(
+        path: impl AsRef<Path>,
+        format: AudioFormat,
+        sample_format: SampleFormat,
+    ) -> Result<Self> {
+        let file = tokio::fs::File::open(path).await?;
+
+        Ok(Self::seekable(file, format, sample_format))
+    }
+
+    pub(crate) fn from_reader(
+        reader: Reader,
+        format: AudioFormat,
+        sample_format: SampleFormat,
+    ) -> Self {
+        Self {
+            reader,
+            format,
+            sample_format,
+            start: 0,
+            len: None,
+            read: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Restrict reading to `len` bytes of audio data beginning at `start`.
+    ///
+    /// The reader must already be positioned at `start`.
+    pub(crate) fn with_bounds(mut self, start: u64, len: Option<u64>) -> Self {
+        self.start = start;
+        self.len = len;
+        self
+    }
+
+    fn bytes_per_frame(&self) -> usize {
+        self.format.frame_size() * self.sample_format.bytes_per_sample()
+    }
+
+    fn bytes_per_second(&self) -> u64 {
+        u64::from(self.format.sample_rate)
+            * u64::from(self.format.channels)
+            * self.sample_format.bytes_per_sample() as u64
+    }
+}
+
+#[async_trait::async_trait]
+impl AudioSource for RawPcmSource {
+    fn format(&self) -> AudioFormat {
+        self.format
+    }
+
+    async fn read_frame(&mut self) -> Result<Option<AudioFrame>> {
+        let mut want = self.bytes_per_frame() as u64;
+        if let Some(len) = self.len {
+            want = want.min(len.saturating_sub(self.read));
+        }
+
+        self.buffer.resize(want as usize, 0);
+        let filled = self.reader.read_full(&mut self.buffer).await?;
+        self.read += filled as u64;
+
+        let mut samples = Vec::new();
+        self.sample_format
+            .decode(&self.buffer[..filled], &mut samples);
+        samples.truncate(samples.len() - samples.len() % self.format.channels.max(1) as usize);
+
+        if samples.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(AudioFrame::Pcm(samples)))
+    }
+
+    fn is_seekable(&self) -> bool {
+        self.reader.is_seekable()
+    }
+
+    async fn seek(&mut self, position: Duration) -> Result<Duration> {
+        if self.bytes_per_second() == 0 {
+            return Err(Error::UnsupportedFormat(
+                "zero channels or sample rate".into(),
+            ));
+        }
+
+        let block = self.format.channels as u64 * self.sample_format.bytes_per_sample() as u64;
+        let mut offset =
+            (position.as_nanos() * u128::from(self.bytes_per_second()) / 1_000_000_000) as u64;
+        offset -= offset % block;
+        if let Some(len) = self.len {
+            offset = offset.min(len - len % block);
+        }
+
+        self.reader
+            .seek(SeekFrom::Start(self.start + offset))
+            .await?;
+        self.read = offset;
+
+        Ok(Duration::from_nanos(
+            (u128::from(offset) * 1_000_000_000 / u128::from(self.bytes_per_second())) as u64,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, time::Duration};
+
+    use super::{RawPcmSource, SampleFormat};
+    use crate::{
+        input::{AudioFormat, AudioFrame, AudioSource},
+        Error,
+    };
+
+    fn pcm_bytes(samples: &[i16]) -> Vec<u8> {
+        samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn decode_formats() {
+        let mut out = Vec::new();
+        SampleFormat::U8.decode(&[0, 128], &mut out);
+        SampleFormat::I16.decode(&i16::MIN.to_le_bytes(), &mut out);
+        SampleFormat::I24.decode(&[0x00, 0x00, 0x40], &mut out);
+        SampleFormat::F32.decode(&0.25f32.to_le_bytes(), &mut out);
+
+        assert_eq!(out, [-1.0, 0.0, -1.0, 0.5, 0.25]);
+    }
+
+    #[tokio::test]
+    async fn reads_frames_from_stream() {
+        let format = AudioFormat::new(8_000, 1);
+        // 1.5 frames of audio.
+        let data = pcm_bytes(&[1000; 240]);
+        let mut source = RawPcmSource::new(Cursor::new(data), format, SampleFormat::I16);
+
+        assert!(!source.is_seekable());
+        assert!(matches!(
+            source.read_frame().await,
+            Ok(Some(AudioFrame::Pcm(s))) if s.len() == 160
+        ));
+        assert!(matches!(
+            source.read_frame().await,
+            Ok(Some(AudioFrame::Pcm(s))) if s.len() == 80
+        ));
+        assert!(matches!(source.read_frame().await, Ok(None)));
+        assert!(matches!(
+            source.seek(Duration::ZERO).await,
+            Err(Error::NotSeekable)
+        ));
+    }
+
+    #[tokio::test]
+    async fn seeks_within_file() -> anyhow::Result<()> {
+        let format = AudioFormat::new(8_000, 2);
+        let samples = (0..16_000).map(|i| i as i16).collect::<Vec<_>>();
+        let mut source =
+            RawPcmSource::seekable(Cursor::new(pcm_bytes(&samples)), format, SampleFormat::I16);
+
+        let reached = source.seek(Duration::from_millis(500)).await?;
+        assert_eq!(reached, Duration::from_millis(500));
+
+        let Some(AudioFrame::Pcm(frame)) = source.read_frame().await? else {
+            panic!("expected a PCM frame");
+        };
+        assert_eq!(frame[0], 8_000.0 / 32_768.0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_seeking_empty_format() {
+        for format in [AudioFormat::new(8_000, 0), AudioFormat::new(0, 2)] {
+            let data = Cursor::new(pcm_bytes(&[0; 320]));
+            let mut source = RawPcmSource::seekable(data, format, SampleFormat::I16);
+
+            assert!(matches!(
+                source.seek(Duration::from_millis(10)).await,
+                Err(Error::UnsupportedFormat(_))
+            ));
+        }
+    }
+}
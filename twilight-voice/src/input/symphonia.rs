@@ -0,0 +1,312 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use ::symphonia::core::{
+    audio::SampleBuffer,
+    codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL},
+    errors::{Error as SymphoniaError, SeekErrorKind},
+    formats::{FormatOptions, FormatReader, SeekMode, SeekTo},
+    io::{MediaSource, MediaSourceStream},
+    meta::MetadataOptions,
+    probe::Hint,
+    units::TimeBase,
+};
+
+use super::{AudioFormat, AudioFrame, AudioSource, Converter, ResampleQuality};
+use crate::{constants, Error, Result};
+
+/// Blocking decoder state, locked on the blocking thread pool for each call into Symphonia.
+struct Decoding {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    buffer: Option<SampleBuffer<f32>>,
+}
+
+impl Decoding {
+    fn probe(source: Box<dyn MediaSource>, extension: Option<String>) -> Result<Self> {
+        let mut hint = Hint::new();
+        if let Some(extension) = &extension {
+            hint.with_extension(extension);
+        }
+
+        let stream = MediaSourceStream::new(source, Default::default());
+        let probed = ::symphonia::default::get_probe().format(
+            &hint,
+            stream,
+            &FormatOptions {
+                enable_gapless: true,
+                ..Default::default()
+            },
+            &MetadataOptions::default(),
+        )?;
+
+        let track = probed
+            .format
+            .tracks()
+            .iter()
+            .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or_else(|| Error::UnsupportedFormat("no decodable audio track".into()))?;
+        let decoder = ::symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())?;
+
+        Ok(Self {
+            track_id: track.id,
+            time_base: track.codec_params.time_base,
+            format: probed.format,
+            decoder,
+            buffer: None,
+        })
+    }
+
+    /// Decode the next packet of the selected track into interleaved samples and their format.
+    fn next(&mut self) -> Result<Option<(Vec<f32>, AudioFormat)>> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(e))
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    return Ok(None)
+                }
+                // The set of tracks changed, as in chained Ogg streams; treat it as the end.
+                Err(SymphoniaError::ResetRequired) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(SymphoniaError::DecodeError(error)) => {
+                    tracing::debug!(error, "skipping undecodable packet");
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let spec = *decoded.spec();
+            let buffer = match &mut self.buffer {
+                Some(buffer) if buffer.capacity() >= decoded.capacity() * spec.channels.count() => {
+                    buffer
+                }
+                slot => slot.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+            };
+            buffer.copy_interleaved_ref(decoded);
+
+            let format = AudioFormat::new(spec.rate, spec.channels.count() as u16);
+
+            return Ok(Some((buffer.samples().to_vec(), format)));
+        }
+    }
+
+    fn seek(&mut self, position: Duration) -> Result<Duration> {
+        let seeked = self
+            .format
+            .seek(
+                SeekMode::Coarse,
+                SeekTo::Time {
+                    time: position.into(),
+                    track_id: Some(self.track_id),
+                },
+            )
+            .map_err(|e| match e {
+                SymphoniaError::SeekError(SeekErrorKind::Unseekable) => Error::NotSeekable,
+                e => e.into(),
+            })?;
+        self.decoder.reset();
+
+        let reached = match self.time_base {
+            Some(time_base) => {
+                let time = time_base.calc_time(seeked.actual_ts);
+                Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac)
+            }
+            None => position,
+        };
+
+        Ok(reached)
+    }
+}
+
+/// Audio in any container and codec supported by Symphonia, such as MP3, FLAC, AAC/M4A or
+/// Vorbis, decoded and resampled to 48 kHz stereo.
+///
+/// Requires the `symphonia` feature.
+pub struct SymphoniaSource {
+    /// Shared with the blocking task, so that the decoder outlives a call which is cancelled.
+    decoding: Arc<Mutex<Decoding>>,
+    seekable: bool,
+    quality: ResampleQuality,
+    converter: Option<Converter>,
+    /// Resampled audio which has not yet filled a whole frame.
+    pcm: Vec<f32>,
+    finished: bool,
+}
+
+impl SymphoniaSource {
+    /// Probe and decode `source`, using the file extension, if known, as a hint to the format.
+    pub async fn new(source: impl MediaSource + 'static, extension: Option<&str>) -> Result<Self> {
+        let seekable = source.is_seekable();
+        let extension = extension.map(str::to_owned);
+        let decoding =
+            tokio::task::spawn_blocking(move || Decoding::probe(Box::new(source), extension))
+                .await??;
+
+        Ok(Self {
+            decoding: Arc::new(Mutex::new(decoding)),
+            seekable,
+            quality: ResampleQuality::default(),
+            converter: None,
+            pcm: Vec::new(),
+            finished: false,
+        })
+    }
+
+    /// Use `quality` when resampling audio which is not already at 48 kHz.
+    pub fn with_quality(mut self, quality: ResampleQuality) -> Self {
+        self.quality = quality;
+        self
+    }
+
+    /// Open an audio file.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        let file = tokio::fs::File::open(path).await?.into_std().await;
+
+        Self::new(file, extension).await
+    }
+
+    /// Run `f` against the decoder on the blocking thread pool.
+    ///
+    /// Fails with [`Error::DecoderLost`] once a call has panicked, leaving the decoder in an
+    /// unknown state.
+    async fn blocking<T: Send + 'static>(
+        &mut self,
+        f: impl FnOnce(&mut Decoding) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let decoding = self.decoding.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut decoding = decoding.lock().map_err(|_| Error::DecoderLost)?;
+            f(&mut decoding)
+        })
+        .await?
+    }
+}
+
+#[async_trait::async_trait]
+impl AudioSource for SymphoniaSource {
+    fn format(&self) -> AudioFormat {
+        AudioFormat::DISCORD
+    }
+
+    async fn read_frame(&mut self) -> Result<Option<AudioFrame>> {
+        while self.pcm.len() < constants::STEREO_FRAME_SIZE && !self.finished {
+            match self.blocking(Decoding::next).await? {
+                Some((samples, format)) => {
+                    let converter = match &mut self.converter {
+                        Some(converter) if converter.format() == format => converter,
+                        slot => {
+                            if let Some(previous) = slot {
+                                previous.flush(&mut self.pcm);
+                            }
+                            slot.insert(Converter::new(format, self.quality))
+                        }
+                    };
+                    converter.process(&samples, &mut self.pcm);
+                }
+                None => {
+                    if let Some(converter) = &mut self.converter {
+                        converter.flush(&mut self.pcm);
+                    }
+                    self.finished = true;
+                }
+            }
+        }
+
+        if self.pcm.is_empty() {
+            return Ok(None);
+        }
+
+        let len = self.pcm.len().min(constants::STEREO_FRAME_SIZE);
+
+        Ok(Some(AudioFrame::Pcm(self.pcm.drain(..len).collect())))
+    }
+
+    fn is_seekable(&self) -> bool {
+        self.seekable
+    }
+
+    async fn seek(&mut self, position: Duration) -> Result<Duration> {
+        if !self.seekable {
+            return Err(Error::NotSeekable);
+        }
+
+        let reached = self
+            .blocking(move |decoding| decoding.seek(position))
+            .await?;
+        if let Some(converter) = &mut self.converter {
+            converter.reset();
+        }
+        self.pcm.clear();
+        self.finished = false;
+
+        Ok(reached)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, time::Duration};
+
+    use super::SymphoniaSource;
+    use crate::{
+        constants,
+        input::{wav::tests::wav_bytes, AudioFormat, AudioFrame, AudioSource},
+        Error, Result,
+    };
+
+    #[tokio::test]
+    async fn decodes_and_resamples_to_discord_format() -> anyhow::Result<()> {
+        let format = AudioFormat::new(44_100, 1);
+        let wav = wav_bytes(format, &vec![8_192; 44_100], false);
+        let mut source = SymphoniaSource::new(Cursor::new(wav), Some("wav")).await?;
+
+        assert_eq!(source.format(), AudioFormat::DISCORD);
+
+        let mut samples = 0;
+        while let Some(AudioFrame::Pcm(frame)) = source.read_frame().await? {
+            assert!(frame.iter().all(|s| (s - 0.25).abs() < 1e-6));
+            samples += frame.len();
+        }
+        assert_eq!(samples, constants::SAMPLE_RATE as usize * 2);
+
+        assert!(source.is_seekable());
+        let reached = source.seek(Duration::from_millis(500)).await?;
+        assert!(reached <= Duration::from_millis(500));
+        assert!(source.read_frame().await?.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_lost_decoder() -> anyhow::Result<()> {
+        let wav = wav_bytes(AudioFormat::DISCORD, &[0; 1920], false);
+        let mut source = SymphoniaSource::new(Cursor::new(wav), Some("wav")).await?;
+
+        let panicked = source
+            .blocking(|_| -> Result<()> { panic!("decoder failed") })
+            .await;
+        assert!(matches!(panicked, Err(Error::Join(_))));
+        assert!(matches!(source.read_frame().await, Err(Error::DecoderLost)));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+use std::{path::Path, time::Duration};
+
+use tokio::io::{AsyncRead, AsyncSeek};
+
+use super::{AudioFormat, AudioFrame, AudioSource, RawPcmSource, Reader, SampleFormat};
+use crate::{Error, Result};
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+/// Length of the largest fmt chunk read, that of `WAVE_FORMAT_EXTENSIBLE`. Any more is skipped.
+const MAX_FMT_LEN: u32 = 40;
+
+/// PCM audio held in a RIFF/WAVE container.
+pub struct WavSource {
+    inner: RawPcmSource,
+}
+
+impl WavSource {
+    /// Stream a WAV file from a reader which cannot seek.
+    pub async fn new(reader: impl AsyncRead + Send + Unpin + 'static) -> Result<Self> {
+        Self::from_reader(Reader::stream(reader)).await
+    }
+
+    /// Read a WAV file from a seekable reader, such as a file or an in-memory cursor.
+    pub async fn seekable(
+        reader: impl AsyncRead + AsyncSeek + Send + Unpin + 'static,
+    ) -> Result<Self> {
+        Self::from_reader(Reader::seekable(reader)).await
+    }
+
+    /// Open a WAV file.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let file = tokio::fs::File::open(path).await?;
+
+        Self::seekable(file).await
+    }
+
+    async fn from_reader(mut reader: Reader) -> Result<Self> {
+        let mut header = [0; 12];
+        if reader.read_full(&mut header).await? != header.len()
+            || &header[..4] != b"RIFF"
+            || &header[8..] != b"WAVE"
+        {
+            return Err(Error::MalformedAudio("missing RIFF/WAVE header".into()));
+        }
+
+        let mut offset = header.len() as u64;
+        let mut spec = None;
+
+        loop {
+            let mut chunk = [0; 8];
+            if reader.read_full(&mut chunk).await? != chunk.len() {
+                return Err(Error::MalformedAudio("missing data chunk".into()));
+            }
+            offset += chunk.len() as u64;
+
+            let len = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+
+            match &chunk[..4] {
+                b"fmt " => {
+                    let mut body = vec![0; len.min(MAX_FMT_LEN) as usize];
+                    if reader.read_full(&mut body).await? != body.len() {
+                        return Err(Error::MalformedAudio("truncated fmt chunk".into()));
+                    }
+                    reader
+                        .skip(u64::from(len.saturating_sub(MAX_FMT_LEN)))
+                        .await?;
+                    spec = Some(parse_fmt(&body)?);
+                }
+                b"data" => {
+                    let Some((format, sample_format)) = spec else {
+                        return Err(Error::MalformedAudio("data chunk before fmt chunk".into()));
+                    };
+                    // Streaming encoders can't know the final length up front.
+                    let len = match len {
+                        u32::MAX => None,
+                        len => Some(u64::from(len)),
+                    };
+
+                    let inner = RawPcmSource::from_reader(reader, format, sample_format)
+                        .with_bounds(offset, len);
+
+                    return Ok(Self { inner });
+                }
+                _ => reader.skip(u64::from(len)).await?,
+            }
+
+            // Chunks are padded to an even length.
+            let padded = u64::from(len) + u64::from(len & 1);
+            if padded != u64::from(len) {
+                reader.skip(1).await?;
+            }
+            offset += padded;
+        }
+    }
+}
+
+fn parse_fmt(body: &[u8]) -> Result<(AudioFormat, SampleFormat)> {
+    if body.len() < 16 {
+        return Err(Error::MalformedAudio("truncated fmt chunk".into()));
+    }
+
+    let u16_at = |i: usize| u16::from_le_bytes([body[i], body[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes([body[i], body[i + 1], body[i + 2], body[i + 3]]);
+
+    let mut tag = u16_at(0);
+    let channels = u16_at(2);
+    let sample_rate = u32_at(4);
+    let bits = u16_at(14);
+
+    if tag == FORMAT_EXTENSIBLE {
+        if body.len() < 26 {
+            return Err(Error::MalformedAudio(
+                "truncated extensible fmt chunk".into(),
+            ));
+        }
+        // The sub-format GUID begins with the plain format tag.
+        tag = u16_at(24);
+    }
+
+    let sample_format = match (tag, bits) {
+        (FORMAT_PCM, 8) => SampleFormat::U8,
+        (FORMAT_PCM, 16) => SampleFormat::I16,
+        (FORMAT_PCM, 24) => SampleFormat::I24,
+        (FORMAT_PCM, 32) => SampleFormat::I32,
+        (FORMAT_FLOAT, 32) => SampleFormat::F32,
+        _ => {
+            return Err(Error::UnsupportedFormat(format!(
+                "WAV format tag {tag} with {bits} bits per sample"
+            )))
+        }
+    };
+
+    if channels == 0 || sample_rate == 0 {
+        return Err(Error::MalformedAudio("zero channels or sample rate".into()));
+    }
+
+    Ok((AudioFormat::new(sample_rate, channels), sample_format))
+}
+
+#[async_trait::async_trait]
+impl AudioSource for WavSource {
+    fn format(&self) -> AudioFormat {
+        self.inner.format()
+    }
+
+    async fn read_frame(&mut self) -> Result<Option<AudioFrame>> {
+        self.inner.read_frame().await
+    }
+
+    fn is_seekable(&self) -> bool {
+        self.inner.is_seekable()
+    }
+
+    async fn seek(&mut self, position: Duration) -> Result<Duration> {
+        self.inner.seek(position).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{io::Cursor, time::Duration};
+
+    use super::WavSource;
+    use crate::{
+        input::{AudioFormat, AudioFrame, AudioSource},
+        Error,
+    };
+
+    /// Build a 16-bit WAV file, optionally with an odd-length LIST chunk before the audio data.
+    pub(crate) fn wav_bytes(format: AudioFormat, samples: &[i16], list: bool) -> Vec<u8> {
+        let data_len = samples.len() as u32 * 2;
+        let list_len = if list { 10 } else { 0 };
+        let mut out = Vec::new();
+        out.extend_from_slice(b"RIFF");
+        out.extend_from_slice(&(4 + 24 + list_len + 8 + data_len).to_le_bytes());
+        out.extend_from_slice(b"WAVE");
+        out.extend_from_slice(b"fmt ");
+        out.extend_from_slice(&16u32.to_le_bytes());
+        out.extend_from_slice(&1u16.to_le_bytes());
+        out.extend_from_slice(&format.channels.to_le_bytes());
+        out.extend_from_slice(&format.sample_rate.to_le_bytes());
+        out.extend_from_slice(&(format.sample_rate * u32::from(format.channels) * 2).to_le_bytes());
+        out.extend_from_slice(&(format.channels * 2).to_le_bytes());
+        out.extend_from_slice(&16u16.to_le_bytes());
+        if list {
+            out.extend_from_slice(b"LIST");
+            out.extend_from_slice(&1u32.to_le_bytes());
+            out.extend_from_slice(&[0, 0]);
+        }
+        out.extend_from_slice(b"data");
+        out.extend_from_slice(&data_len.to_le_bytes());
+        out.extend(samples.iter().flat_map(|s| s.to_le_bytes()));
+        out
+    }
+
+    #[tokio::test]
+    async fn reads_header_and_frames() -> anyhow::Result<()> {
+        let format = AudioFormat::new(44_100, 2);
+        let samples = vec![-16_384; format.frame_size() * 3];
+        let mut source =
+            WavSource::seekable(Cursor::new(wav_bytes(format, &samples, true))).await?;
+
+        assert_eq!(source.format(), format);
+
+        let mut frames = 0;
+        while let Some(frame) = source.read_frame().await? {
+            assert_eq!(frame, AudioFrame::Pcm(vec![-0.5; format.frame_size()]));
+            frames += 1;
+        }
+        assert_eq!(frames, 3);
+
+        assert_eq!(
+            source.seek(Duration::from_millis(40)).await?,
+            Duration::from_millis(40)
+        );
+        assert!(source.read_frame().await?.is_some());
+        assert!(source.read_frame().await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn opens_fixture_file() -> anyhow::Result<()> {
+        let format = AudioFormat::new(48_000, 1);
+        let path = std::env::temp_dir().join(format!("twilight-voice-{}.wav", std::process::id()));
+        tokio::fs::write(&path, wav_bytes(format, &[0; 960], true)).await?;
+
+        let mut source = WavSource::open(&path).await?;
+        tokio::fs::remove_file(&path).await?;
+
+        assert!(source.is_seekable());
+        assert!(source.read_frame().await?.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skips_oversized_fmt_chunk() -> anyhow::Result<()> {
+        let format = AudioFormat::new(48_000, 1);
+        let mut bytes = wav_bytes(format, &[0; 960], true);
+        // Grow the fmt chunk from 16 to 64 bytes.
+        bytes[16..20].copy_from_slice(&64u32.to_le_bytes());
+        bytes.splice(36..36, [0; 48]);
+
+        let mut source = WavSource::new(Cursor::new(bytes)).await?;
+        assert_eq!(source.format(), format);
+        assert!(source.read_frame().await?.is_some());
+
+        // A fmt chunk claiming to fill the file is not allocated up front.
+        let mut bytes = wav_bytes(format, &[], true);
+        bytes[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            WavSource::new(Cursor::new(bytes)).await,
+            Err(Error::MalformedAudio(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ends_at_empty_data_chunk() -> anyhow::Result<()> {
+        let mut bytes = wav_bytes(AudioFormat::new(48_000, 1), &[], true);
+        bytes.extend_from_slice(b"LIST");
+        bytes.extend_from_slice(&8u32.to_le_bytes());
+        bytes.extend_from_slice(b"INFOISFT");
+
+        let mut source = WavSource::new(Cursor::new(bytes)).await?;
+        assert!(source.read_frame().await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_garbage() {
+        assert!(matches!(
+            WavSource::new(Cursor::new(b"not a wav file".to_vec())).await,
+            Err(Error::MalformedAudio(_))
+        ));
+    }
+}
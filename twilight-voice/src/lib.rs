@@ -1,5 +1,25 @@
+// `Error` holds the tungstenite error unboxed, as it always has, which makes clippy flag every
+// function returning `Result`.
+#![allow(clippy::result_large_err)]
+
 pub mod client;
+pub mod codec;
+pub mod config;
+pub mod constants;
+pub mod crypto;
+mod driver;
+pub mod events;
 pub mod gateway;
+pub mod input;
+mod mixer;
+pub mod queue;
+pub mod receive;
+pub mod record;
+pub mod rtcp;
+pub mod stats;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+pub mod track;
 pub mod types;
 pub mod voice;
 
@@ -17,16 +37,60 @@ pub enum Error {
     Io(#[from] std::io::Error),
     #[error("SystemTime error: {0}")]
     SystemTime(#[from] std::time::SystemTimeError),
+    #[error("Audio source does not support seeking.")]
+    NotSeekable,
+    #[error("Unsupported audio format: {0}")]
+    UnsupportedFormat(String),
+    #[error("Malformed audio data: {0}")]
+    MalformedAudio(String),
+    #[error("VoiceClient is not connected to a voice server.")]
+    NotConnected,
+    #[error("Timed out connecting to the voice gateway.")]
+    ConnectTimeout,
+    #[error("Voice server did not provide an endpoint.")]
+    NoEndpoint,
+    #[error("Voice gateway closed with code {0:?}.")]
+    GatewayClosed(Option<twilight_voice_model::CloseCode>),
+    #[error("IP discovery returned an invalid address.")]
+    IpDiscovery,
+    #[error("IP discovery got no response.")]
+    IpDiscoveryTimeout,
+    #[error("No supported encryption mode among {0:?}.")]
+    NoCompatibleCryptoMode(Vec<String>),
+    #[error("Failed to encrypt or decrypt a voice packet.")]
+    Crypto,
+    #[error("A recording is already in progress.")]
+    AlreadyRecording,
+    #[error("Encoding or decoding Opus requires the `opus` feature.")]
+    OpusUnavailable,
+    #[cfg(feature = "opus")]
+    #[error("Opus error: {0}")]
+    Opus(#[from] audiopus::Error),
+    #[cfg(feature = "symphonia")]
+    #[error("Symphonia error: {0}")]
+    Symphonia(#[from] symphonia::core::errors::Error),
+    #[error("Invalid voice gateway payload: {0}")]
+    InvalidPayload(#[from] twilight_voice_model::builder::BuildError),
+    #[error("Audio decoder panicked and can no longer be used.")]
+    DecoderLost,
+    #[error("Child process exited with {0}.")]
+    ProcessExit(std::process::ExitStatus),
+    #[error("Background task failed: {0}")]
+    Join(#[from] tokio::task::JoinError),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[cfg(test)]
 mod tests {
-    use std::env;
+    use std::{env, time::Duration};
 
     use super::*;
-    use client::{PartialVoiceStateUpdate, VoiceUpdate};
+    use client::{PartialVoiceStateUpdate, VoiceClient, VoiceUpdate};
+    use events::VoiceEvent;
+    use input::MemorySource;
+    use test_util::{MockVoiceServer, MockVoiceUpdate};
+    use tokio::{sync::broadcast, time};
     use twilight_gateway::{Event, Intents, MessageSender, Shard, ShardId};
     use twilight_model::{
         gateway::payload::outgoing::UpdateVoiceState,
@@ -35,6 +99,7 @@ mod tests {
             Id,
         },
     };
+    use twilight_voice_model::{CloseCode, OpCode};
 
     struct TwilightVoiceUpdate {
         sender: MessageSender,
@@ -57,7 +122,231 @@ mod tests {
         }
     }
 
+    /// Wait for the next event of kind `op` sent to `server`, skipping any others.
+    async fn expect_sent(server: &mut MockVoiceServer, op: OpCode) -> twilight_voice_model::Event {
+        time::timeout(Duration::from_secs(5), async {
+            loop {
+                let event = server.next_event().await.expect("server stopped");
+                if event.kind() == op {
+                    return event;
+                }
+            }
+        })
+        .await
+        .expect("timed out waiting for event")
+    }
+
+    async fn expect_event(events: &mut broadcast::Receiver<VoiceEvent>) -> VoiceEvent {
+        time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("timed out waiting for event")
+            .expect("events closed")
+    }
+
+    /// Join a channel with `server` as the voice server, answering the client as the Discord
+    /// gateway would.
+    async fn connect(
+        server: &MockVoiceServer,
+    ) -> Result<(VoiceClient<MockVoiceUpdate>, MockVoiceUpdate)> {
+        let gateway = MockVoiceUpdate::new(Id::new(3), server.endpoint());
+        let config = server.configure(config::Config::default());
+        let mut client = VoiceClient::new(gateway.clone(), Id::new(1), Id::new(2), config);
+
+        client.join(false, false).await?;
+        while let Some(event) = gateway.try_next_event() {
+            event.dispatch(&mut client).await?;
+        }
+
+        Ok((client, gateway))
+    }
+
+    #[tokio::test]
+    async fn plays_through_mock_server() -> anyhow::Result<()> {
+        let mut server = MockVoiceServer::start().await?;
+        let (client, _) = connect(&server).await?;
+
+        let twilight_voice_model::Event::Identify(identify) =
+            expect_sent(&mut server, OpCode::Identify).await
+        else {
+            unreachable!()
+        };
+        assert!(identify.token.starts_with(MockVoiceServer::TOKEN));
+        assert_eq!(identify.session_id, MockVoiceUpdate::SESSION_ID);
+        expect_sent(&mut server, OpCode::SelectProtocol).await;
+
+        client.play(MemorySource::opus(vec![vec![1, 2, 3]; 2]));
+        expect_sent(&mut server, OpCode::Speaking).await;
+        for _ in 0..2 {
+            let packet = time::timeout(Duration::from_secs(5), server.next_packet()).await?;
+            let packet = packet.expect("server stopped");
+            assert_eq!(packet.ssrc, MockVoiceServer::SSRC);
+            assert_eq!(packet.payload, [1, 2, 3]);
+        }
+        assert!(server.crypto_mode().is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resumes_after_server_crash() -> anyhow::Result<()> {
+        let mut server = MockVoiceServer::start().await?;
+        let (client, _) = connect(&server).await?;
+        let mut events = client.events();
+        expect_sent(&mut server, OpCode::SelectProtocol).await;
+
+        server.close(Some(CloseCode::VoiceServerCrash));
+        expect_sent(&mut server, OpCode::Resume).await;
+        assert!(matches!(
+            expect_event(&mut events).await,
+            VoiceEvent::Reconnecting { attempt: 0, .. }
+        ));
+        assert!(matches!(
+            expect_event(&mut events).await,
+            VoiceEvent::Reconnected
+        ));
+        assert_eq!(client.stats().reconnects, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn starts_new_session_when_invalidated() -> anyhow::Result<()> {
+        let mut server = MockVoiceServer::start().await?;
+        let (mut client, gateway) = connect(&server).await?;
+        let mut events = client.events();
+        expect_sent(&mut server, OpCode::SelectProtocol).await;
+
+        server.close(Some(CloseCode::SessionNoLongerValid));
+        // Stand in for the bot's event loop while the driver requests a new session.
+        for _ in 0..2 {
+            let event = time::timeout(Duration::from_secs(5), gateway.next_event()).await?;
+            event
+                .expect("gateway stopped")
+                .dispatch(&mut client)
+                .await?;
+        }
+        expect_sent(&mut server, OpCode::Identify).await;
+        expect_sent(&mut server, OpCode::SelectProtocol).await;
+
+        assert_eq!(gateway.requests().len(), 2);
+        assert!(matches!(
+            expect_event(&mut events).await,
+            VoiceEvent::Reconnecting { attempt: 0, .. }
+        ));
+        assert!(matches!(
+            expect_event(&mut events).await,
+            VoiceEvent::Reconnected
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn follows_move_to_another_channel() -> anyhow::Result<()> {
+        let mut server = MockVoiceServer::start().await?;
+        let (mut client, gateway) = connect(&server).await?;
+        expect_sent(&mut server, OpCode::SelectProtocol).await;
+
+        gateway.move_to(Id::new(1), Id::new(5));
+        while let Some(event) = gateway.try_next_event() {
+            event.dispatch(&mut client).await?;
+        }
+        expect_sent(&mut server, OpCode::Identify).await;
+        expect_sent(&mut server, OpCode::SelectProtocol).await;
+
+        // A new session is requested for the channel moved to.
+        server.close(Some(CloseCode::SessionNoLongerValid));
+        let event = time::timeout(Duration::from_secs(5), gateway.next_event()).await?;
+        assert!(event.is_some());
+        assert_eq!(gateway.requests()[1].channel_id, Some(Id::new(5)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mutes_while_connected() -> anyhow::Result<()> {
+        let mut server = MockVoiceServer::start().await?;
+        let (mut client, gateway) = connect(&server).await?;
+
+        client.play(MemorySource::opus(vec![vec![1, 2, 3]; 500]));
+        let packet = time::timeout(Duration::from_secs(5), server.next_packet()).await?;
+        assert_eq!(packet.expect("server stopped").payload, [1, 2, 3]);
+
+        client.set_mute(true).await?;
+        assert!(gateway.requests()[1].self_mute);
+        // Answering the update does not disturb the session.
+        while let Some(event) = gateway.try_next_event() {
+            event.dispatch(&mut client).await?;
+        }
+
+        // Audio stops after a few frames of silence, and speaking is cleared.
+        let mut last = Vec::new();
+        while let Ok(packet) = time::timeout(Duration::from_millis(200), server.next_packet()).await
+        {
+            last = packet.expect("server stopped").payload;
+        }
+        assert_eq!(last, codec::SILENCE_FRAME);
+        loop {
+            let twilight_voice_model::Event::Speaking(speaking) =
+                expect_sent(&mut server, OpCode::Speaking).await
+            else {
+                unreachable!()
+            };
+            if speaking.speaking.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(
+            client.connection_state(),
+            client::ConnectionState::Connected
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn plays_on_while_next_track_starts() -> anyhow::Result<()> {
+        /// A source which takes a while to produce anything, as a process starting up does.
+        struct SlowStart;
+
+        #[async_trait::async_trait]
+        impl input::AudioSource for SlowStart {
+            fn format(&self) -> input::AudioFormat {
+                input::AudioFormat::DISCORD
+            }
+
+            async fn read_frame(&mut self) -> Result<Option<input::AudioFrame>> {
+                time::sleep(Duration::from_secs(2)).await;
+                Ok(None)
+            }
+        }
+
+        let mut server = MockVoiceServer::start().await?;
+        let (client, _) = connect(&server).await?;
+
+        client.play(MemorySource::opus(vec![vec![1, 2, 3]; 500]));
+        let first = time::timeout(Duration::from_secs(5), server.next_packet()).await?;
+        let mut sequence = first.expect("server stopped").sequence;
+        client.queue().enqueue(SlowStart);
+
+        let mut last = time::Instant::now();
+        for _ in 0..100 {
+            let packet = time::timeout(Duration::from_secs(5), server.next_packet()).await?;
+            let packet = packet.expect("server stopped");
+            assert_eq!(packet.sequence, sequence.wrapping_add(1));
+            assert!(
+                last.elapsed() < Duration::from_millis(500),
+                "playback stalled"
+            );
+            sequence = packet.sequence;
+            last = time::Instant::now();
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
+    #[ignore = "connects to Discord with DISCORD_TOKEN"]
     async fn test() -> anyhow::Result<()> {
         dotenvy::dotenv().ok();
 
@@ -70,7 +359,12 @@ mod tests {
         };
         let guild_id = Id::new(961916734137315358);
         let channel_id = Id::new(961916734523179051);
-        let mut vc = client::VoiceClient::new(voice_update, guild_id, channel_id);
+        let mut vc = client::VoiceClient::new(
+            voice_update,
+            guild_id,
+            channel_id,
+            config::Config::default(),
+        );
 
         loop {
             let event = match shard.next_event().await {
@@ -0,0 +1,187 @@
+//! Combining concurrently playing tracks into a single stream of Opus packets.
+
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use crate::{
+    codec::{self, OpusEncoder},
+    constants, Result,
+};
+
+/// Level above which the limiter starts to compress peaks.
+const LIMITER_THRESHOLD: f32 = 0.8;
+
+/// Number of samples per channel over which a change of volume is ramped in, 5 ms at 48 kHz.
+const RAMP_SAMPLES: usize = 240;
+
+/// Apply a gain ramping from `from` to `to` over the first [`RAMP_SAMPLES`] of a frame, then
+/// holding at `to`.
+fn ramp(from: f32, to: f32) -> impl Iterator<Item = f32> {
+    (0..).map(move |frame: usize| {
+        let frame = frame / usize::from(constants::CHANNELS);
+        if frame < RAMP_SAMPLES {
+            from + (to - from) * frame as f32 / RAMP_SAMPLES as f32
+        } else {
+            to
+        }
+    })
+}
+
+/// Compress `sample` smoothly into `[-1.0, 1.0]`, leaving quieter samples untouched.
+pub(crate) fn soft_limit(sample: f32) -> f32 {
+    let magnitude = sample.abs();
+    if magnitude <= LIMITER_THRESHOLD {
+        return sample;
+    }
+
+    let headroom = 1.0 - LIMITER_THRESHOLD;
+    let limited =
+        LIMITER_THRESHOLD + headroom * ((magnitude - LIMITER_THRESHOLD) / headroom).tanh();
+
+    limited.copysign(sample)
+}
+
+/// Connection-wide volume, shared between a [`VoiceClient`] and its driver.
+///
+/// [`VoiceClient`]: crate::client::VoiceClient
+#[derive(Clone)]
+pub(crate) struct MasterVolume(Arc<AtomicU32>);
+
+impl MasterVolume {
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, volume: f32) {
+        self.0.store(volume.max(0.0).to_bits(), Ordering::Relaxed);
+    }
+}
+
+impl Default for MasterVolume {
+    fn default() -> Self {
+        Self(Arc::new(AtomicU32::new(1.0f32.to_bits())))
+    }
+}
+
+/// Sums the frames of all playing tracks and encodes the result.
+pub(crate) struct Mixer {
+    samples: Vec<f32>,
+    /// Master volume applied at the end of the last frame.
+    master: f32,
+    encoder: Option<OpusEncoder>,
+    packet: Vec<u8>,
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self {
+            samples: Vec::new(),
+            master: 1.0,
+            encoder: None,
+            packet: Vec::new(),
+        }
+    }
+}
+
+impl Mixer {
+    /// Start mixing a new frame, from silence.
+    pub fn clear(&mut self) {
+        self.samples.clear();
+        self.samples.resize(constants::STEREO_FRAME_SIZE, 0.0);
+    }
+
+    /// Whether a frame at master volume `master` would pass through unchanged.
+    pub fn is_unity(&self, master: f32) -> bool {
+        self.master == 1.0 && master == 1.0
+    }
+
+    /// Add one frame of 48 kHz stereo `samples`, ramping from volume `from` to `to`. A short
+    /// frame is padded with silence.
+    pub fn add(&mut self, samples: &[f32], from: f32, to: f32) {
+        for ((mixed, sample), gain) in self.samples.iter_mut().zip(samples).zip(ramp(from, to)) {
+            *mixed += sample * gain;
+        }
+    }
+
+    /// Apply master volume `master` and limit the mixed frame to full scale, returning its
+    /// samples.
+    pub fn finish(&mut self, master: f32) -> &[f32] {
+        for (sample, gain) in self.samples.iter_mut().zip(ramp(self.master, master)) {
+            *sample = soft_limit(*sample * gain);
+        }
+        self.master = master;
+
+        &self.samples
+    }
+
+    /// Finish and encode the mixed frame, returning the Opus packet.
+    pub fn encode(&mut self, master: f32) -> Result<&[u8]> {
+        self.finish(master);
+        codec::encoder(&mut self.encoder)?.encode(&self.samples, &mut self.packet)?;
+
+        Ok(&self.packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{soft_limit, Mixer, LIMITER_THRESHOLD, RAMP_SAMPLES};
+    use crate::constants;
+
+    #[test]
+    fn limits_smoothly() {
+        assert_eq!(soft_limit(0.5), 0.5);
+        assert_eq!(soft_limit(-LIMITER_THRESHOLD), -LIMITER_THRESHOLD);
+
+        let mut previous = 0.0;
+        for step in 1..=120 {
+            let limited = soft_limit(step as f32 * 0.01);
+            assert!(limited > previous && limited < 1.0);
+            previous = limited;
+        }
+        assert!(soft_limit(100.0) <= 1.0);
+        assert_eq!(soft_limit(-4.0), -soft_limit(4.0));
+    }
+
+    #[test]
+    fn mixes_tracks_at_volume() {
+        let mut mixer = Mixer::default();
+        mixer.clear();
+        mixer.add(&[0.25; constants::STEREO_FRAME_SIZE], 1.0, 1.0);
+        mixer.add(&[0.5; 4], 0.5, 0.5);
+
+        let mixed = mixer.finish(1.0);
+        assert_eq!(mixed.len(), constants::STEREO_FRAME_SIZE);
+        assert_eq!(mixed[..5], [0.5, 0.5, 0.5, 0.5, 0.25]);
+        assert!(mixer.is_unity(1.0));
+
+        mixer.clear();
+        mixer.add(&[0.9; 2], 1.0, 1.0);
+        mixer.add(&[0.9; 2], 1.0, 1.0);
+        assert!(mixer.finish(1.0)[0] < 1.0);
+    }
+
+    #[test]
+    fn ramps_volume_changes() {
+        let mut mixer = Mixer::default();
+        mixer.clear();
+        mixer.add(&[0.5; constants::STEREO_FRAME_SIZE], 1.0, 0.0);
+
+        let mixed = mixer.finish(0.5);
+        // Both channels of each frame share a gain, which falls steadily then holds.
+        assert_eq!(mixed[0], 0.5);
+        assert_eq!(mixed[0], mixed[1]);
+        assert!(mixed[2] < mixed[0]);
+        assert!(mixed[..RAMP_SAMPLES * 2].windows(2).all(|w| w[1] <= w[0]));
+        assert!(mixed[RAMP_SAMPLES * 2..].iter().all(|s| *s == 0.0));
+
+        // The master volume carries over into the next frame.
+        assert!(!mixer.is_unity(1.0));
+        mixer.clear();
+        mixer.add(&[0.5; constants::STEREO_FRAME_SIZE], 1.0, 1.0);
+        let mixed = mixer.finish(0.5);
+        assert!(mixed.iter().all(|s| *s == 0.25));
+    }
+}
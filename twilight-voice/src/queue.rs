@@ -0,0 +1,355 @@
+//! Sequential playback of tracks over a voice connection.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::{
+    input::AudioSource,
+    track::{Track, TrackHandle, TrackState},
+};
+
+/// A track waiting in the queue, which the driver may buffer while it waits.
+struct Queued {
+    handle: TrackHandle,
+    track: Arc<AsyncMutex<Track>>,
+    /// Whether a task has been started to buffer the track.
+    buffering: bool,
+}
+
+impl Queued {
+    fn new(track: Track) -> Self {
+        Self {
+            handle: track.handle.clone(),
+            track: Arc::new(AsyncMutex::new(track)),
+            buffering: false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    current: Option<TrackHandle>,
+    upcoming: VecDeque<Queued>,
+}
+
+/// Tracks played one after another over a voice connection.
+///
+/// The start of the next track is buffered while the current one plays, so that it follows
+/// on without a gap.
+///
+/// Each [`VoiceClient`] owns a queue, which outlives the connection itself: tracks may be
+/// queued before joining, and the queue carries on where it left off after the voice gateway
+/// resumes. Cloning a queue yields another handle to the same tracks.
+///
+/// [`VoiceClient`]: crate::client::VoiceClient
+#[derive(Clone, Default)]
+pub struct TrackQueue {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl TrackQueue {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Add `source` to the end of the queue.
+    pub fn enqueue(&self, source: impl AudioSource + 'static) -> TrackHandle {
+        let track = Track::new(source);
+        let handle = track.handle.clone();
+        self.lock().upcoming.push_back(Queued::new(track));
+
+        handle
+    }
+
+    /// Play `source` immediately, ending the current track and leaving the rest of the queue to
+    /// follow it.
+    pub fn play_now(&self, source: impl AudioSource + 'static) -> TrackHandle {
+        let track = Track::new(source);
+        let handle = track.handle.clone();
+
+        let mut inner = self.lock();
+        inner.upcoming.push_front(Queued::new(track));
+        if let Some(current) = &inner.current {
+            current.stop();
+        }
+
+        handle
+    }
+
+    /// The track which is currently playing or paused.
+    pub fn current(&self) -> Option<TrackHandle> {
+        self.lock().current.clone()
+    }
+
+    /// The tracks waiting to be played, in order.
+    pub fn upcoming(&self) -> Vec<TrackHandle> {
+        self.lock()
+            .upcoming
+            .iter()
+            .map(|queued| queued.handle.clone())
+            .collect()
+    }
+
+    /// Number of tracks in the queue, including the current one.
+    pub fn len(&self) -> usize {
+        let inner = self.lock();
+
+        inner.upcoming.len() + usize::from(inner.current.is_some())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// End the current track, moving on to the next.
+    pub fn skip(&self) -> Option<TrackHandle> {
+        let current = self.current()?;
+        current.stop();
+
+        Some(current)
+    }
+
+    /// Fade out the current track over `duration` while fading in the next one, which starts
+    /// straight away.
+    pub fn crossfade(&self, duration: Duration) -> Option<TrackHandle> {
+        let inner = self.lock();
+        let current = inner.current.clone()?;
+        current.fade_out(duration);
+
+        if let Some(next) = inner.upcoming.front() {
+            let volume = next.handle.volume();
+            next.handle.set_volume(0.0);
+            next.handle.fade(volume, duration);
+        }
+
+        Some(current)
+    }
+
+    /// Remove the upcoming track at `index`, where 0 is the next to play.
+    pub fn remove(&self, index: usize) -> Option<TrackHandle> {
+        let queued = self.lock().upcoming.remove(index)?;
+        queued.handle.stop();
+
+        Some(queued.handle)
+    }
+
+    /// Move the upcoming track at `from` to `to`, shifting the tracks in between.
+    ///
+    /// Returns `false` if either index is out of range.
+    pub fn reorder(&self, from: usize, to: usize) -> bool {
+        let mut inner = self.lock();
+        if from >= inner.upcoming.len() || to >= inner.upcoming.len() {
+            return false;
+        }
+
+        let track = inner.upcoming.remove(from).expect("index is in range");
+        inner.upcoming.insert(to, track);
+
+        true
+    }
+
+    /// Remove all upcoming tracks, leaving the current one playing.
+    pub fn clear(&self) {
+        let upcoming = std::mem::take(&mut self.lock().upcoming);
+        for queued in upcoming {
+            queued.handle.stop();
+        }
+    }
+
+    /// Pause the current track.
+    pub fn pause(&self) {
+        if let Some(current) = self.current() {
+            current.pause();
+        }
+    }
+
+    /// Resume the current track.
+    pub fn resume(&self) {
+        if let Some(current) = self.current() {
+            current.play();
+        }
+    }
+
+    /// End the current track and remove all upcoming ones.
+    pub fn stop(&self) {
+        self.clear();
+        self.skip();
+    }
+
+    /// Take the next track to play, skipping any which were stopped while queued.
+    ///
+    /// Returns `None` while the next track is still being buffered, leaving it at the front of
+    /// the queue to be taken by a later call.
+    pub(crate) fn next(&self) -> Option<Track> {
+        let mut inner = self.lock();
+
+        while let Some(queued) = inner.upcoming.pop_front() {
+            if queued.handle.state() == TrackState::Ended {
+                continue;
+            }
+
+            match Arc::try_unwrap(queued.track) {
+                Ok(track) => {
+                    inner.current = Some(queued.handle);
+                    return Some(track.into_inner());
+                }
+                Err(track) => {
+                    inner.upcoming.push_front(Queued { track, ..queued });
+                    break;
+                }
+            }
+        }
+
+        inner.current = None;
+
+        None
+    }
+
+    /// Start buffering the start of the next track on its own task, so that it can follow the
+    /// current one without a gap, however long its source takes to start.
+    pub(crate) fn buffer_next(&self) {
+        let mut inner = self.lock();
+        let Some(next) = inner.upcoming.front_mut().filter(|next| !next.buffering) else {
+            return;
+        };
+        next.buffering = true;
+
+        let track = next.track.clone();
+        tokio::spawn(async move { track.lock().await.buffer().await });
+    }
+
+    /// Record that the current track has finished.
+    pub(crate) fn finish(&self, handle: &TrackHandle) {
+        let mut inner = self.lock();
+        if inner
+            .current
+            .as_ref()
+            .is_some_and(|current| current.id() == handle.id())
+        {
+            inner.current = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::TrackQueue;
+    use crate::{
+        input::{AudioFrame, MemorySource},
+        track::TrackState,
+    };
+
+    fn source() -> MemorySource {
+        MemorySource::opus(vec![vec![0xF8, 0xFF, 0xFE]])
+    }
+
+    #[test]
+    fn plays_in_order() {
+        let queue = TrackQueue::default();
+        let first = queue.enqueue(source());
+        let second = queue.enqueue(source());
+        let third = queue.enqueue(source());
+        assert_eq!(queue.len(), 3);
+
+        assert!(queue.reorder(2, 0));
+        assert!(!queue.reorder(0, 3));
+        let removed = queue.remove(1).unwrap();
+        assert_eq!(removed.id(), first.id());
+        assert_eq!(removed.state(), TrackState::Ended);
+
+        let next = queue.next().unwrap();
+        assert_eq!(next.handle.id(), third.id());
+        assert_eq!(queue.current().unwrap().id(), third.id());
+        assert_eq!(queue.len(), 2);
+
+        queue.finish(&next.handle);
+        assert_eq!(queue.next().unwrap().handle.id(), second.id());
+        assert!(queue.next().is_none());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn skips_stopped_tracks() {
+        let queue = TrackQueue::default();
+        let first = queue.enqueue(source());
+        let second = queue.enqueue(source());
+
+        first.stop();
+        assert_eq!(queue.next().unwrap().handle.id(), second.id());
+
+        queue.pause();
+        assert_eq!(second.state(), TrackState::Paused);
+        queue.resume();
+        assert_eq!(second.state(), TrackState::Playing);
+
+        let now = queue.play_now(source());
+        assert_eq!(second.state(), TrackState::Ended);
+        assert_eq!(queue.next().unwrap().handle.id(), now.id());
+
+        let next = queue.enqueue(source());
+        assert_eq!(
+            queue.crossfade(Duration::from_millis(20)).unwrap().id(),
+            now.id()
+        );
+        assert!(now.is_fading_out());
+        assert_eq!(next.volume(), 0.0);
+        assert_eq!(next.advance_volume(), 1.0);
+
+        queue.enqueue(source());
+        queue.stop();
+        assert_eq!(now.state(), TrackState::Ended);
+        assert!(queue.next().is_none());
+    }
+
+    #[test]
+    fn keeps_track_being_buffered() {
+        let queue = TrackQueue::default();
+        let handle = queue.enqueue(source());
+
+        let buffering = queue.lock().upcoming[0].track.clone();
+        assert!(queue.next().is_none());
+        assert_eq!(queue.upcoming()[0].id(), handle.id());
+        assert_eq!(handle.state(), TrackState::Playing);
+
+        drop(buffering);
+        assert_eq!(queue.next().unwrap().handle.id(), handle.id());
+    }
+
+    #[tokio::test]
+    async fn buffers_next_track() -> anyhow::Result<()> {
+        let queue = TrackQueue::default();
+        queue.enqueue(source());
+        queue.enqueue(MemorySource::opus(vec![vec![0xF8, 1], vec![0xF8, 2]]));
+
+        queue.next().unwrap();
+        queue.buffer_next();
+        queue.buffer_next();
+
+        // The track is handed out once buffered.
+        let mut next = loop {
+            match queue.next() {
+                Some(next) => break next,
+                None => tokio::task::yield_now().await,
+            }
+        };
+        assert_eq!(
+            next.read_frame().await?,
+            Some(AudioFrame::Opus(vec![0xF8, 1]))
+        );
+        assert_eq!(
+            next.read_frame().await?,
+            Some(AudioFrame::Opus(vec![0xF8, 2]))
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+//! Reordering and smoothing out the arrival of a single speaker's packets.
+
+use std::{collections::VecDeque, time::Instant};
+
+use super::RtpPacket;
+use crate::constants;
+
+/// Most frames held per speaker. A packet further ahead than this restarts the buffer.
+const MAX_FRAMES: usize = 64;
+
+/// Bounds on the number of frames a jitter buffer holds back before playing.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct JitterLimits {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl JitterLimits {
+    pub fn new(min: usize, max: usize) -> Self {
+        let min = min.clamp(1, MAX_FRAMES / 2);
+
+        Self {
+            min,
+            max: max.clamp(min, MAX_FRAMES / 2),
+        }
+    }
+}
+
+impl Default for JitterLimits {
+    /// Between 40 and 200 ms.
+    fn default() -> Self {
+        Self::new(2, 10)
+    }
+}
+
+/// What to play for the next frame of a speaker.
+#[derive(Debug)]
+pub(crate) enum Playout {
+    /// Nothing: the buffer is filling up, or the speaker is silent.
+    Waiting,
+    Packet(RtpPacket),
+    /// The packet with this sequence number did not arrive in time.
+    Lost(u16),
+}
+
+/// Jitter buffer for one SSRC, playing packets in sequence order once per frame.
+///
+/// The buffer holds back enough frames to cover the variation in packet arrival times seen so
+/// far, within [`JitterLimits`]. Playback starts once that many frames have arrived, and
+/// restarts the same way after the buffer runs dry.
+pub(crate) struct JitterBuffer {
+    /// Packets from sequence number `next` onwards, with gaps for those not yet received.
+    slots: VecDeque<Option<RtpPacket>>,
+    next: u16,
+    buffering: bool,
+    /// Interarrival jitter estimate as per RFC 3550, in 48 kHz samples.
+    jitter: f64,
+    /// Arrival time and RTP timestamp of the last packet.
+    last_arrival: Option<(Instant, u32)>,
+}
+
+impl JitterBuffer {
+    pub fn new() -> Self {
+        Self {
+            slots: VecDeque::new(),
+            next: 0,
+            buffering: true,
+            jitter: 0.0,
+            last_arrival: None,
+        }
+    }
+
+    /// Number of frames to hold back to absorb the jitter seen so far.
+    pub fn target(&self, limits: JitterLimits) -> usize {
+        let frame = constants::MONO_FRAME_SIZE as f64;
+        let frames = (3.0 * self.jitter / frame).ceil() as usize + 1;
+
+        frames.clamp(limits.min, limits.max)
+    }
+
+    /// Whether the buffer is empty and waiting to start playing, as before the first packet
+    /// of a burst of speech.
+    pub fn is_idle(&self) -> bool {
+        self.buffering && self.slots.is_empty()
+    }
+
+    fn buffered(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.is_some()).count()
+    }
+
+    /// Add a packet which arrived at `now`, returning `false` if it was dropped for arriving
+    /// too late or twice.
+    pub fn push(&mut self, packet: RtpPacket, now: Instant) -> bool {
+        if let Some((arrival, timestamp)) = self.last_arrival {
+            let elapsed = now.duration_since(arrival).as_secs_f64();
+            let sent = f64::from(packet.timestamp.wrapping_sub(timestamp) as i32);
+            let difference = elapsed * f64::from(constants::SAMPLE_RATE) - sent;
+            self.jitter += (difference.abs() - self.jitter) / 16.0;
+        }
+        self.last_arrival = Some((now, packet.timestamp));
+
+        if self.slots.is_empty() && self.buffering {
+            self.next = packet.sequence;
+        }
+
+        let offset = packet.sequence.wrapping_sub(self.next);
+        let index = if offset > u16::MAX / 2 {
+            let behind = usize::from(offset.wrapping_neg());
+            // Before playback starts, an earlier packet simply begins the buffer sooner.
+            if !self.buffering || self.slots.len() + behind > MAX_FRAMES {
+                return false;
+            }
+            for _ in 0..behind {
+                self.slots.push_front(None);
+            }
+            self.next = packet.sequence;
+            0
+        } else if usize::from(offset) >= MAX_FRAMES {
+            // Too far ahead to wait for what comes between, so start over from here.
+            self.slots.clear();
+            self.next = packet.sequence;
+            self.buffering = true;
+            0
+        } else {
+            usize::from(offset)
+        };
+
+        if self.slots.len() <= index {
+            self.slots.resize_with(index + 1, || None);
+        }
+        let slot = &mut self.slots[index];
+        if slot.is_some() {
+            return false;
+        }
+        *slot = Some(packet);
+
+        true
+    }
+
+    /// The packet following the one last played or lost, if it has arrived.
+    pub fn peek(&self) -> Option<&RtpPacket> {
+        self.slots.front().and_then(Option::as_ref)
+    }
+
+    /// Take what to play for the next frame.
+    pub fn pop(&mut self, limits: JitterLimits) -> Playout {
+        let target = self.target(limits);
+        if self.buffering {
+            if self.buffered() < target {
+                return Playout::Waiting;
+            }
+            self.buffering = false;
+            // Skip straight to the first packet received.
+            while let Some(None) = self.slots.front() {
+                self.advance();
+            }
+        }
+
+        // Catch up when more has built up than the jitter calls for.
+        if self.slots.len() > target + 2 {
+            self.advance();
+        }
+
+        let sequence = self.next;
+        match self.advance() {
+            Some(Some(packet)) => Playout::Packet(packet),
+            Some(None) => Playout::Lost(sequence),
+            None => {
+                self.buffering = true;
+                Playout::Waiting
+            }
+        }
+    }
+
+    fn advance(&mut self) -> Option<Option<RtpPacket>> {
+        let slot = self.slots.pop_front()?;
+        self.next = self.next.wrapping_add(1);
+
+        Some(slot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{JitterBuffer, JitterLimits, Playout};
+    use crate::receive::RtpPacket;
+
+    fn packet(sequence: u16) -> RtpPacket {
+        RtpPacket {
+            sequence,
+            timestamp: u32::from(sequence) * 960,
+            ssrc: 1,
+            payload: vec![0xF8],
+        }
+    }
+
+    fn played(playout: Playout) -> Option<u16> {
+        match playout {
+            Playout::Packet(packet) => Some(packet.sequence),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn reorders_across_wraparound() {
+        let limits = JitterLimits::new(3, 3);
+        let now = Instant::now();
+        let mut buffer = JitterBuffer::new();
+
+        for sequence in [u16::MAX, 1, u16::MAX - 1, 0] {
+            assert!(buffer.push(packet(sequence), now));
+        }
+        assert!(!buffer.push(packet(0), now));
+
+        let order: Vec<_> = (0..4).map(|_| played(buffer.pop(limits))).collect();
+        assert_eq!(
+            order,
+            [Some(u16::MAX - 1), Some(u16::MAX), Some(0), Some(1)]
+        );
+
+        // Having played on, anything older is too late.
+        assert!(!buffer.push(packet(u16::MAX), now));
+        assert!(matches!(buffer.pop(limits), Playout::Waiting));
+    }
+
+    #[test]
+    fn reports_lost_packets() {
+        let limits = JitterLimits::new(2, 2);
+        let now = Instant::now();
+        let mut buffer = JitterBuffer::new();
+
+        buffer.push(packet(10), now);
+        assert!(matches!(buffer.pop(limits), Playout::Waiting));
+        buffer.push(packet(12), now);
+
+        assert_eq!(played(buffer.pop(limits)), Some(10));
+        assert!(matches!(buffer.pop(limits), Playout::Lost(11)));
+        assert_eq!(buffer.peek().map(|packet| packet.sequence), Some(12));
+        assert_eq!(played(buffer.pop(limits)), Some(12));
+    }
+
+    #[test]
+    fn adapts_to_jitter() {
+        let limits = JitterLimits::new(1, 8);
+        let start = Instant::now();
+        let mut buffer = JitterBuffer::new();
+        assert_eq!(buffer.target(limits), 1);
+
+        // Packets sent 20 ms apart arriving alternately 0 and 60 ms late.
+        for sequence in 0..32u16 {
+            let late = if sequence % 2 == 0 { 0 } else { 60 };
+            let arrival = start + Duration::from_millis(u64::from(sequence) * 20 + late);
+            buffer.push(packet(sequence), arrival);
+        }
+        assert!(buffer.target(limits) > 4);
+        assert_eq!(buffer.target(JitterLimits::new(1, 3)), 3);
+    }
+}
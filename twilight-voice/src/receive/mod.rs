@@ -0,0 +1,627 @@
+//! Receiving and decoding the audio of other users in the voice channel.
+//!
+//! Received packets are decrypted and held in a jitter buffer per SSRC, which puts them back in
+//! order and plays them out every 20 ms. Each frame is then decoded to 48 kHz stereo PCM with
+//! one Opus decoder per SSRC, which requires the `opus` feature. Lost packets are concealed,
+//! using the forward error correction data of the packet following them where possible.
+//! Decoded audio is received through [`VoiceClient::receive`].
+//!
+//! Everyone's audio can also be mixed into a single stream, received through
+//! [`VoiceClient::receive_mixed`]. Each speaker's RTP timestamps are mapped to the local time
+//! their audio was sent, taken from the least delayed packet of each burst of speech. The mix
+//! follows a playout clock running behind the present by the largest jitter delay of any
+//! speaker, taking from each speaker the frame sent at the point it has reached, so that audio
+//! sent at the same time is mixed together even though each jitter buffer holds back a
+//! different amount. The stream carries on with silence while nobody speaks.
+//!
+//! [`VoiceClient::receive`]: crate::client::VoiceClient::receive
+//! [`VoiceClient::receive_mixed`]: crate::client::VoiceClient::receive_mixed
+
+mod jitter;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::broadcast;
+use twilight_model::id::{marker::UserMarker, Id};
+
+pub(crate) use self::jitter::JitterLimits;
+use self::jitter::{JitterBuffer, Playout};
+use crate::{
+    codec::{self, OpusDecoder},
+    constants,
+    crypto::{Cipher, CryptoMode, RTP_HEADER_LEN},
+    mixer::soft_limit,
+    voice::RTP_PROFILE_OPUS,
+    Error, Result,
+};
+
+/// Number of decoded frames buffered for each subscriber before the oldest are dropped.
+const RECEIVE_CAPACITY: usize = 256;
+
+/// How long a speaker may stay silent before their decoder is dropped, by default.
+pub const DEFAULT_SPEAKER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// One frame of audio received from a user.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ReceivedAudio {
+    /// RTP synchronisation source of the speaker.
+    pub ssrc: u32,
+    /// User the SSRC belongs to, once Discord has announced it.
+    pub user_id: Option<Id<UserMarker>>,
+    /// RTP sequence number of the packet.
+    pub sequence: u16,
+    /// RTP timestamp of the packet, in 48 kHz samples.
+    pub timestamp: u32,
+    /// Interleaved 48 kHz stereo samples.
+    pub samples: Arc<[f32]>,
+    /// Whether the frame was lost, and these samples rebuilt by the decoder.
+    pub concealed: bool,
+}
+
+/// One frame of everyone speaking, mixed together.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct MixedAudio {
+    /// Interleaved 48 kHz stereo samples, silent if nobody spoke.
+    pub samples: Arc<[f32]>,
+    /// SSRCs of the speakers heard in this frame.
+    pub speakers: Vec<u32>,
+}
+
+/// A frame played out of a speaker's jitter buffer.
+#[derive(Clone)]
+pub(crate) struct SpeakerFrame {
+    pub ssrc: u32,
+    pub user_id: Option<Id<UserMarker>>,
+    pub sequence: u16,
+    pub timestamp: u32,
+    /// The Opus packet, or `None` if it was lost.
+    pub packet: Option<Vec<u8>>,
+    /// Decoded or concealed audio, if decoding was asked for and succeeded.
+    pub samples: Option<Arc<[f32]>>,
+}
+
+impl SpeakerFrame {
+    fn to_received(&self) -> Option<ReceivedAudio> {
+        Some(ReceivedAudio {
+            ssrc: self.ssrc,
+            user_id: self.user_id,
+            sequence: self.sequence,
+            timestamp: self.timestamp,
+            samples: self.samples.clone()?,
+            concealed: self.packet.is_none(),
+        })
+    }
+}
+
+/// An Opus packet received over RTP, after decryption.
+#[derive(Debug)]
+pub(crate) struct RtpPacket {
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    pub payload: Vec<u8>,
+}
+
+impl RtpPacket {
+    /// Decrypt `packet`, returning `Ok(None)` if it does not hold Opus audio.
+    pub fn open(packet: &[u8], cipher: &Cipher) -> Result<Option<Self>> {
+        if packet.len() < RTP_HEADER_LEN || packet[0] >> 6 != 2 {
+            return Ok(None);
+        }
+        if packet[1] & 0x7F != RTP_PROFILE_OPUS {
+            return Ok(None);
+        }
+
+        let padding = packet[0] & 0x20 != 0;
+        let extension = packet[0] & 0x10 != 0;
+        let csrc_count = usize::from(packet[0] & 0x0F);
+        let mut header_len = RTP_HEADER_LEN + 4 * csrc_count;
+
+        // With `rtpsize` modes the extension header is sent in the clear, and only its body is
+        // encrypted. Other modes encrypt the whole extension.
+        let clear_extension = extension && cipher.mode() == CryptoMode::XChaCha20Poly1305Rtpsize;
+        if clear_extension {
+            header_len += 4;
+        }
+        if packet.len() < header_len {
+            return Err(Error::MalformedAudio("truncated RTP header".into()));
+        }
+
+        let mut payload = cipher.decrypt(packet, header_len)?;
+
+        if padding {
+            let len = payload.last().map_or(0, |len| usize::from(*len));
+            payload.truncate(payload.len().saturating_sub(len));
+        }
+        if extension {
+            let (words, skip) = if clear_extension {
+                (&packet[header_len - 2..header_len], 0)
+            } else {
+                (payload.get(2..4).unwrap_or(&[0, 0]), 4)
+            };
+            let len = skip + 4 * usize::from(u16::from_be_bytes([words[0], words[1]]));
+            if payload.len() < len {
+                return Err(Error::MalformedAudio("truncated RTP extension".into()));
+            }
+            payload.drain(..len);
+        }
+
+        Ok(Some(Self {
+            sequence: u16::from_be_bytes([packet[2], packet[3]]),
+            timestamp: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+            ssrc: u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]),
+            payload,
+        }))
+    }
+}
+
+/// Settings for receiving audio, shared with the driver.
+#[derive(Clone, Copy)]
+struct Settings {
+    speaker_timeout: Duration,
+    jitter: JitterLimits,
+}
+
+/// Decoded audio shared between a [`VoiceClient`] and its driver.
+///
+/// [`VoiceClient`]: crate::client::VoiceClient
+#[derive(Clone)]
+pub(crate) struct AudioReceiver {
+    sender: broadcast::Sender<ReceivedAudio>,
+    mixed: broadcast::Sender<MixedAudio>,
+    settings: Arc<Mutex<Settings>>,
+}
+
+impl AudioReceiver {
+    fn settings(&self) -> MutexGuard<'_, Settings> {
+        self.settings
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ReceivedAudio> {
+        self.sender.subscribe()
+    }
+
+    pub fn subscribe_mixed(&self) -> broadcast::Receiver<MixedAudio> {
+        self.mixed.subscribe()
+    }
+
+    /// Whether anyone is subscribed, without which received audio is not decoded at all.
+    pub fn is_listening(&self) -> bool {
+        self.sender.receiver_count() > 0 || self.mixed.receiver_count() > 0
+    }
+
+    /// Whether anyone is subscribed to the mixed stream.
+    pub fn is_mixing(&self) -> bool {
+        self.mixed.receiver_count() > 0
+    }
+
+    /// Hand out the frames played by each speaker in one tick, along with the mix if one was
+    /// made.
+    pub fn send(&self, frames: &[SpeakerFrame], mixed: Option<MixedAudio>) {
+        // Subscribers leaving between packets is not an error.
+        if let Some(mixed) = mixed {
+            let _ = self.mixed.send(mixed);
+        }
+        for frame in frames.iter().filter_map(SpeakerFrame::to_received) {
+            let _ = self.sender.send(frame);
+        }
+    }
+
+    pub fn speaker_timeout(&self) -> Duration {
+        self.settings().speaker_timeout
+    }
+
+    pub fn set_speaker_timeout(&self, timeout: Duration) {
+        self.settings().speaker_timeout = timeout;
+    }
+
+    pub fn jitter_limits(&self) -> JitterLimits {
+        self.settings().jitter
+    }
+
+    /// Hold back between `min` and `max` of audio per speaker, rounded up to whole frames.
+    pub fn set_jitter_delay(&self, min: Duration, max: Duration) {
+        let frames = |delay: Duration| {
+            let frame = constants::FRAME_DURATION.as_nanos();
+            usize::try_from(delay.as_nanos().div_ceil(frame)).unwrap_or(usize::MAX)
+        };
+
+        self.settings().jitter = JitterLimits::new(frames(min), frames(max));
+    }
+}
+
+impl Default for AudioReceiver {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(RECEIVE_CAPACITY).0,
+            mixed: broadcast::channel(RECEIVE_CAPACITY).0,
+            settings: Arc::new(Mutex::new(Settings {
+                speaker_timeout: DEFAULT_SPEAKER_TIMEOUT,
+                jitter: JitterLimits::default(),
+            })),
+        }
+    }
+}
+
+/// Maps the RTP timestamps of one speaker to the local time their audio was sent, give or
+/// take the network delay of the least delayed packet.
+#[derive(Clone, Copy)]
+struct SenderClock {
+    timestamp: u32,
+    at: Instant,
+}
+
+impl SenderClock {
+    /// Local time at which the audio at `timestamp` was sent.
+    fn at(self, timestamp: u32) -> Instant {
+        let samples = timestamp.wrapping_sub(self.timestamp) as i32;
+        let offset = Duration::from_nanos(
+            u64::from(samples.unsigned_abs()) * 1_000_000_000 / u64::from(constants::SAMPLE_RATE),
+        );
+
+        match samples >= 0 {
+            true => self.at + offset,
+            false => self.at.checked_sub(offset).unwrap_or(self.at),
+        }
+    }
+}
+
+/// Receiving state of one SSRC.
+struct Speaker {
+    buffer: JitterBuffer,
+    decoder: Option<OpusDecoder>,
+    /// Samples per channel of the last packet played, used to conceal lost ones.
+    samples: usize,
+    /// RTP timestamp of the last frame played.
+    timestamp: u32,
+    last_heard: Instant,
+    clock: Option<SenderClock>,
+    /// Decoded frames and the time they were sent, waiting for the mix to reach them.
+    unmixed: VecDeque<(Instant, Arc<[f32]>)>,
+}
+
+impl Speaker {
+    fn new(now: Instant) -> Self {
+        Self {
+            buffer: JitterBuffer::new(),
+            decoder: None,
+            samples: constants::MONO_FRAME_SIZE,
+            timestamp: 0,
+            last_heard: now,
+            clock: None,
+            unmixed: VecDeque::new(),
+        }
+    }
+
+    /// Map RTP timestamps to local time from a packet sent at `timestamp` arriving at `now`.
+    ///
+    /// The mapping restarts with each burst of speech, so that drift between the clocks does
+    /// not build up, and moves earlier whenever a packet arrives sooner than it predicts.
+    fn observe(&mut self, timestamp: u32, now: Instant) {
+        let restart = self.buffer.is_idle();
+        match &mut self.clock {
+            Some(clock) if !restart && clock.at(timestamp) <= now => {}
+            clock => *clock = Some(SenderClock { timestamp, at: now }),
+        }
+    }
+
+    /// Take the unmixed frame sent within the 20 ms around `playout`, dropping any which the
+    /// mix has already passed.
+    fn take_unmixed(&mut self, playout: Instant) -> Option<Arc<[f32]>> {
+        let half = constants::FRAME_DURATION / 2;
+        let start = playout.checked_sub(half).unwrap_or(playout);
+
+        while self.unmixed.front().is_some_and(|(sent, _)| *sent < start) {
+            self.unmixed.pop_front();
+        }
+        match self.unmixed.front() {
+            Some((sent, _)) if *sent < playout + half => {
+                self.unmixed.pop_front().map(|(_, samples)| samples)
+            }
+            _ => None,
+        }
+    }
+
+    /// Take the next frame due from the jitter buffer, if any, returning its sequence number
+    /// and its packet unless it was lost.
+    fn play(&mut self, limits: JitterLimits) -> Option<(u16, Option<Vec<u8>>)> {
+        match self.buffer.pop(limits) {
+            Playout::Waiting => None,
+            Playout::Packet(packet) => {
+                self.samples = codec::packet_samples(&packet.payload).unwrap_or(self.samples);
+                self.timestamp = packet.timestamp;
+
+                Some((packet.sequence, Some(packet.payload)))
+            }
+            Playout::Lost(sequence) => {
+                self.timestamp = self.timestamp.wrapping_add(self.samples as u32);
+
+                Some((sequence, None))
+            }
+        }
+    }
+
+    /// Decode the frame just played, concealing it if the packet was lost.
+    fn decode(&mut self, packet: Option<&[u8]>) -> Result<Vec<f32>> {
+        let decoder = codec::decoder(&mut self.decoder)?;
+
+        match packet {
+            Some(packet) => decoder.decode(Some(packet), self.samples, false),
+            // The packet right after a loss carries enough redundancy to rebuild it.
+            None => match self.buffer.peek() {
+                Some(next) => decoder.decode(Some(&next.payload), self.samples, true),
+                None => decoder.decode(None, self.samples, false),
+            },
+        }
+    }
+}
+
+/// Jitter buffers and decoders for everyone speaking in the channel, keyed by SSRC.
+#[derive(Default)]
+pub(crate) struct Speakers {
+    speakers: HashMap<u32, Speaker>,
+    users: HashMap<u32, Id<UserMarker>>,
+    /// Local send time of the audio in the next mixed frame.
+    playout: Option<Instant>,
+}
+
+impl Speakers {
+    /// Record that audio from `ssrc` is sent by `user_id`.
+    pub fn set_user(&mut self, ssrc: u32, user_id: Id<UserMarker>) {
+        self.users.insert(ssrc, user_id);
+    }
+
+    /// Drop all state for a user who left the channel.
+    pub fn remove_user(&mut self, user_id: Id<UserMarker>) {
+        self.users.retain(|ssrc, user| {
+            let keep = *user != user_id;
+            if !keep {
+                self.speakers.remove(ssrc);
+            }
+            keep
+        });
+    }
+
+    /// Drop the decoders of speakers who have been silent for longer than `timeout`.
+    pub fn prune(&mut self, now: Instant, timeout: Duration) {
+        self.speakers
+            .retain(|_, speaker| now.duration_since(speaker.last_heard) <= timeout);
+    }
+
+    /// Buffer `packet`, which arrived at `now`, until its frame is due to be played.
+    ///
+    /// Returns `false` if it was dropped for arriving too late or twice.
+    pub fn push(&mut self, packet: RtpPacket, now: Instant) -> bool {
+        let speaker = self
+            .speakers
+            .entry(packet.ssrc)
+            .or_insert_with(|| Speaker::new(now));
+
+        speaker.last_heard = now;
+        speaker.observe(packet.timestamp, now);
+        speaker.buffer.push(packet, now)
+    }
+
+    /// Play the next frame of every speaker, to be called every 20 ms.
+    ///
+    /// With `decode`, frames are also decoded, concealing lost packets. Frames which cannot be
+    /// decoded are left without samples.
+    pub fn tick(&mut self, limits: JitterLimits, decode: bool) -> Vec<SpeakerFrame> {
+        let mut frames = Vec::new();
+
+        for (ssrc, speaker) in &mut self.speakers {
+            let Some((sequence, packet)) = speaker.play(limits) else {
+                continue;
+            };
+            let samples = match decode {
+                true => match speaker.decode(packet.as_deref()) {
+                    Ok(samples) => Some(samples.into()),
+                    Err(error) => {
+                        tracing::debug!(%error, ssrc, "failed to decode received audio");
+                        None
+                    }
+                },
+                false => None,
+            };
+
+            frames.push(SpeakerFrame {
+                ssrc: *ssrc,
+                user_id: self.users.get(ssrc).copied(),
+                sequence,
+                timestamp: speaker.timestamp,
+                packet,
+                samples,
+            });
+        }
+
+        frames
+    }
+
+    /// Mix the next frame of everyone speaking, to be called every 20 ms with the frames just
+    /// played by [`Speakers::tick`].
+    ///
+    /// The mix runs behind `now` by the largest jitter delay of any speaker, plus a frame, and
+    /// takes from each speaker the frame sent at the point it has reached.
+    pub fn mix(
+        &mut self,
+        frames: &[SpeakerFrame],
+        now: Instant,
+        limits: JitterLimits,
+    ) -> MixedAudio {
+        for frame in frames {
+            let Some(speaker) = self.speakers.get_mut(&frame.ssrc) else {
+                continue;
+            };
+            if let (Some(clock), Some(samples)) = (speaker.clock, &frame.samples) {
+                speaker
+                    .unmixed
+                    .push_back((clock.at(frame.timestamp), samples.clone()));
+            }
+        }
+
+        let delay = self
+            .speakers
+            .values()
+            .map(|speaker| speaker.buffer.target(limits))
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let target = now
+            .checked_sub(constants::FRAME_DURATION * delay as u32)
+            .unwrap_or(now);
+        // Carry on steadily, unless the delay changed by more than a little or ticks were
+        // missed.
+        let playout = match self.playout {
+            Some(playout)
+                if playout.max(target) - playout.min(target) <= constants::FRAME_DURATION * 2 =>
+            {
+                playout
+            }
+            _ => target,
+        };
+        self.playout = Some(playout + constants::FRAME_DURATION);
+
+        let mut mixed = vec![0.0; constants::STEREO_FRAME_SIZE];
+        let mut speakers = Vec::new();
+        for (ssrc, speaker) in &mut self.speakers {
+            let Some(samples) = speaker.take_unmixed(playout) else {
+                continue;
+            };
+            for (mixed, sample) in mixed.iter_mut().zip(samples.iter()) {
+                *mixed += sample;
+            }
+            speakers.push(*ssrc);
+        }
+        for sample in &mut mixed {
+            *sample = soft_limit(*sample);
+        }
+        speakers.sort_unstable();
+
+        MixedAudio {
+            samples: mixed.into(),
+            speakers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use twilight_model::id::Id;
+
+    use super::{JitterLimits, RtpPacket, Speaker, Speakers};
+    use crate::{
+        constants,
+        crypto::{Cipher, CryptoMode},
+    };
+
+    #[test]
+    fn opens_packets_with_extensions() {
+        let mut cipher = Cipher::new(CryptoMode::Lite, &[3; 32]).unwrap();
+        let mut packet = vec![0x90, 0x78, 0x01, 0x02, 0, 0, 0x03, 0xC0, 0, 0, 0, 9];
+        packet.extend_from_slice(&[0xBE, 0xDE, 0, 1, 0x10, 0xFF, 0, 0]);
+        packet.extend_from_slice(&[0xF8, 0xFF, 0xFE]);
+        cipher.encrypt(&mut packet).unwrap();
+
+        let opened = RtpPacket::open(&packet, &cipher).unwrap().unwrap();
+        assert_eq!(opened.sequence, 0x0102);
+        assert_eq!(opened.timestamp, 960);
+        assert_eq!(opened.ssrc, 9);
+        assert_eq!(opened.payload, [0xF8, 0xFF, 0xFE]);
+
+        // RTCP shares the socket, but is not audio.
+        let rtcp = [0x80, 0xC9, 0, 1, 0, 0, 0, 9];
+        assert!(RtpPacket::open(&rtcp, &cipher).unwrap().is_none());
+    }
+
+    #[test]
+    fn mixes_speakers_by_send_time() {
+        let start = Instant::now();
+        let ms = |ms: u64| start + Duration::from_millis(ms);
+        let limits = JitterLimits::default();
+
+        // Speaker 1 arrives steadily, while every other packet of speaker 2 is 60 ms late, so
+        // its jitter buffer holds back more.
+        let mut arrivals: Vec<_> = (0..100u16)
+            .flat_map(|index| {
+                let sent = u64::from(index) * 20;
+                let late = if index % 2 == 1 { 60 } else { 0 };
+                [(ms(sent), 1, index), (ms(sent + late), 2, index)]
+            })
+            .collect();
+        arrivals.sort_by_key(|(arrival, ..)| *arrival);
+        let mut arrivals = arrivals.into_iter().peekable();
+
+        let mut speakers = Speakers::default();
+        let mut aligned = 0;
+        for tick in 0..110 {
+            let now = ms(tick * 20);
+            while let Some((_, ssrc, index)) = arrivals.next_if(|(arrival, ..)| *arrival <= now) {
+                let packet = RtpPacket {
+                    sequence: index,
+                    timestamp: u32::from(index) * 960,
+                    ssrc,
+                    payload: vec![0xF8],
+                };
+                speakers.push(packet, now);
+            }
+
+            // Stand in for decoding, which conceals lost packets, with speaker 1 on the left
+            // and speaker 2 on the right.
+            let mut frames = speakers.tick(limits, false);
+            for frame in &mut frames {
+                let value = f32::from(frame.sequence) / 1000.0;
+                let (left, right) = if frame.ssrc == 1 {
+                    (value, 0.0)
+                } else {
+                    (0.0, value)
+                };
+                let samples: Vec<_> = (0..constants::MONO_FRAME_SIZE)
+                    .flat_map(|_| [left, right])
+                    .collect();
+                frame.samples = Some(samples.into());
+            }
+
+            let mixed = speakers.mix(&frames, now, limits);
+            assert_eq!(mixed.samples.len(), constants::STEREO_FRAME_SIZE);
+            if mixed.speakers == [1, 2] {
+                assert_eq!(mixed.samples[0], mixed.samples[1], "tick {tick}");
+                aligned += 1;
+            }
+        }
+        assert!(aligned > 90, "only {aligned} frames mixed both speakers");
+
+        let silent = speakers.mix(&[], ms(3000), limits);
+        assert!(silent.samples.iter().all(|sample| *sample == 0.0));
+        assert!(silent.speakers.is_empty());
+    }
+
+    #[test]
+    fn drops_departed_and_silent_speakers() {
+        let start = Instant::now();
+        let mut speakers = Speakers::default();
+        for ssrc in [1, 2, 3] {
+            let heard = start + Duration::from_secs(ssrc.into());
+            speakers.speakers.insert(ssrc, Speaker::new(heard));
+        }
+        speakers.set_user(1, Id::new(10));
+        speakers.set_user(2, Id::new(20));
+
+        speakers.remove_user(Id::new(10));
+        assert!(!speakers.speakers.contains_key(&1));
+        assert!(!speakers.users.contains_key(&1));
+
+        speakers.prune(start + Duration::from_secs(5), Duration::from_secs(2));
+        assert!(!speakers.speakers.contains_key(&2));
+        assert!(speakers.speakers.contains_key(&3));
+    }
+}
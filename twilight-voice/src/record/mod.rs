@@ -0,0 +1,432 @@
+//! Recording the audio received over a voice connection to files.
+//!
+//! A recording writes one file per speaker, and optionally one of everyone mixed together,
+//! all to the same directory. Every file starts when the recording starts and is padded with
+//! silence while its speaker is quiet, so that they all line up and can be laid over each
+//! other.
+//!
+//! Ogg Opus files of individual speakers hold the packets exactly as received. WAV files, and
+//! mixed Ogg Opus files, require decoding and so the `opus` feature.
+
+mod ogg;
+mod wav;
+
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Instant,
+};
+
+use tokio::{
+    fs::File,
+    io::BufWriter,
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+};
+
+use self::{ogg::OggWriter, wav::WavWriter};
+use crate::{
+    codec::{self, OpusDecoder, OpusEncoder, SILENCE_FRAME},
+    constants,
+    receive::{MixedAudio, SpeakerFrame},
+    Error, Result,
+};
+
+/// File format of a recording.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RecordingFormat {
+    /// 16-bit 48 kHz stereo PCM.
+    ///
+    /// A WAV file holds at most 4 GiB, so each file stops growing after a little over 6 hours
+    /// of audio.
+    Wav,
+    /// Opus in an Ogg container.
+    OggOpus,
+}
+
+impl RecordingFormat {
+    const fn extension(self) -> &'static str {
+        match self {
+            Self::Wav => "wav",
+            Self::OggOpus => "ogg",
+        }
+    }
+}
+
+/// What to record, and where.
+#[derive(Clone, Debug)]
+pub struct RecordingOptions {
+    directory: PathBuf,
+    format: RecordingFormat,
+    mixed: bool,
+}
+
+impl RecordingOptions {
+    /// Record each speaker to a file in `directory`, which is created if needed.
+    ///
+    /// Files are named after the speaker's user ID, or their SSRC if Discord has not said
+    /// who it belongs to by the time they first speak.
+    pub fn new(directory: impl Into<PathBuf>, format: RecordingFormat) -> Self {
+        Self {
+            directory: directory.into(),
+            format,
+            mixed: false,
+        }
+    }
+
+    /// Also record everyone mixed together, to a file named `mixed`.
+    pub fn with_mixed(mut self, mixed: bool) -> Self {
+        self.mixed = mixed;
+        self
+    }
+
+    /// Whether the recording needs received audio to be decoded.
+    fn needs_pcm(&self) -> bool {
+        self.format == RecordingFormat::Wav || self.mixed
+    }
+}
+
+enum FileWriter {
+    Wav(WavWriter<BufWriter<File>>),
+    Ogg(OggWriter<BufWriter<File>>),
+}
+
+/// A file being recorded to, with the number of frames written so far.
+struct Output {
+    writer: FileWriter,
+    frames: u64,
+}
+
+impl Output {
+    async fn create(path: PathBuf, format: RecordingFormat) -> Result<Self> {
+        let file = BufWriter::new(File::create(path).await?);
+        let writer = match format {
+            RecordingFormat::Wav => FileWriter::Wav(WavWriter::new(file).await?),
+            RecordingFormat::OggOpus => FileWriter::Ogg(OggWriter::new(file).await?),
+        };
+
+        Ok(Self { writer, frames: 0 })
+    }
+
+    /// Write one frame of a speaker, or silence if it could not be decoded or was lost.
+    async fn write_frame(&mut self, frame: &SpeakerFrame) -> Result<()> {
+        match &mut self.writer {
+            FileWriter::Wav(wav) => {
+                let samples = frame.samples.as_deref().unwrap_or(&[]);
+                wav.write_frame(samples).await?;
+            }
+            FileWriter::Ogg(ogg) => {
+                let packet = frame.packet.as_deref().unwrap_or(&SILENCE_FRAME);
+                ogg.write_packet(packet).await?;
+            }
+        }
+        self.frames += 1;
+
+        Ok(())
+    }
+
+    /// Write one frame of mixed audio.
+    async fn write_mixed(
+        &mut self,
+        samples: &[f32],
+        encoder: &mut Option<OpusEncoder>,
+    ) -> Result<()> {
+        match &mut self.writer {
+            FileWriter::Wav(wav) => wav.write_frame(samples).await?,
+            FileWriter::Ogg(ogg) => {
+                let mut packet = Vec::new();
+                codec::encoder(encoder)?.encode(samples, &mut packet)?;
+                ogg.write_packet(&packet).await?;
+            }
+        }
+        self.frames += 1;
+
+        Ok(())
+    }
+
+    /// Write silence until `frames` frames have been written.
+    async fn pad_to(&mut self, frames: u64) -> Result<()> {
+        while self.frames < frames {
+            match &mut self.writer {
+                FileWriter::Wav(wav) => wav.write_frame(&[]).await?,
+                FileWriter::Ogg(ogg) => ogg.write_packet(&SILENCE_FRAME).await?,
+            }
+            self.frames += 1;
+        }
+
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        match &mut self.writer {
+            FileWriter::Wav(wav) => wav.finish().await,
+            FileWriter::Ogg(ogg) => ogg.finish().await,
+        }
+    }
+}
+
+/// Number of ticks, 5 seconds' worth, waiting to be written before more are dropped.
+const TICK_CAPACITY: usize = 250;
+
+/// The frames played out by every speaker at one point in time.
+struct Tick {
+    at: Instant,
+    frames: Vec<SpeakerFrame>,
+    /// Everyone mixed together, if the recording includes the mix.
+    mixed: Option<Arc<[f32]>>,
+}
+
+/// Write every tick received from `ticks` until the channel closes, then finish all files.
+async fn write(
+    options: RecordingOptions,
+    start: Instant,
+    mut ticks: mpsc::Receiver<Tick>,
+) -> Result<()> {
+    let extension = options.format.extension();
+    let mut names = HashSet::new();
+    let mut speakers: HashMap<u32, Output> = HashMap::new();
+    let mut mixed = match options.mixed {
+        true => {
+            let path = options.directory.join(format!("mixed.{extension}"));
+            Some(Output::create(path, options.format).await?)
+        }
+        false => None,
+    };
+    let mut encoder = None;
+    let mut next = 0;
+
+    while let Some(tick) = ticks.recv().await {
+        // Place each tick by when it happened, so that files stay aligned across any ticks
+        // missed while the connection was down.
+        let elapsed = tick.at.saturating_duration_since(start).as_nanos();
+        let index = (elapsed / constants::FRAME_DURATION.as_nanos()) as u64;
+        let index = index.max(next);
+
+        for frame in &tick.frames {
+            let output = match speakers.entry(frame.ssrc) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let mut name = match frame.user_id {
+                        Some(user_id) => user_id.to_string(),
+                        None => format!("ssrc-{}", frame.ssrc),
+                    };
+                    // A user who rejoins speaks with a new SSRC.
+                    if !names.insert(name.clone()) {
+                        name = format!("{name}-{}", frame.ssrc);
+                    }
+                    let path = options.directory.join(format!("{name}.{extension}"));
+                    entry.insert(Output::create(path, options.format).await?)
+                }
+            };
+            output.pad_to(index).await?;
+            output.write_frame(frame).await?;
+        }
+
+        if let (Some(mixed), Some(samples)) = (&mut mixed, &tick.mixed) {
+            mixed.pad_to(index).await?;
+            mixed.write_mixed(samples, &mut encoder).await?;
+        }
+
+        next = index + 1;
+    }
+
+    for output in speakers.values_mut().chain(&mut mixed) {
+        output.pad_to(next).await?;
+        output.finish().await?;
+    }
+
+    Ok(())
+}
+
+/// A recording in progress.
+struct Recording {
+    ticks: mpsc::Sender<Tick>,
+    task: JoinHandle<Result<()>>,
+    needs_pcm: bool,
+    mixed: bool,
+}
+
+/// Recording state shared between a [`VoiceClient`] and its driver.
+///
+/// [`VoiceClient`]: crate::client::VoiceClient
+#[derive(Clone, Default)]
+pub(crate) struct Recorder(Arc<Mutex<Option<Recording>>>);
+
+impl Recorder {
+    fn lock(&self) -> MutexGuard<'_, Option<Recording>> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub async fn start(&self, options: RecordingOptions) -> Result<()> {
+        if self.is_recording() {
+            return Err(Error::AlreadyRecording);
+        }
+
+        // Fail up front rather than once audio arrives.
+        let needs_pcm = options.needs_pcm();
+        let mixed = options.mixed;
+        if needs_pcm {
+            OpusDecoder::new(constants::CHANNELS)?;
+        }
+        if options.mixed && options.format == RecordingFormat::OggOpus {
+            OpusEncoder::new()?;
+        }
+        tokio::fs::create_dir_all(&options.directory).await?;
+
+        let mut recording = self.lock();
+        if recording.is_some() {
+            return Err(Error::AlreadyRecording);
+        }
+
+        let (ticks, receiver) = mpsc::channel(TICK_CAPACITY);
+        *recording = Some(Recording {
+            ticks,
+            task: tokio::spawn(write(options, Instant::now(), receiver)),
+            needs_pcm,
+            mixed,
+        });
+
+        Ok(())
+    }
+
+    /// Stop recording, waiting for all files to be finished.
+    pub async fn stop(&self) -> Result<()> {
+        let Some(recording) = self.lock().take() else {
+            return Ok(());
+        };
+        drop(recording.ticks);
+
+        recording.task.await?
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.lock().is_some()
+    }
+
+    pub fn needs_pcm(&self) -> bool {
+        self.lock()
+            .as_ref()
+            .is_some_and(|recording| recording.needs_pcm)
+    }
+
+    /// Whether the recording includes everyone mixed together.
+    pub fn needs_mixed(&self) -> bool {
+        self.lock()
+            .as_ref()
+            .is_some_and(|recording| recording.mixed)
+    }
+
+    /// Record the frames played by every speaker at `at`, and their mix if one was made.
+    pub fn record(&self, at: Instant, frames: &[SpeakerFrame], mixed: Option<&MixedAudio>) {
+        if let Some(recording) = &*self.lock() {
+            let tick = Tick {
+                at,
+                frames: frames.to_vec(),
+                mixed: mixed.map(|mixed| mixed.samples.clone()),
+            };
+            match recording.ticks.try_send(tick) {
+                // The files are padded with silence where the tick belonged.
+                Err(TrySendError::Full(_)) => {
+                    tracing::warn!("recording is falling behind, dropping received audio");
+                }
+                // A failed recording reports its error once stopped.
+                Ok(()) | Err(TrySendError::Closed(_)) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use tokio::sync::mpsc;
+    use twilight_model::id::Id;
+
+    use super::{write, RecordingFormat, RecordingOptions, Tick};
+    use crate::{
+        codec::SILENCE_FRAME,
+        input::{AudioFrame, AudioSource, OggOpusSource},
+        receive::SpeakerFrame,
+    };
+
+    fn frame(ssrc: u32, packet: u8) -> SpeakerFrame {
+        SpeakerFrame {
+            ssrc,
+            user_id: (ssrc == 1).then(|| Id::new(100)),
+            sequence: 0,
+            timestamp: 0,
+            packet: Some(vec![0xF8, packet]),
+            samples: None,
+        }
+    }
+
+    async fn packets(path: std::path::PathBuf) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut source = OggOpusSource::open(path).await?;
+        let mut packets = Vec::new();
+        while let Some(AudioFrame::Opus(packet)) = source.read_frame().await? {
+            packets.push(packet);
+        }
+
+        Ok(packets)
+    }
+
+    #[tokio::test]
+    async fn aligns_speakers() -> anyhow::Result<()> {
+        let directory =
+            std::env::temp_dir().join(format!("twilight-voice-record-{}", std::process::id()));
+        tokio::fs::create_dir_all(&directory).await?;
+
+        let options = RecordingOptions::new(&directory, RecordingFormat::OggOpus);
+        let start = Instant::now();
+        let (ticks, receiver) = mpsc::channel(4);
+        let at = |frame: u32| start + Duration::from_millis(20) * frame;
+
+        ticks
+            .send(Tick {
+                at: at(0),
+                frames: vec![frame(1, 0)],
+                mixed: None,
+            })
+            .await?;
+        // Speaker 2 starts late, and a tick is missed entirely.
+        ticks
+            .send(Tick {
+                at: at(2),
+                frames: vec![frame(1, 2), frame(2, 2)],
+                mixed: None,
+            })
+            .await?;
+        ticks
+            .send(Tick {
+                at: at(3),
+                frames: Vec::new(),
+                mixed: None,
+            })
+            .await?;
+        drop(ticks);
+        write(options, start, receiver).await?;
+
+        let silence = SILENCE_FRAME.to_vec();
+        assert_eq!(
+            packets(directory.join("100.ogg")).await?,
+            [
+                vec![0xF8, 0],
+                silence.clone(),
+                vec![0xF8, 2],
+                silence.clone()
+            ]
+        );
+        assert_eq!(
+            packets(directory.join("ssrc-2.ogg")).await?,
+            [silence.clone(), silence.clone(), vec![0xF8, 2], silence]
+        );
+
+        tokio::fs::remove_dir_all(directory).await?;
+
+        Ok(())
+    }
+}
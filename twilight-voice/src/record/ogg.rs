@@ -0,0 +1,188 @@
+//! Writing Opus packets into an Ogg Opus file.
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::{codec, constants, Result};
+
+/// Header type flag marking the first page of a logical stream.
+const HEADER_BOS: u8 = 0x02;
+/// Header type flag marking the last page of a logical stream.
+const HEADER_EOS: u8 = 0x04;
+
+/// Most packets written to one page, one second of audio.
+const PACKETS_PER_PAGE: usize = constants::FRAMES_PER_SECOND as usize;
+
+/// Lookup table for the CRC used by Ogg: polynomial 0x04C11DB7, unreflected, starting at 0.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, byte| {
+        (crc << 8) ^ CRC_TABLE[usize::from((crc >> 24) as u8 ^ byte)]
+    })
+}
+
+/// Number of lacing values needed for a packet of `len` bytes.
+fn lacing_len(len: usize) -> usize {
+    len / 255 + 1
+}
+
+/// Writes stereo 48 kHz Opus packets as a single Ogg Opus stream.
+pub(super) struct OggWriter<W> {
+    writer: W,
+    serial: u32,
+    sequence: u32,
+    /// Granule position at the end of the last packet written.
+    granule: u64,
+    /// Packets waiting to fill the next page.
+    packets: Vec<Vec<u8>>,
+}
+
+impl<W: AsyncWrite + Unpin> OggWriter<W> {
+    /// Start a stream, writing its identification and comment headers.
+    pub async fn new(writer: W) -> Result<Self> {
+        let mut ogg = Self {
+            writer,
+            serial: rand::random(),
+            sequence: 0,
+            granule: 0,
+            packets: Vec::new(),
+        };
+
+        let mut head = b"OpusHead\x01".to_vec();
+        head.push(constants::CHANNELS as u8);
+        // Recordings start mid-stream, so there is no encoder delay to skip.
+        head.extend_from_slice(&0u16.to_le_bytes());
+        head.extend_from_slice(&constants::SAMPLE_RATE.to_le_bytes());
+        head.extend_from_slice(&[0, 0, 0]);
+        ogg.write_page(HEADER_BOS, &[head]).await?;
+
+        let vendor = concat!("twilight-voice ", env!("CARGO_PKG_VERSION"));
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        tags.extend_from_slice(vendor.as_bytes());
+        tags.extend_from_slice(&0u32.to_le_bytes());
+        ogg.write_page(0, &[tags]).await?;
+
+        Ok(ogg)
+    }
+
+    pub async fn write_packet(&mut self, packet: &[u8]) -> Result<()> {
+        let segments: usize = self.packets.iter().map(|p| lacing_len(p.len())).sum();
+        if segments + lacing_len(packet.len()) > 255 || self.packets.len() >= PACKETS_PER_PAGE {
+            self.flush_page(0).await?;
+        }
+
+        let samples = codec::packet_samples(packet).unwrap_or(constants::MONO_FRAME_SIZE);
+        self.granule += samples as u64;
+        self.packets.push(packet.to_vec());
+
+        Ok(())
+    }
+
+    /// Write any remaining packets on a final page.
+    pub async fn finish(&mut self) -> Result<()> {
+        self.flush_page(HEADER_EOS).await?;
+        self.writer.flush().await?;
+
+        Ok(())
+    }
+
+    async fn flush_page(&mut self, header_type: u8) -> Result<()> {
+        let packets = std::mem::take(&mut self.packets);
+        self.write_page(header_type, &packets).await
+    }
+
+    async fn write_page(&mut self, header_type: u8, packets: &[Vec<u8>]) -> Result<()> {
+        let mut page = b"OggS\0".to_vec();
+        page.push(header_type);
+        // Header pages carry no audio, and so a granule position of 0.
+        let granule = if self.sequence < 2 { 0 } else { self.granule };
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&self.serial.to_le_bytes());
+        page.extend_from_slice(&self.sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]);
+
+        let lacing: Vec<u8> = packets
+            .iter()
+            .flat_map(|packet| {
+                let len = packet.len();
+                std::iter::repeat_n(255, len / 255).chain([(len % 255) as u8])
+            })
+            .collect();
+        page.push(lacing.len() as u8);
+        page.extend_from_slice(&lacing);
+        for packet in packets {
+            page.extend_from_slice(packet);
+        }
+
+        let crc = crc(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+
+        self.writer.write_all(&page).await?;
+        self.sequence += 1;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{crc, OggWriter};
+    use crate::{
+        codec::SILENCE_FRAME,
+        input::{AudioFrame, AudioSource, OggOpusSource},
+    };
+
+    #[test]
+    fn computes_ogg_crc() {
+        assert_eq!(crc(b""), 0);
+        assert_eq!(crc(b"123456789"), 0x89A1_897F);
+    }
+
+    #[tokio::test]
+    async fn writes_readable_stream() -> anyhow::Result<()> {
+        let mut large = vec![0xF8];
+        large.resize(1000, 0x42);
+
+        let mut ogg = OggWriter::new(Cursor::new(Vec::new())).await?;
+        for _ in 0..60 {
+            ogg.write_packet(&SILENCE_FRAME).await?;
+        }
+        ogg.write_packet(&large).await?;
+        ogg.finish().await?;
+
+        let data = ogg.writer.into_inner();
+        let mut source = OggOpusSource::new(Cursor::new(data)).await?;
+        assert!(source.is_passthrough());
+        for _ in 0..60 {
+            assert_eq!(
+                source.read_frame().await?,
+                Some(AudioFrame::Opus(SILENCE_FRAME.to_vec()))
+            );
+        }
+        assert_eq!(source.read_frame().await?, Some(AudioFrame::Opus(large)));
+        assert_eq!(source.read_frame().await?, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+//! Writing 16-bit PCM into a WAV file.
+
+use std::io::SeekFrom;
+
+use tokio::io::{AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{constants, Result};
+
+/// Length of the RIFF, fmt and data chunk headers written before the samples.
+const HEADER_LEN: u32 = 44;
+
+/// Bytes of one 20 ms frame of 16-bit stereo samples.
+const FRAME_LEN: u32 = constants::STEREO_FRAME_SIZE as u32 * 2;
+
+/// Most sample data that fits in the 32-bit length of the RIFF chunk, in whole frames.
+const MAX_DATA_LEN: u32 = (u32::MAX - (HEADER_LEN - 8)) / FRAME_LEN * FRAME_LEN;
+
+/// Writes 48 kHz stereo audio as 16-bit PCM, filling in the chunk lengths once finished.
+pub(super) struct WavWriter<W> {
+    writer: W,
+    /// Bytes of sample data written so far.
+    data_len: u32,
+    buffer: Vec<u8>,
+}
+
+impl<W: AsyncWrite + AsyncSeek + Unpin> WavWriter<W> {
+    pub async fn new(mut writer: W) -> Result<Self> {
+        let channels = constants::CHANNELS;
+        let block_align = channels * 2;
+
+        let mut header = Vec::with_capacity(HEADER_LEN as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(HEADER_LEN - 8).to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&constants::SAMPLE_RATE.to_le_bytes());
+        header.extend_from_slice(&(constants::SAMPLE_RATE * u32::from(block_align)).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        writer.write_all(&header).await?;
+
+        Ok(Self {
+            writer,
+            data_len: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Write one frame of interleaved stereo samples, padded with silence or cut to 20 ms.
+    ///
+    /// Frames beyond the largest size a WAV file can describe are dropped.
+    pub async fn write_frame(&mut self, samples: &[f32]) -> Result<()> {
+        if self.data_len > MAX_DATA_LEN - FRAME_LEN {
+            return Ok(());
+        }
+
+        self.buffer.clear();
+        let padding = std::iter::repeat(0.0);
+        for sample in samples.iter().copied().chain(padding) {
+            if self.buffer.len() == FRAME_LEN as usize {
+                break;
+            }
+            let sample = (sample.clamp(-1.0, 1.0) * f32::from(i16::MAX)) as i16;
+            self.buffer.extend_from_slice(&sample.to_le_bytes());
+        }
+
+        self.writer.write_all(&self.buffer).await?;
+        self.data_len += FRAME_LEN;
+
+        Ok(())
+    }
+
+    /// Fill in the chunk lengths.
+    pub async fn finish(&mut self) -> Result<()> {
+        self.writer.seek(SeekFrom::Start(4)).await?;
+        self.writer
+            .write_all(&(HEADER_LEN - 8).saturating_add(self.data_len).to_le_bytes())
+            .await?;
+        self.writer.seek(SeekFrom::Start(40)).await?;
+        self.writer.write_all(&self.data_len.to_le_bytes()).await?;
+        self.writer.seek(SeekFrom::End(0)).await?;
+        self.writer.flush().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{WavWriter, FRAME_LEN, MAX_DATA_LEN};
+    use crate::{
+        constants,
+        input::{AudioFormat, AudioFrame, AudioSource, WavSource},
+    };
+
+    #[tokio::test]
+    async fn writes_readable_file() -> anyhow::Result<()> {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new())).await?;
+        wav.write_frame(&[0.5, -0.5]).await?;
+        wav.write_frame(&[]).await?;
+        wav.finish().await?;
+
+        let data = wav.writer.into_inner();
+        assert_eq!(data.len(), 44 + constants::STEREO_FRAME_SIZE * 2 * 2);
+
+        let mut source = WavSource::new(Cursor::new(data)).await?;
+        assert_eq!(source.format(), AudioFormat::DISCORD);
+        let Some(AudioFrame::Pcm(samples)) = source.read_frame().await? else {
+            panic!("expected PCM");
+        };
+        assert_eq!(samples.len(), constants::STEREO_FRAME_SIZE);
+        assert!((samples[0] - 0.5).abs() < 0.001);
+        assert!((samples[1] + 0.5).abs() < 0.001);
+        assert!(samples[2..].iter().all(|sample| *sample == 0.0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stops_at_size_limit() -> anyhow::Result<()> {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new())).await?;
+        wav.data_len = MAX_DATA_LEN - FRAME_LEN;
+        wav.write_frame(&[0.5]).await?;
+        wav.write_frame(&[0.5]).await?;
+        wav.finish().await?;
+
+        let data = wav.writer.into_inner();
+        assert_eq!(data.len(), 44 + FRAME_LEN as usize);
+        let riff_len = u32::from_le_bytes(data[4..8].try_into()?);
+        assert_eq!(riff_len, 36 + MAX_DATA_LEN);
+        let data_len = u32::from_le_bytes(data[40..44].try_into()?);
+        assert_eq!(data_len, MAX_DATA_LEN);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,513 @@
+//! RTCP reports exchanged alongside voice packets on the same UDP socket.
+//!
+//! Discord multiplexes RTCP with RTP as described in RFC 5761, so the two are told apart by
+//! the packet type in the second byte. RTCP packets are encrypted like RTP, leaving only the
+//! first 8 bytes of the header in the clear.
+//!
+//! Receiver reports from the voice server describe how well the audio we send arrives, while
+//! the audio we receive is measured locally. Both are kept as [`RtcpStats`], available from
+//! [`VoiceClient::rtcp_stats`]. Sending sender reports is optional, see
+//! [`VoiceClient::set_sender_reports`].
+//!
+//! [`VoiceClient::rtcp_stats`]: crate::client::VoiceClient::rtcp_stats
+//! [`VoiceClient::set_sender_reports`]: crate::client::VoiceClient::set_sender_reports
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    constants,
+    crypto::{RTCP_HEADER_LEN, RTP_HEADER_LEN},
+    voice::RTP_PROFILE_OPUS,
+};
+
+/// How often reception is measured and sender reports are sent.
+pub(crate) const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+const SENDER_REPORT: u8 = 200;
+const RECEIVER_REPORT: u8 = 201;
+
+/// Length of the sender information following the header of a sender report.
+const SENDER_INFO_LEN: usize = 20;
+const REPORT_BLOCK_LEN: usize = 24;
+/// Most report blocks one packet can carry.
+const MAX_REPORT_BLOCKS: usize = 31;
+
+/// Seconds between the NTP epoch of 1900 and the Unix epoch.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Whether `packet` is RTCP rather than RTP.
+///
+/// RTCP packet types 192 to 223 would be RTP payload types 64 to 95 with the marker bit set,
+/// which are never used for audio.
+pub(crate) fn is_rtcp(packet: &[u8]) -> bool {
+    packet.len() >= RTCP_HEADER_LEN && (192..=223).contains(&packet[1])
+}
+
+/// The current wall clock time as a 64-bit NTP timestamp.
+pub(crate) fn ntp_now() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = now.as_secs() + NTP_UNIX_OFFSET;
+    let fraction = (u64::from(now.subsec_nanos()) << 32) / 1_000_000_000;
+
+    (seconds << 32) | fraction
+}
+
+/// The middle 32 bits of an NTP timestamp, as used to time round trips.
+const fn ntp_short(ntp: u64) -> u32 {
+    (ntp >> 16) as u32
+}
+
+/// Convert a duration in units of 1/65536 seconds.
+fn from_ntp_short(units: u32) -> Duration {
+    Duration::from_secs_f64(f64::from(units) / 65536.0)
+}
+
+/// Reception of one RTP stream, as carried by sender and receiver reports.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct ReportBlock {
+    pub ssrc: u32,
+    /// Fraction of packets lost since the previous report, in 1/256ths.
+    pub fraction_lost: u8,
+    pub packets_lost: i32,
+    pub highest_sequence: u32,
+    /// Interarrival jitter in 48 kHz samples.
+    pub jitter: u32,
+    /// Middle 32 bits of the NTP timestamp of the last sender report from this stream.
+    pub last_sender_report: u32,
+    /// Time since that sender report, in 1/65536 seconds.
+    pub delay: u32,
+}
+
+impl ReportBlock {
+    fn parse(block: &[u8]) -> Self {
+        let word = |at: usize| {
+            u32::from_be_bytes([block[at], block[at + 1], block[at + 2], block[at + 3]])
+        };
+        // Cumulative loss is a signed 24-bit number.
+        let packets_lost = (word(4) << 8) as i32 >> 8;
+
+        Self {
+            ssrc: word(0),
+            fraction_lost: block[4],
+            packets_lost,
+            highest_sequence: word(8),
+            jitter: word(12),
+            last_sender_report: word(16),
+            delay: word(20),
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let lost = self.packets_lost.clamp(-0x80_0000, 0x7F_FFFF) as u32 & 0xFF_FFFF;
+
+        out.extend_from_slice(&self.ssrc.to_be_bytes());
+        out.extend_from_slice(&((u32::from(self.fraction_lost) << 24) | lost).to_be_bytes());
+        out.extend_from_slice(&self.highest_sequence.to_be_bytes());
+        out.extend_from_slice(&self.jitter.to_be_bytes());
+        out.extend_from_slice(&self.last_sender_report.to_be_bytes());
+        out.extend_from_slice(&self.delay.to_be_bytes());
+    }
+}
+
+/// A sender or receiver report from a decrypted RTCP packet.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Report {
+    /// SSRC of the stream sending the report.
+    pub ssrc: u32,
+    /// NTP timestamp of a sender report.
+    pub sent_at: Option<u64>,
+    pub blocks: Vec<ReportBlock>,
+}
+
+/// Parse the sender and receiver reports in a compound RTCP packet, skipping other types.
+///
+/// `header` is the unencrypted start of the packet, and `body` the decrypted rest.
+pub(crate) fn parse(header: &[u8], body: &[u8]) -> Vec<Report> {
+    let mut compound = header[..RTCP_HEADER_LEN].to_vec();
+    compound.extend_from_slice(body);
+
+    let mut reports = Vec::new();
+    let mut rest = compound.as_slice();
+    while rest.len() >= RTCP_HEADER_LEN && rest[0] >> 6 == 2 {
+        let len = (usize::from(u16::from_be_bytes([rest[2], rest[3]])) + 1) * 4;
+        let Some(packet) = rest.get(..len) else {
+            break;
+        };
+        rest = &rest[len..];
+
+        let (blocks_at, sent_at) = match packet[1] {
+            SENDER_REPORT if packet.len() >= RTCP_HEADER_LEN + SENDER_INFO_LEN => {
+                let ntp = u64::from_be_bytes(packet[8..16].try_into().expect("8 bytes"));
+                (RTCP_HEADER_LEN + SENDER_INFO_LEN, Some(ntp))
+            }
+            RECEIVER_REPORT if packet.len() >= RTCP_HEADER_LEN => (RTCP_HEADER_LEN, None),
+            _ => continue,
+        };
+
+        let count = usize::from(packet[0] & 0x1F);
+        reports.push(Report {
+            ssrc: u32::from_be_bytes(packet[4..8].try_into().expect("4 bytes")),
+            sent_at,
+            blocks: packet[blocks_at..]
+                .chunks_exact(REPORT_BLOCK_LEN)
+                .take(count)
+                .map(ReportBlock::parse)
+                .collect(),
+        });
+    }
+
+    reports
+}
+
+/// Build an unencrypted sender report about the stream `ssrc`.
+pub(crate) fn sender_report(
+    ssrc: u32,
+    sent_at: u64,
+    timestamp: u32,
+    packets: u32,
+    octets: u32,
+    blocks: &[ReportBlock],
+) -> Vec<u8> {
+    let blocks = &blocks[..blocks.len().min(MAX_REPORT_BLOCKS)];
+    let len = RTCP_HEADER_LEN + SENDER_INFO_LEN + blocks.len() * REPORT_BLOCK_LEN;
+
+    let mut packet = Vec::with_capacity(len);
+    packet.push(0x80 | blocks.len() as u8);
+    packet.push(SENDER_REPORT);
+    packet.extend_from_slice(&((len / 4 - 1) as u16).to_be_bytes());
+    packet.extend_from_slice(&ssrc.to_be_bytes());
+    packet.extend_from_slice(&sent_at.to_be_bytes());
+    packet.extend_from_slice(&timestamp.to_be_bytes());
+    packet.extend_from_slice(&packets.to_be_bytes());
+    packet.extend_from_slice(&octets.to_be_bytes());
+    for block in blocks {
+        block.write(&mut packet);
+    }
+
+    packet
+}
+
+/// Quality of one RTP stream of audio, as seen by its receiver.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct ReceptionReport {
+    pub ssrc: u32,
+    /// Fraction of packets lost over the last report interval, from 0 to 1.
+    pub fraction_lost: f32,
+    /// Packets lost since the stream began. Negative if duplicates arrived.
+    pub packets_lost: i32,
+    /// Highest sequence number received, extended with the number of wraparounds.
+    pub highest_sequence: u32,
+    /// Variation in packet arrival times.
+    pub jitter: Duration,
+    /// Round trip time to the receiver, if it has answered one of our sender reports.
+    pub round_trip: Option<Duration>,
+}
+
+impl ReceptionReport {
+    fn new(block: &ReportBlock, round_trip: Option<Duration>) -> Self {
+        Self {
+            ssrc: block.ssrc,
+            fraction_lost: f32::from(block.fraction_lost) / 256.0,
+            packets_lost: block.packets_lost,
+            highest_sequence: block.highest_sequence,
+            jitter: Duration::from_secs_f64(
+                f64::from(block.jitter) / f64::from(constants::SAMPLE_RATE),
+            ),
+            round_trip,
+        }
+    }
+}
+
+/// RTCP statistics of a voice connection.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub struct RtcpStats {
+    /// Quality of the audio we send, from the latest receiver report of the voice server.
+    pub outbound: Option<ReceptionReport>,
+    /// Quality of each stream of audio we receive, measured every 5 seconds.
+    pub inbound: Vec<ReceptionReport>,
+    /// RTCP packets received.
+    pub reports_received: u64,
+    /// Sender reports sent.
+    pub sender_reports_sent: u64,
+}
+
+/// RTCP state shared between a [`VoiceClient`] and its driver.
+///
+/// [`VoiceClient`]: crate::client::VoiceClient
+#[derive(Clone, Default)]
+pub(crate) struct Rtcp {
+    stats: Arc<Mutex<RtcpStats>>,
+    sender_reports: Arc<AtomicBool>,
+}
+
+impl Rtcp {
+    pub fn stats(&self) -> MutexGuard<'_, RtcpStats> {
+        self.stats
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn sender_reports(&self) -> bool {
+        self.sender_reports.load(Ordering::Relaxed)
+    }
+
+    pub fn set_sender_reports(&self, enabled: bool) {
+        self.sender_reports.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Record a receiver report from the voice server about our stream `ssrc`, received at
+    /// `received_at`.
+    pub fn receive(&self, reports: &[Report], ssrc: u32, received_at: u64) {
+        let mut stats = self.stats();
+        stats.reports_received += 1;
+
+        let block = reports
+            .iter()
+            .flat_map(|report| &report.blocks)
+            .find(|block| block.ssrc == ssrc);
+        if let Some(block) = block {
+            let round_trip = (block.last_sender_report != 0).then(|| {
+                let elapsed = ntp_short(received_at).wrapping_sub(block.last_sender_report);
+                from_ntp_short(elapsed.saturating_sub(block.delay))
+            });
+            stats.outbound = Some(ReceptionReport::new(block, round_trip));
+        }
+    }
+
+    pub fn set_inbound(&self, blocks: &[ReportBlock]) {
+        self.stats().inbound = blocks
+            .iter()
+            .map(|block| ReceptionReport::new(block, None))
+            .collect();
+    }
+
+    pub fn sent_sender_report(&self) {
+        self.stats().sender_reports_sent += 1;
+    }
+}
+
+/// Reception statistics of one stream, following RFC 3550 appendix A.
+struct Source {
+    /// First and highest sequence numbers received, extended past wraparound.
+    base: i64,
+    highest: i64,
+    received: i64,
+    expected_prior: i64,
+    received_prior: i64,
+    /// Interarrival jitter in 48 kHz samples.
+    jitter: f64,
+    /// Arrival time and RTP timestamp of the last packet.
+    last_arrival: (Instant, u32),
+    /// Short NTP timestamp of the last sender report from this stream, and when it arrived.
+    sender_report: Option<(u32, Instant)>,
+}
+
+impl Source {
+    fn new(sequence: u16, timestamp: u32, now: Instant) -> Self {
+        Self {
+            base: i64::from(sequence),
+            highest: i64::from(sequence),
+            received: 1,
+            expected_prior: 0,
+            received_prior: 0,
+            jitter: 0.0,
+            last_arrival: (now, timestamp),
+            sender_report: None,
+        }
+    }
+
+    fn push(&mut self, sequence: u16, timestamp: u32, now: Instant) {
+        let delta = sequence.wrapping_sub(self.highest as u16) as i16;
+        self.highest = self.highest.max(self.highest + i64::from(delta));
+        self.received += 1;
+
+        let (arrival, last_timestamp) = self.last_arrival;
+        let elapsed = now.saturating_duration_since(arrival).as_secs_f64();
+        let sent = f64::from(timestamp.wrapping_sub(last_timestamp) as i32);
+        let difference = elapsed * f64::from(constants::SAMPLE_RATE) - sent;
+        self.jitter += (difference.abs() - self.jitter) / 16.0;
+        self.last_arrival = (now, timestamp);
+    }
+
+    /// Report on the stream, starting a new interval for the fraction lost.
+    fn report(&mut self, ssrc: u32, now: Instant) -> ReportBlock {
+        let expected = self.highest - self.base + 1;
+        let expected_interval = expected - self.expected_prior;
+        let received_interval = self.received - self.received_prior;
+        let lost_interval = expected_interval - received_interval;
+        self.expected_prior = expected;
+        self.received_prior = self.received;
+
+        let fraction_lost = match expected_interval {
+            0 => 0,
+            _ if lost_interval <= 0 => 0,
+            _ => ((lost_interval << 8) / expected_interval).min(255) as u8,
+        };
+        let (last_sender_report, delay) = match self.sender_report {
+            Some((sent_at, received)) => {
+                let delay = now.saturating_duration_since(received).as_secs_f64() * 65536.0;
+                (sent_at, delay as u32)
+            }
+            None => (0, 0),
+        };
+
+        ReportBlock {
+            ssrc,
+            fraction_lost,
+            packets_lost: (expected - self.received).clamp(i32::MIN.into(), i32::MAX.into()) as i32,
+            highest_sequence: self.highest as u32,
+            jitter: self.jitter as u32,
+            last_sender_report,
+            delay,
+        }
+    }
+}
+
+/// Reception statistics of every stream received, measured from the unencrypted RTP headers.
+#[derive(Default)]
+pub(crate) struct Reception {
+    sources: HashMap<u32, Source>,
+}
+
+impl Reception {
+    /// Account for an RTP packet received at `now`.
+    pub fn push(&mut self, packet: &[u8], now: Instant) {
+        if packet.len() < RTP_HEADER_LEN
+            || packet[0] >> 6 != 2
+            || packet[1] & 0x7F != RTP_PROFILE_OPUS
+        {
+            return;
+        }
+        let sequence = u16::from_be_bytes([packet[2], packet[3]]);
+        let timestamp = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+
+        self.sources
+            .entry(ssrc)
+            .and_modify(|source| source.push(sequence, timestamp, now))
+            .or_insert_with(|| Source::new(sequence, timestamp, now));
+    }
+
+    /// Note a sender report from the stream `ssrc`, to be answered in our next report.
+    pub fn sender_report(&mut self, ssrc: u32, sent_at: u64, now: Instant) {
+        if let Some(source) = self.sources.get_mut(&ssrc) {
+            source.sender_report = Some((ntp_short(sent_at), now));
+        }
+    }
+
+    /// Report on every stream, dropping those not heard from for `timeout`.
+    pub fn report(&mut self, now: Instant, timeout: Duration) -> Vec<ReportBlock> {
+        self.sources
+            .retain(|_, source| now.saturating_duration_since(source.last_arrival.0) < timeout);
+
+        let mut blocks: Vec<_> = self
+            .sources
+            .iter_mut()
+            .map(|(ssrc, source)| source.report(*ssrc, now))
+            .collect();
+        blocks.sort_by_key(|block| block.ssrc);
+
+        blocks
+    }
+
+    pub fn clear(&mut self) {
+        self.sources.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{
+        is_rtcp, parse, sender_report, Reception, ReportBlock, RECEIVER_REPORT, SENDER_REPORT,
+    };
+    use crate::crypto::RTCP_HEADER_LEN;
+
+    fn rtp(sequence: u16) -> Vec<u8> {
+        let mut packet = vec![0x80, 0x78];
+        packet.extend_from_slice(&sequence.to_be_bytes());
+        packet.extend_from_slice(&(u32::from(sequence) * 960).to_be_bytes());
+        packet.extend_from_slice(&7u32.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn round_trips_sender_reports() {
+        let block = ReportBlock {
+            ssrc: 7,
+            fraction_lost: 64,
+            packets_lost: -2,
+            highest_sequence: 0x1_0005,
+            jitter: 480,
+            last_sender_report: 0x1234_5678,
+            delay: 65536,
+        };
+        let packet = sender_report(1, 0xAABB_CCDD_0000_0001, 960, 10, 200, &[block]);
+        assert!(is_rtcp(&packet));
+        assert!(!is_rtcp(&rtp(0)));
+        assert_eq!(packet.len(), 52);
+
+        let (header, body) = packet.split_at(RTCP_HEADER_LEN);
+        let reports = parse(header, body);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].ssrc, 1);
+        assert_eq!(reports[0].sent_at, Some(0xAABB_CCDD_0000_0001));
+        assert_eq!(reports[0].blocks, [block]);
+    }
+
+    #[test]
+    fn skips_truncated_reports() {
+        // A length of 0 leaves no room for the SSRC.
+        let mut packet = vec![0x80, RECEIVER_REPORT, 0, 0];
+        let receiver_report = [0x80, RECEIVER_REPORT, 0, 1, 0, 0, 0, 3];
+        packet.extend_from_slice(&receiver_report);
+        // A sender report too short for its sender info.
+        packet.extend_from_slice(&[0x80, SENDER_REPORT, 0, 1, 0, 0, 0, 4]);
+
+        let (header, body) = packet.split_at(RTCP_HEADER_LEN);
+        let reports = parse(header, body);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].ssrc, 3);
+        assert_eq!(reports[0].sent_at, None);
+    }
+
+    #[test]
+    fn measures_loss_across_wraparound() {
+        let now = Instant::now();
+        let mut reception = Reception::default();
+
+        for sequence in [u16::MAX - 2, u16::MAX - 1, 0, 1, 3] {
+            reception.push(&rtp(sequence), now);
+        }
+        let [block] = reception.report(now, Duration::from_secs(30))[..] else {
+            panic!("expected one stream");
+        };
+        assert_eq!(block.ssrc, 7);
+        assert_eq!(block.highest_sequence, 0x1_0003);
+        assert_eq!(block.packets_lost, 2);
+        // 2 of 7 packets lost.
+        assert_eq!(block.fraction_lost, 73);
+
+        // Nothing more lost in the next interval.
+        reception.push(&rtp(4), now);
+        let block = reception.report(now, Duration::from_secs(30))[0];
+        assert_eq!(block.fraction_lost, 0);
+        assert_eq!(block.packets_lost, 2);
+
+        assert!(reception
+            .report(now + Duration::from_secs(31), Duration::from_secs(30))
+            .is_empty());
+    }
+}
@@ -0,0 +1,235 @@
+//! Statistics of a voice connection.
+//!
+//! A snapshot is available at any time from [`VoiceClient::stats`]. With the `metrics`
+//! feature, the same numbers are also recorded through the [`metrics`] facade as they change,
+//! labelled with the guild ID. Without a recorder installed this does nothing, so no exporter
+//! is needed.
+//!
+//! | Metric | Kind |
+//! |--------|------|
+//! | `voice_heartbeat_rtt_seconds` | histogram |
+//! | `voice_packets_sent_total`, `voice_bytes_sent_total` | counter |
+//! | `voice_packets_received_total`, `voice_bytes_received_total` | counter |
+//! | `voice_frames_dropped_total`, `voice_packets_dropped_total` | counter |
+//! | `voice_encrypt_failures_total`, `voice_decrypt_failures_total` | counter |
+//! | `voice_reconnects_total` | counter |
+//!
+//! [`VoiceClient::stats`]: crate::client::VoiceClient::stats
+//! [`metrics`]: https://docs.rs/metrics
+
+use std::{
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use twilight_model::id::{marker::GuildMarker, Id};
+
+use crate::{crypto::CryptoMode, rtcp::RtcpStats};
+
+/// Snapshot of the statistics of a voice connection.
+///
+/// Counters start from zero when the [`VoiceClient`] is created, and carry on across
+/// reconnects.
+///
+/// [`VoiceClient`]: crate::client::VoiceClient
+#[derive(Clone, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub struct ConnectionStats {
+    /// Time taken by the voice gateway to acknowledge the last heartbeat.
+    pub heartbeat_rtt: Option<Duration>,
+    /// UDP packets sent, including keepalives and RTCP.
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    /// UDP packets received, including RTCP.
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    /// Frames of audio which could not be sent.
+    pub frames_dropped: u64,
+    /// Received packets of audio discarded for arriving too late or twice.
+    pub packets_dropped: u64,
+    pub encrypt_failures: u64,
+    pub decrypt_failures: u64,
+    /// Times the connection was restored, by resuming the session, starting a new one or
+    /// replacing the UDP socket.
+    pub reconnects: u64,
+    /// Encryption mode of the current session.
+    pub crypto_mode: Option<CryptoMode>,
+    /// Voice server endpoint of the current session.
+    pub endpoint: Option<String>,
+    /// SSRC assigned to the audio we send in the current session.
+    pub ssrc: Option<u32>,
+    pub rtcp: RtcpStats,
+}
+
+/// Statistics shared between a [`VoiceClient`] and its driver.
+///
+/// [`VoiceClient`]: crate::client::VoiceClient
+#[derive(Clone)]
+pub(crate) struct Stats {
+    stats: Arc<Mutex<ConnectionStats>>,
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    guild_id: String,
+}
+
+#[cfg(feature = "metrics")]
+macro_rules! counter {
+    ($stats:expr, $name:literal, $value:expr) => {
+        metrics::counter!($name, "guild_id" => $stats.guild_id.clone()).increment($value)
+    };
+}
+
+#[cfg(not(feature = "metrics"))]
+macro_rules! counter {
+    ($stats:expr, $name:literal, $value:expr) => {};
+}
+
+impl Stats {
+    pub fn new(guild_id: Id<GuildMarker>) -> Self {
+        Self {
+            stats: Arc::default(),
+            guild_id: guild_id.to_string(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ConnectionStats> {
+        self.stats
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn snapshot(&self, rtcp: RtcpStats) -> ConnectionStats {
+        ConnectionStats {
+            rtcp,
+            ..self.lock().clone()
+        }
+    }
+
+    pub fn heartbeat_acked(&self, rtt: Duration) {
+        self.lock().heartbeat_rtt = Some(rtt);
+        #[cfg(feature = "metrics")]
+        metrics::histogram!("voice_heartbeat_rtt_seconds", "guild_id" => self.guild_id.clone())
+            .record(rtt);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        let mut stats = self.lock();
+        stats.packets_sent += 1;
+        stats.bytes_sent += bytes as u64;
+        counter!(self, "voice_packets_sent_total", 1);
+        counter!(self, "voice_bytes_sent_total", bytes as u64);
+    }
+
+    pub fn received(&self, bytes: usize) {
+        let mut stats = self.lock();
+        stats.packets_received += 1;
+        stats.bytes_received += bytes as u64;
+        counter!(self, "voice_packets_received_total", 1);
+        counter!(self, "voice_bytes_received_total", bytes as u64);
+    }
+
+    pub fn frame_dropped(&self) {
+        self.lock().frames_dropped += 1;
+        counter!(self, "voice_frames_dropped_total", 1);
+    }
+
+    pub fn packet_dropped(&self) {
+        self.lock().packets_dropped += 1;
+        counter!(self, "voice_packets_dropped_total", 1);
+    }
+
+    pub fn encrypt_failed(&self) {
+        self.lock().encrypt_failures += 1;
+        counter!(self, "voice_encrypt_failures_total", 1);
+    }
+
+    pub fn decrypt_failed(&self) {
+        self.lock().decrypt_failures += 1;
+        counter!(self, "voice_decrypt_failures_total", 1);
+    }
+
+    pub fn reconnected(&self) {
+        self.lock().reconnects += 1;
+        counter!(self, "voice_reconnects_total", 1);
+    }
+
+    /// Start a new session with the voice server at `endpoint`.
+    pub fn set_endpoint(&self, endpoint: Option<String>) {
+        let mut stats = self.lock();
+        stats.endpoint = endpoint;
+        stats.crypto_mode = None;
+        stats.ssrc = None;
+    }
+
+    pub fn set_ssrc(&self, ssrc: u32) {
+        self.lock().ssrc = Some(ssrc);
+    }
+
+    pub fn set_crypto_mode(&self, mode: CryptoMode) {
+        self.lock().crypto_mode = Some(mode);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use twilight_model::id::Id;
+
+    use super::Stats;
+    use crate::rtcp::RtcpStats;
+
+    #[test]
+    fn counts_traffic() {
+        let stats = Stats::new(Id::new(1));
+        stats.set_endpoint(Some("voice.example".into()));
+        stats.set_ssrc(7);
+        stats.sent(100);
+        stats.sent(50);
+        stats.received(20);
+        stats.heartbeat_acked(Duration::from_millis(30));
+
+        let snapshot = stats.snapshot(RtcpStats::default());
+        assert_eq!(snapshot.packets_sent, 2);
+        assert_eq!(snapshot.bytes_sent, 150);
+        assert_eq!(snapshot.bytes_received, 20);
+        assert_eq!(snapshot.heartbeat_rtt, Some(Duration::from_millis(30)));
+        assert_eq!(snapshot.endpoint.as_deref(), Some("voice.example"));
+        assert_eq!(snapshot.ssrc, Some(7));
+
+        stats.set_endpoint(None);
+        assert_eq!(stats.snapshot(RtcpStats::default()).ssrc, None);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn records_metrics() {
+        use metrics_util::{
+            debugging::{DebugValue, DebuggingRecorder},
+            MetricKind,
+        };
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            let stats = Stats::new(Id::new(1));
+            stats.sent(100);
+            stats.sent(50);
+            stats.decrypt_failed();
+        });
+
+        let metrics = snapshotter.snapshot().into_vec();
+        let value = |name: &str| {
+            metrics.iter().find_map(|(key, _, _, value)| {
+                (key.kind() == MetricKind::Counter && key.key().name() == name).then_some(value)
+            })
+        };
+        assert_eq!(
+            value("voice_bytes_sent_total"),
+            Some(&DebugValue::Counter(150))
+        );
+        assert_eq!(
+            value("voice_decrypt_failures_total"),
+            Some(&DebugValue::Counter(1))
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Voice gateway websocket of the mock server.
+
+use std::net::Ipv4Addr;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinSet,
+};
+use tokio_tungstenite::{
+    tungstenite::{protocol::CloseFrame, Message},
+    WebSocketStream,
+};
+use twilight_voice_model::{
+    payload::{HeartbeatAck, Hello, Ready, SessionDescription},
+    CloseCode, Event,
+};
+
+use super::SharedHandle;
+use crate::crypto::{Cipher, CryptoMode};
+
+/// Action for a websocket connection to take.
+pub(super) enum Command {
+    Send(Event),
+    /// Close with a code, or drop the connection without a close frame.
+    Close(Option<CloseCode>),
+}
+
+/// Accept websocket connections until aborted, which stops the connections too.
+pub(super) async fn serve(
+    listener: TcpListener,
+    udp_port: u16,
+    shared: SharedHandle,
+    events: mpsc::UnboundedSender<Event>,
+) {
+    // Connections overlap while a client moves to a new session.
+    let mut connections = JoinSet::new();
+
+    while let Ok((stream, _)) = listener.accept().await {
+        while connections.try_join_next().is_some() {}
+
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        shared.lock().connection = Some(commands);
+        connections.spawn(handle(
+            stream,
+            udp_port,
+            shared.clone(),
+            events.clone(),
+            commands_rx,
+        ));
+    }
+}
+
+async fn handle(
+    stream: TcpStream,
+    udp_port: u16,
+    shared: SharedHandle,
+    events: mpsc::UnboundedSender<Event>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+) {
+    let Ok(mut websocket) = tokio_tungstenite::accept_async(stream).await else {
+        return;
+    };
+
+    let heartbeat_interval = shared.lock().heartbeat_interval;
+    let hello = Event::Hello(Hello {
+        heartbeat_interval: heartbeat_interval.as_secs_f64() * 1000.0,
+    });
+    if send(&mut websocket, &hello).await.is_err() {
+        return;
+    }
+
+    loop {
+        let command = tokio::select! {
+            message = websocket.next() => {
+                let Some(Ok(message)) = message else {
+                    return;
+                };
+                let Message::Text(data) = message else {
+                    continue;
+                };
+                let Ok(event) = serde_json::from_str::<Event>(&data) else {
+                    continue;
+                };
+
+                let reply = respond(&event, udp_port, &shared);
+                // The server may be dropped while the client is still connected.
+                let _ = events.send(event);
+                match reply {
+                    Some(command) => command,
+                    None => continue,
+                }
+            }
+            command = commands.recv() => match command {
+                Some(command) => command,
+                // Replaced by a newer connection.
+                None => return,
+            },
+        };
+
+        match command {
+            Command::Send(event) => {
+                if send(&mut websocket, &event).await.is_err() {
+                    return;
+                }
+            }
+            Command::Close(Some(code)) => {
+                let frame = CloseFrame {
+                    code: (code as u16).into(),
+                    reason: "".into(),
+                };
+                let _ = websocket.close(Some(frame)).await;
+                return;
+            }
+            Command::Close(None) => return,
+        }
+    }
+}
+
+/// Answer `event` the way Discord would.
+fn respond(event: &Event, udp_port: u16, shared: &SharedHandle) -> Option<Command> {
+    let mut shared = shared.lock();
+
+    let reply = match event {
+        Event::Identify(_) => match shared.close_on_identify.take() {
+            Some(code) => return Some(Command::Close(Some(code))),
+            None => Event::Ready(Ready {
+                ip: Ipv4Addr::LOCALHOST.into(),
+                modes: CryptoMode::ALL
+                    .iter()
+                    .map(|mode| mode.name().into())
+                    .collect(),
+                port: udp_port,
+                ssrc: super::MockVoiceServer::SSRC,
+            }),
+        },
+        Event::SelectProtocol(select) => {
+            let cipher = select
+                .data
+                .mode
+                .parse()
+                .and_then(|mode| Cipher::new(mode, &shared.secret_key));
+            let Ok(cipher) = cipher else {
+                return Some(Command::Close(Some(CloseCode::UnknownEncryptionMode)));
+            };
+            shared.cipher = Some(cipher);
+
+            Event::SessionDescription(SessionDescription {
+                mode: select.data.mode.clone(),
+                secret_key: shared.secret_key.to_vec(),
+            })
+        }
+        Event::Heartbeat(heartbeat) => Event::HeartbeatAck(HeartbeatAck { t: heartbeat.t }),
+        Event::Resume(_) => match shared.close_on_resume.take() {
+            Some(code) => return Some(Command::Close(Some(code))),
+            None => Event::Resumed,
+        },
+        _ => return None,
+    };
+
+    Some(Command::Send(reply))
+}
+
+async fn send(
+    websocket: &mut WebSocketStream<TcpStream>,
+    event: &Event,
+) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    let data = serde_json::to_string(event).expect("events serialize");
+    websocket.send(Message::Text(data)).await
+}
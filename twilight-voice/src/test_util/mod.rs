@@ -0,0 +1,261 @@
+//! Mock voice server for testing voice connections offline, enabled by the `test-util`
+//! feature.
+//!
+//! [`MockVoiceServer`] listens on localhost, with a voice gateway websocket answering
+//! `Identify`, `SelectProtocol`, `Heartbeat` and `Resume` as Discord does, and a UDP socket
+//! answering IP discovery. Audio sent to it is decrypted, and can be read back with
+//! [`MockVoiceServer::next_packet`].
+//!
+//! [`MockVoiceUpdate`] stands in for the Discord gateway, answering the voice state updates a
+//! [`VoiceClient`] requests with the events Discord would send.
+//!
+//! [`VoiceClient`]: crate::client::VoiceClient
+
+mod gateway;
+mod udp;
+mod voice_update;
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use discortp::rtp::{MutableRtpPacket, RtpType};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use twilight_model::{
+    gateway::payload::incoming::VoiceServerUpdate,
+    id::{marker::GuildMarker, Id},
+};
+use twilight_voice_model::{CloseCode, Event};
+
+use crate::{
+    config::Config,
+    crypto::{Cipher, CryptoMode, RTP_HEADER_LEN},
+    voice::RTP_PROFILE_OPUS,
+    Error, Result,
+};
+
+use self::gateway::Command;
+
+pub use self::voice_update::{GatewayEvent, MockVoiceUpdate, VoiceStateRequest};
+
+/// A packet of audio sent to a [`MockVoiceServer`], after decryption.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct CapturedPacket {
+    pub ssrc: u32,
+    pub sequence: u16,
+    pub timestamp: u32,
+    /// The Opus packet within.
+    pub payload: Vec<u8>,
+}
+
+/// State shared between a [`MockVoiceServer`] and its tasks.
+struct Shared {
+    heartbeat_interval: Duration,
+    secret_key: [u8; 32],
+    /// Cipher of the session, once the client selected a mode.
+    cipher: Option<Cipher>,
+    /// Address the client sent IP discovery from.
+    client: Option<SocketAddr>,
+    close_on_identify: Option<CloseCode>,
+    close_on_resume: Option<CloseCode>,
+    /// Commands for the latest websocket connection.
+    connection: Option<mpsc::UnboundedSender<Command>>,
+}
+
+#[derive(Clone)]
+struct SharedHandle(Arc<Mutex<Shared>>);
+
+impl SharedHandle {
+    fn lock(&self) -> MutexGuard<'_, Shared> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// A fake Discord voice server on localhost.
+///
+/// Connect a [`VoiceClient`] to it by passing its [`Config`] through
+/// [`MockVoiceServer::configure`], and the [`VoiceServerUpdate`] from
+/// [`MockVoiceServer::voice_server_update`] to [`VoiceClient::on_voice_server_update`]. Any
+/// session ID and user ID are accepted.
+///
+/// The server stops when dropped.
+///
+/// [`VoiceClient`]: crate::client::VoiceClient
+/// [`VoiceClient::on_voice_server_update`]: crate::client::VoiceClient::on_voice_server_update
+pub struct MockVoiceServer {
+    endpoint: SocketAddr,
+    shared: SharedHandle,
+    udp: Arc<UdpSocket>,
+    events: mpsc::UnboundedReceiver<Event>,
+    packets: mpsc::UnboundedReceiver<CapturedPacket>,
+    tasks: [JoinHandle<()>; 2],
+}
+
+impl MockVoiceServer {
+    /// SSRC assigned to the client in `Ready`.
+    pub const SSRC: u32 = 1;
+    /// Token sent in [`MockVoiceServer::voice_server_update`], and prefixing those sent by
+    /// [`MockVoiceUpdate`].
+    pub const TOKEN: &'static str = "mock-token";
+
+    /// Start listening on free ports on localhost.
+    pub async fn start() -> Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let udp = Arc::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?);
+        let endpoint = listener.local_addr()?;
+        let udp_port = udp.local_addr()?.port();
+
+        let shared = SharedHandle(Arc::new(Mutex::new(Shared {
+            heartbeat_interval: Duration::from_secs(5),
+            secret_key: rand::random(),
+            cipher: None,
+            client: None,
+            close_on_identify: None,
+            close_on_resume: None,
+            connection: None,
+        })));
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let (packets_tx, packets) = mpsc::unbounded_channel();
+
+        let tasks = [
+            tokio::spawn(gateway::serve(
+                listener,
+                udp_port,
+                shared.clone(),
+                events_tx,
+            )),
+            tokio::spawn(udp::serve(udp.clone(), shared.clone(), packets_tx)),
+        ];
+
+        Ok(Self {
+            endpoint,
+            shared,
+            udp,
+            events,
+            packets,
+            tasks,
+        })
+    }
+
+    /// Address of the voice gateway, as sent in the [`VoiceServerUpdate`].
+    pub fn endpoint(&self) -> String {
+        self.endpoint.to_string()
+    }
+
+    /// Adjust `config` to connect to this server, which does not use TLS.
+    pub fn configure(&self, mut config: Config) -> Config {
+        config.gateway_tls = false;
+        config
+    }
+
+    /// The `VoiceServerUpdate` Discord would send to join a channel in `guild_id` on this
+    /// server.
+    pub fn voice_server_update(&self, guild_id: Id<GuildMarker>) -> VoiceServerUpdate {
+        VoiceServerUpdate {
+            endpoint: Some(self.endpoint()),
+            guild_id,
+            token: Self::TOKEN.into(),
+        }
+    }
+
+    /// Request heartbeats every `interval` in `Hello`, defaulting to 5 seconds. Applies from
+    /// the next connection.
+    pub fn set_heartbeat_interval(&self, interval: Duration) {
+        self.shared.lock().heartbeat_interval = interval;
+    }
+
+    /// Close the websocket with `code` instead of answering the next `Identify`.
+    pub fn close_on_identify(&self, code: CloseCode) {
+        self.shared.lock().close_on_identify = Some(code);
+    }
+
+    /// Close the websocket with `code` instead of answering the next `Resume`.
+    pub fn close_on_resume(&self, code: CloseCode) {
+        self.shared.lock().close_on_resume = Some(code);
+    }
+
+    /// Close the current websocket with `code`, or drop it without a close frame if `None`.
+    pub fn close(&self, code: Option<CloseCode>) {
+        self.command(Command::Close(code));
+    }
+
+    /// Send `event` to the client over the current websocket, such as `Speaking` for another
+    /// user.
+    pub fn send(&self, event: Event) {
+        self.command(Command::Send(event));
+    }
+
+    fn command(&self, command: Command) {
+        if let Some(connection) = &self.shared.lock().connection {
+            // The connection may have closed since.
+            let _ = connection.send(command);
+        }
+    }
+
+    /// Wait for the next event sent by a client, in the order received across connections.
+    pub async fn next_event(&mut self) -> Option<Event> {
+        self.events.recv().await
+    }
+
+    /// Wait for the next packet of audio sent by a client.
+    pub async fn next_packet(&mut self) -> Option<CapturedPacket> {
+        self.packets.recv().await
+    }
+
+    /// Encrypt and send a packet of audio to the client, as if from another user with `ssrc`.
+    ///
+    /// Fails with [`Error::NotConnected`] until the client has run IP discovery and selected an
+    /// encryption mode.
+    pub async fn send_packet(
+        &self,
+        ssrc: u32,
+        sequence: u16,
+        timestamp: u32,
+        payload: &[u8],
+    ) -> Result<()> {
+        let mut packet = vec![0; RTP_HEADER_LEN];
+        {
+            let mut rtp = MutableRtpPacket::new(&mut packet).unwrap();
+            rtp.set_version(2);
+            rtp.set_payload_type(RtpType::Dynamic(RTP_PROFILE_OPUS));
+            rtp.set_sequence(sequence.into());
+            rtp.set_timestamp(timestamp.into());
+            rtp.set_ssrc(ssrc);
+        }
+        packet.extend_from_slice(payload);
+
+        let client = {
+            let mut shared = self.shared.lock();
+            let client = shared.client.ok_or(Error::NotConnected)?;
+            let cipher = shared.cipher.as_mut().ok_or(Error::NotConnected)?;
+            cipher.encrypt(&mut packet)?;
+
+            client
+        };
+        self.udp.send_to(&packet, client).await?;
+
+        Ok(())
+    }
+
+    /// Encryption mode selected by the client, once it has.
+    pub fn crypto_mode(&self) -> Option<CryptoMode> {
+        self.shared.lock().cipher.as_ref().map(Cipher::mode)
+    }
+}
+
+impl Drop for MockVoiceServer {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! UDP socket of the mock server.
+
+use std::sync::Arc;
+
+use discortp::discord::{IpDiscoveryPacket, IpDiscoveryType, MutableIpDiscoveryPacket};
+use tokio::{net::UdpSocket, sync::mpsc};
+
+use super::{CapturedPacket, SharedHandle};
+use crate::{receive::RtpPacket, rtcp};
+
+/// Answer IP discovery and capture audio until aborted.
+pub(super) async fn serve(
+    socket: Arc<UdpSocket>,
+    shared: SharedHandle,
+    packets: mpsc::UnboundedSender<CapturedPacket>,
+) {
+    let mut buffer = [0; 1500];
+
+    loop {
+        let Ok((len, from)) = socket.recv_from(&mut buffer).await else {
+            continue;
+        };
+        let packet = &buffer[..len];
+
+        let discovery = (len == IpDiscoveryPacket::const_packet_size())
+            .then(|| IpDiscoveryPacket::new(packet))
+            .flatten()
+            .filter(|request| request.get_pkt_type() == IpDiscoveryType::Request);
+        if discovery.is_some() {
+            shared.lock().client = Some(from);
+
+            let mut response = [0; IpDiscoveryPacket::const_packet_size()];
+            {
+                let mut packet = MutableIpDiscoveryPacket::new(&mut response).unwrap();
+                packet.set_pkt_type(IpDiscoveryType::Response);
+                packet.set_ssrc(super::MockVoiceServer::SSRC);
+                packet.set_length(70);
+
+                let mut address = [0; 64];
+                let ip = from.ip().to_string();
+                address[..ip.len()].copy_from_slice(ip.as_bytes());
+                packet.set_address(&address);
+                packet.set_port(from.port());
+            }
+            let _ = socket.send_to(&response, from).await;
+            continue;
+        }
+
+        // Keepalives and RTCP carry no audio.
+        if rtcp::is_rtcp(packet) {
+            continue;
+        }
+        let opened = match &shared.lock().cipher {
+            Some(cipher) => RtpPacket::open(packet, cipher),
+            None => continue,
+        };
+        if let Ok(Some(rtp)) = opened {
+            let _ = packets.send(CapturedPacket {
+                ssrc: rtp.ssrc,
+                sequence: rtp.sequence,
+                timestamp: rtp.timestamp,
+                payload: rtp.payload,
+            });
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! Stand-in for the Discord gateway, answering voice state updates.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::sync::{self, mpsc};
+use twilight_model::{
+    gateway::payload::incoming::VoiceServerUpdate,
+    id::{
+        marker::{ChannelMarker, GuildMarker, UserMarker},
+        Id,
+    },
+};
+
+use super::MockVoiceServer;
+use crate::{
+    client::{PartialVoiceStateUpdate, VoiceClient, VoiceUpdate},
+    Result,
+};
+
+/// A voice state update requested through [`VoiceUpdate`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VoiceStateRequest {
+    pub guild_id: Id<GuildMarker>,
+    /// Channel to join, or `None` to leave.
+    pub channel_id: Option<Id<ChannelMarker>>,
+    pub self_deaf: bool,
+    pub self_mute: bool,
+}
+
+/// An event from the Discord gateway for a [`VoiceClient`].
+#[derive(Clone, Debug)]
+pub enum GatewayEvent {
+    VoiceStateUpdate(PartialVoiceStateUpdate),
+    VoiceServerUpdate(VoiceServerUpdate),
+}
+
+impl GatewayEvent {
+    /// Hand the event to `client`, as a bot's event loop would.
+    pub async fn dispatch<D: VoiceUpdate + 'static>(
+        self,
+        client: &mut VoiceClient<D>,
+    ) -> Result<()> {
+        match self {
+            Self::VoiceStateUpdate(data) => client.on_voice_state_update(data).await,
+            Self::VoiceServerUpdate(data) => client.on_voice_server_update(data).await,
+        }
+    }
+}
+
+struct State {
+    requests: Vec<VoiceStateRequest>,
+    auto_respond: bool,
+    endpoint: String,
+    /// Voice server updates sent, numbering their tokens.
+    servers: u32,
+    /// The last request answered, while in a channel.
+    joined: Option<VoiceStateRequest>,
+    last: Option<GatewayEvent>,
+}
+
+struct Inner {
+    user_id: Id<UserMarker>,
+    state: Mutex<State>,
+    sender: mpsc::UnboundedSender<GatewayEvent>,
+    events: sync::Mutex<mpsc::UnboundedReceiver<GatewayEvent>>,
+}
+
+/// A [`VoiceUpdate`] which records the voice state updates requested, and answers them with the
+/// events the Discord gateway would send.
+///
+/// Like Discord, every voice server update carries a new token, so the same session is never
+/// assigned twice.
+///
+/// Events are queued rather than delivered, to be read with [`MockVoiceUpdate::next_event`] and
+/// handed to the client with [`GatewayEvent::dispatch`]. Further events, such as being moved
+/// or disconnected, can be queued at any time.
+///
+/// Clones share the same queue, so one can be given to the [`VoiceClient`] and another kept to
+/// drive it.
+#[derive(Clone)]
+pub struct MockVoiceUpdate(Arc<Inner>);
+
+impl MockVoiceUpdate {
+    /// Session ID sent in every voice state update.
+    pub const SESSION_ID: &'static str = "mock-session";
+
+    /// Act as the Discord gateway for `user_id`, assigning the voice server at `endpoint`, such
+    /// as [`MockVoiceServer::endpoint`].
+    pub fn new(user_id: Id<UserMarker>, endpoint: impl Into<String>) -> Self {
+        let (sender, events) = mpsc::unbounded_channel();
+
+        Self(Arc::new(Inner {
+            user_id,
+            state: Mutex::new(State {
+                requests: Vec::new(),
+                auto_respond: true,
+                endpoint: endpoint.into(),
+                servers: 0,
+                joined: None,
+                last: None,
+            }),
+            sender,
+            events: sync::Mutex::new(events),
+        }))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.0
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Whether to answer requests as they are made, which is the default. Otherwise they are
+    /// only recorded, to be answered with [`MockVoiceUpdate::respond`].
+    pub fn set_auto_respond(&self, enabled: bool) {
+        self.lock().auto_respond = enabled;
+    }
+
+    /// Every voice state update requested so far, oldest first.
+    pub fn requests(&self) -> Vec<VoiceStateRequest> {
+        self.lock().requests.clone()
+    }
+
+    /// Wait for the next queued event.
+    pub async fn next_event(&self) -> Option<GatewayEvent> {
+        self.0.events.lock().await.recv().await
+    }
+
+    /// Take the next queued event, if there is one.
+    pub fn try_next_event(&self) -> Option<GatewayEvent> {
+        self.0.events.try_lock().ok()?.try_recv().ok()
+    }
+
+    fn queue(&self, event: GatewayEvent) {
+        self.lock().last = Some(event.clone());
+        // The receiver lives as long as the sender.
+        let _ = self.0.sender.send(event);
+    }
+
+    fn voice_state(&self, channel_id: Option<Id<ChannelMarker>>) -> GatewayEvent {
+        GatewayEvent::VoiceStateUpdate(PartialVoiceStateUpdate {
+            session_id: Self::SESSION_ID.into(),
+            channel_id,
+            user_id: self.0.user_id,
+        })
+    }
+
+    /// A voice server update with a new token, as Discord sends for every new session.
+    fn voice_server(&self, guild_id: Id<GuildMarker>) -> GatewayEvent {
+        let mut state = self.lock();
+        state.servers += 1;
+
+        GatewayEvent::VoiceServerUpdate(VoiceServerUpdate {
+            endpoint: Some(state.endpoint.clone()),
+            guild_id,
+            token: format!("{}-{}", MockVoiceServer::TOKEN, state.servers),
+        })
+    }
+
+    /// Queue the events answering `request`: a voice state update, followed by a voice server
+    /// update when joining a channel unless only self-mute or self-deafen changed.
+    pub fn respond(&self, request: VoiceStateRequest) {
+        let joined = std::mem::replace(
+            &mut self.lock().joined,
+            request.channel_id.is_some().then_some(request),
+        );
+        let muted_only = joined.is_some_and(|joined| {
+            joined.channel_id == request.channel_id
+                && (joined.self_deaf, joined.self_mute) != (request.self_deaf, request.self_mute)
+        });
+
+        self.queue(self.voice_state(request.channel_id));
+        if request.channel_id.is_some() && !muted_only {
+            self.queue(self.voice_server(request.guild_id));
+        }
+    }
+
+    /// Queue the events of being moved to `channel_id` by someone else.
+    pub fn move_to(&self, guild_id: Id<GuildMarker>, channel_id: Id<ChannelMarker>) {
+        if let Some(joined) = &mut self.lock().joined {
+            joined.channel_id = Some(channel_id);
+        }
+        self.queue(self.voice_state(Some(channel_id)));
+        self.queue(self.voice_server(guild_id));
+    }
+
+    /// Queue the event of being disconnected from the channel by someone else.
+    pub fn disconnect(&self) {
+        self.lock().joined = None;
+        self.queue(self.voice_state(None));
+    }
+
+    /// Queue a voice server update assigning the voice server at `endpoint`, as when Discord
+    /// moves a call to another server.
+    pub fn change_server(&self, guild_id: Id<GuildMarker>, endpoint: impl Into<String>) {
+        self.lock().endpoint = endpoint.into();
+        self.queue(self.voice_server(guild_id));
+    }
+
+    /// Queue the last event again.
+    pub fn repeat(&self) {
+        let last = self.lock().last.clone();
+        if let Some(event) = last {
+            self.queue(event);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl VoiceUpdate for MockVoiceUpdate {
+    async fn update_voice_state(
+        &self,
+        guild_id: Id<GuildMarker>,
+        channel_id: Option<Id<ChannelMarker>>,
+        self_deaf: bool,
+        self_mute: bool,
+    ) -> Result<()> {
+        let request = VoiceStateRequest {
+            guild_id,
+            channel_id,
+            self_deaf,
+            self_mute,
+        };
+
+        let auto_respond = {
+            let mut state = self.lock();
+            state.requests.push(request);
+            state.auto_respond
+        };
+        if auto_respond {
+            self.respond(request);
+        }
+
+        Ok(())
+    }
+}
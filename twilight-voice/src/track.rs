@@ -0,0 +1,534 @@
+//! Individual pieces of audio scheduled on a voice connection, and handles to control them.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use crate::{
+    codec::{self, OpusDecoder},
+    constants,
+    input::{AudioFrame, AudioSource, ConvertedSource, ResampleQuality},
+    Error, Result,
+};
+
+/// Identifier of a track, unique within the process.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TrackId(u64);
+
+impl TrackId {
+    fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for TrackId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Playback state of a track.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TrackState {
+    /// Playing, or waiting in a queue to be played.
+    Playing,
+    /// Holding its position until resumed.
+    Paused,
+    /// Finished, stopped or failed. An ended track cannot be resumed.
+    Ended,
+}
+
+/// How many more times a track starts over from the beginning once it reaches its end.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Loops {
+    Finite(u32),
+    Infinite,
+}
+
+/// Gradual change of a track's volume, advanced once per frame played.
+#[derive(Clone, Copy)]
+struct Fade {
+    from: f32,
+    to: f32,
+    frames: u32,
+    elapsed: u32,
+    /// Whether to end the track once the fade completes.
+    stop: bool,
+}
+
+/// State shared between a [`TrackHandle`] and the driver playing its track.
+struct Control {
+    state: TrackState,
+    volume: f32,
+    fade: Option<Fade>,
+    /// Playback time of the audio sent so far.
+    position: Duration,
+    /// Position requested by [`TrackHandle::seek`], not yet reached.
+    seek: Option<Duration>,
+    loops: Loops,
+}
+
+struct Shared {
+    id: TrackId,
+    seekable: bool,
+    control: Mutex<Control>,
+}
+
+/// Handle to control a track, which stays valid after the track ends.
+#[derive(Clone)]
+pub struct TrackHandle {
+    inner: Arc<Shared>,
+}
+
+impl TrackHandle {
+    fn new(seekable: bool) -> Self {
+        Self {
+            inner: Arc::new(Shared {
+                id: TrackId::next(),
+                seekable,
+                control: Mutex::new(Control {
+                    state: TrackState::Playing,
+                    volume: 1.0,
+                    fade: None,
+                    position: Duration::ZERO,
+                    seek: None,
+                    loops: Loops::Finite(0),
+                }),
+            }),
+        }
+    }
+
+    fn control(&self) -> MutexGuard<'_, Control> {
+        self.inner
+            .control
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn id(&self) -> TrackId {
+        self.inner.id
+    }
+
+    pub fn state(&self) -> TrackState {
+        self.control().state
+    }
+
+    /// Playback time of the audio sent over the connection so far.
+    ///
+    /// This counts frames as they are sent, so it stands still while the track is paused or
+    /// the connection is not ready, and jumps to the position reached by a seek.
+    pub fn position(&self) -> Duration {
+        self.control().position
+    }
+
+    /// Whether [`TrackHandle::seek`] is supported by the track's source.
+    pub fn is_seekable(&self) -> bool {
+        self.inner.seekable
+    }
+
+    /// Move playback to `position`, which takes effect before the track's next frame is sent.
+    ///
+    /// Sources may only be able to seek approximately, such as to the start of an Ogg page, in
+    /// which case [`TrackHandle::position`] reports where playback actually resumed. Seeking
+    /// past the end ends the track.
+    pub fn seek(&self, position: Duration) -> Result<()> {
+        if !self.inner.seekable {
+            return Err(Error::NotSeekable);
+        }
+
+        self.control().seek = Some(position);
+
+        Ok(())
+    }
+
+    pub(crate) fn advance_position(&self, duration: Duration) {
+        self.control().position += duration;
+    }
+
+    /// Loops remaining before the track ends.
+    pub fn loops(&self) -> Loops {
+        self.control().loops
+    }
+
+    /// Play the track again from the start after it ends, `loops` more times.
+    ///
+    /// Each loop starts straight after the end of the previous one, without a gap. Looping
+    /// requires a seekable source.
+    pub fn set_loops(&self, loops: Loops) -> Result<()> {
+        if !self.inner.seekable {
+            return Err(Error::NotSeekable);
+        }
+
+        self.control().loops = loops;
+
+        Ok(())
+    }
+
+    /// Use up one loop, returning whether the track should start over.
+    pub(crate) fn take_loop(&self) -> bool {
+        let mut control = self.control();
+        match &mut control.loops {
+            Loops::Infinite => true,
+            Loops::Finite(0) => false,
+            Loops::Finite(remaining) => {
+                *remaining -= 1;
+                true
+            }
+        }
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.control().volume
+    }
+
+    /// Scale the track's audio by `volume`, where 1.0 plays it unchanged, cancelling any fade.
+    ///
+    /// The change is ramped in over a few milliseconds to avoid clicks. Any volume other than
+    /// 1.0 requires Opus tracks to be decoded and re-encoded.
+    pub fn set_volume(&self, volume: f32) {
+        let mut control = self.control();
+        control.volume = volume.max(0.0);
+        control.fade = None;
+    }
+
+    /// Change the volume to `volume` gradually over `duration` of playback.
+    ///
+    /// Time spent paused does not count towards the fade.
+    pub fn fade(&self, volume: f32, duration: Duration) {
+        self.start_fade(volume, duration, false);
+    }
+
+    /// Fade the track to silence over `duration`, then end it.
+    ///
+    /// A queue moves on to its next track as soon as the fade begins, so the two overlap: see
+    /// [`TrackQueue::crossfade`].
+    ///
+    /// [`TrackQueue::crossfade`]: crate::queue::TrackQueue::crossfade
+    pub fn fade_out(&self, duration: Duration) {
+        self.start_fade(0.0, duration, true);
+    }
+
+    fn start_fade(&self, volume: f32, duration: Duration, stop: bool) {
+        let frames = (duration.as_millis() / constants::FRAME_DURATION.as_millis()).max(1);
+
+        let mut control = self.control();
+        control.fade = Some(Fade {
+            from: control.volume,
+            to: volume.max(0.0),
+            frames: u32::try_from(frames).unwrap_or(u32::MAX),
+            elapsed: 0,
+            stop,
+        });
+    }
+
+    /// Whether the track is fading out before it ends.
+    pub(crate) fn is_fading_out(&self) -> bool {
+        self.control().fade.is_some_and(|fade| fade.stop)
+    }
+
+    /// Advance any fade by one frame, returning the volume the frame should be played at.
+    pub(crate) fn advance_volume(&self) -> f32 {
+        let mut control = self.control();
+        let Some(fade) = &mut control.fade else {
+            return control.volume;
+        };
+
+        fade.elapsed += 1;
+        let progress = fade.elapsed as f32 / fade.frames as f32;
+        let volume = fade.from + (fade.to - fade.from) * progress;
+        let done = fade.elapsed >= fade.frames;
+        let stop = fade.stop;
+
+        control.volume = volume;
+        if done {
+            control.fade = None;
+            if stop {
+                control.state = TrackState::Ended;
+            }
+        }
+
+        volume
+    }
+
+    /// Resume a paused track.
+    pub fn play(&self) {
+        self.set_state(TrackState::Playing);
+    }
+
+    /// Pause the track, keeping its position.
+    ///
+    /// A queued track which is paused holds up the rest of its queue once it is reached.
+    pub fn pause(&self) {
+        self.set_state(TrackState::Paused);
+    }
+
+    /// End the track. Stopping a queued track removes it before it ever plays.
+    pub fn stop(&self) {
+        self.set_state(TrackState::Ended);
+    }
+
+    /// Change the state, unless the track has already ended.
+    pub(crate) fn set_state(&self, state: TrackState) {
+        let mut control = self.control();
+        if control.state != TrackState::Ended {
+            control.state = state;
+        }
+    }
+}
+
+impl fmt::Debug for TrackHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TrackHandle")
+            .field("id", &self.id())
+            .field("state", &self.state())
+            .field("position", &self.position())
+            .finish()
+    }
+}
+
+/// An audio source together with the handle controlling it.
+pub(crate) struct Track {
+    pub handle: TrackHandle,
+    pub source: Box<dyn AudioSource>,
+    /// Decoder for Opus frames which need to be mixed, created when first needed.
+    decoder: Option<OpusDecoder>,
+    /// Volume applied at the end of the last frame mixed, from which the next one is ramped.
+    pub gain: Option<f32>,
+    /// Result of reading the first frame ahead of time, see [`Track::buffer`].
+    buffered: Option<Result<Option<AudioFrame>>>,
+}
+
+impl Track {
+    /// Wrap `source`, converting PCM which is not 48 kHz stereo. The format is checked for
+    /// every frame, as sources such as [`OggOpusSource`] may change it partway through.
+    ///
+    /// [`OggOpusSource`]: crate::input::OggOpusSource
+    pub fn new(source: impl AudioSource + 'static) -> Self {
+        let source: Box<dyn AudioSource> =
+            Box::new(ConvertedSource::new(source, ResampleQuality::default()));
+
+        Self {
+            handle: TrackHandle::new(source.is_seekable()),
+            source,
+            decoder: None,
+            gain: None,
+            buffered: None,
+        }
+    }
+
+    /// Read the first frame ahead of time, so that starting the track does not wait on its
+    /// source.
+    pub async fn buffer(&mut self) {
+        if self.buffered.is_none() {
+            self.buffered = Some(self.source.read_frame().await);
+        }
+    }
+
+    /// Read the next frame, first performing any seek requested through the handle.
+    pub async fn read_frame(&mut self) -> Result<Option<AudioFrame>> {
+        let seek = self.handle.control().seek.take();
+        if let Some(position) = seek {
+            self.buffered = None;
+            self.seek(position).await?;
+        }
+
+        match self.buffered.take() {
+            Some(buffered) => buffered,
+            None => self.source.read_frame().await,
+        }
+    }
+
+    /// Play the track again from the start.
+    pub async fn restart(&mut self) -> Result<()> {
+        self.seek(Duration::ZERO).await
+    }
+
+    async fn seek(&mut self, position: Duration) -> Result<()> {
+        let reached = self.source.seek(position).await?;
+        self.handle.control().position = reached;
+        // Decoding picks up from an unrelated packet.
+        self.decoder = None;
+
+        Ok(())
+    }
+
+    /// Turn a frame read from this track into 48 kHz stereo PCM for mixing.
+    pub fn decode(&mut self, frame: AudioFrame) -> Result<Vec<f32>> {
+        match frame {
+            AudioFrame::Pcm(samples) => Ok(samples),
+            AudioFrame::Opus(packet) => codec::decoder(&mut self.decoder)?.decode(
+                Some(&packet),
+                constants::MONO_FRAME_SIZE,
+                false,
+            ),
+        }
+    }
+}
+
+/// Tracks waiting to be picked up by the driver and played alongside the queue.
+#[derive(Clone, Default)]
+pub(crate) struct PendingTracks(Arc<Mutex<Vec<Track>>>);
+
+impl PendingTracks {
+    pub fn push(&self, track: Track) {
+        self.lock().push(track);
+    }
+
+    pub fn take(&self) -> Vec<Track> {
+        std::mem::take(&mut *self.lock())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Track>> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Loops, Track, TrackHandle, TrackState};
+    use crate::{
+        input::{AudioFormat, AudioFrame, AudioSource, MemorySource, RawPcmSource, SampleFormat},
+        Error, Result,
+    };
+
+    #[test]
+    fn fades_once_per_frame() {
+        let handle = TrackHandle::new(false);
+        handle.set_volume(0.5);
+        assert_eq!(handle.advance_volume(), 0.5);
+
+        handle.fade(1.0, Duration::from_millis(100));
+        let volumes = (0..6).map(|_| handle.advance_volume()).collect::<Vec<_>>();
+        assert_eq!(volumes, [0.6, 0.7, 0.8, 0.9, 1.0, 1.0]);
+
+        handle.fade_out(Duration::from_millis(40));
+        assert!(handle.is_fading_out());
+        assert_eq!(handle.advance_volume(), 0.5);
+        assert_eq!(handle.state(), TrackState::Playing);
+        assert_eq!(handle.advance_volume(), 0.0);
+        assert_eq!(handle.state(), TrackState::Ended);
+        assert!(!handle.is_fading_out());
+    }
+
+    #[tokio::test]
+    async fn seeks_before_next_frame() -> anyhow::Result<()> {
+        let packets: Vec<_> = (0..10u8).map(|i| vec![0xF8, i]).collect();
+        let mut track = Track::new(MemorySource::opus(packets));
+        let handle = track.handle.clone();
+
+        assert!(handle.is_seekable());
+        handle.seek(Duration::from_millis(100))?;
+        assert_eq!(
+            track.read_frame().await?,
+            Some(AudioFrame::Opus(vec![0xF8, 5]))
+        );
+        assert_eq!(handle.position(), Duration::from_millis(100));
+
+        let piped = RawPcmSource::new(tokio::io::empty(), AudioFormat::DISCORD, SampleFormat::I16);
+        let piped = Track::new(piped);
+        assert!(matches!(
+            piped.handle.seek(Duration::ZERO),
+            Err(Error::NotSeekable)
+        ));
+        assert!(matches!(
+            piped.handle.set_loops(Loops::Infinite),
+            Err(Error::NotSeekable)
+        ));
+
+        Ok(())
+    }
+
+    /// Opus followed by mono PCM, as from an Ogg stream which falls back to transcoding.
+    struct SwitchingSource {
+        frames: Vec<AudioFrame>,
+        format: AudioFormat,
+    }
+
+    #[async_trait::async_trait]
+    impl AudioSource for SwitchingSource {
+        fn format(&self) -> AudioFormat {
+            self.format
+        }
+
+        async fn read_frame(&mut self) -> Result<Option<AudioFrame>> {
+            if self.frames.is_empty() {
+                return Ok(None);
+            }
+            let frame = self.frames.remove(0);
+            if let AudioFrame::Pcm(_) = frame {
+                self.format = AudioFormat::new(48_000, 1);
+            }
+
+            Ok(Some(frame))
+        }
+    }
+
+    #[tokio::test]
+    async fn converts_format_changed_midway() -> anyhow::Result<()> {
+        let mut track = Track::new(SwitchingSource {
+            frames: vec![
+                AudioFrame::Opus(vec![0xF8, 0]),
+                AudioFrame::Pcm(vec![0.5; 960]),
+            ],
+            format: AudioFormat::DISCORD,
+        });
+
+        assert_eq!(
+            track.read_frame().await?,
+            Some(AudioFrame::Opus(vec![0xF8, 0]))
+        );
+        let frame = track.read_frame().await?.unwrap();
+        assert_eq!(track.decode(frame)?, vec![0.5; 1920]);
+        assert_eq!(track.read_frame().await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn loops_from_buffered_start() -> anyhow::Result<()> {
+        let packets: Vec<_> = (0..2u8).map(|i| vec![0xF8, i]).collect();
+        let mut track = Track::new(MemorySource::opus(packets));
+        let handle = track.handle.clone();
+
+        track.buffer().await;
+        track.buffer().await;
+        assert_eq!(
+            track.read_frame().await?,
+            Some(AudioFrame::Opus(vec![0xF8, 0]))
+        );
+
+        handle.set_loops(Loops::Finite(2))?;
+        assert!(handle.take_loop());
+        assert_eq!(handle.loops(), Loops::Finite(1));
+        handle.advance_position(Duration::from_millis(40));
+        track.restart().await?;
+        assert_eq!(handle.position(), Duration::ZERO);
+        assert_eq!(
+            track.read_frame().await?,
+            Some(AudioFrame::Opus(vec![0xF8, 0]))
+        );
+
+        assert!(handle.take_loop());
+        assert!(!handle.take_loop());
+        handle.set_loops(Loops::Infinite)?;
+        assert!(handle.take_loop());
+        assert_eq!(handle.loops(), Loops::Infinite);
+
+        Ok(())
+    }
+}
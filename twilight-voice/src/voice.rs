@@ -1,33 +1,140 @@
-use discortp::discord::{IpDiscoveryPacket, IpDiscoveryType, MutableIpDiscoveryPacket};
-use tokio::net::UdpSocket;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
 
-use crate::Result;
+use discortp::{
+    discord::{
+        IpDiscoveryPacket, IpDiscoveryType, KeepalivePacket, MutableIpDiscoveryPacket,
+        MutableKeepalivePacket,
+    },
+    rtp::{MutableRtpPacket, RtpType},
+};
+use tokio::{net::UdpSocket, time};
+
+use crate::{
+    constants,
+    crypto::{Cipher, RTCP_HEADER_LEN, RTP_HEADER_LEN},
+    receive::RtpPacket,
+    rtcp::{self, Report, ReportBlock},
+    stats::Stats,
+    Error, Result,
+};
+
+/// Payload type Discord assigns to Opus audio.
+pub(crate) const RTP_PROFILE_OPUS: u8 = 120;
+
+/// Time without sending anything after which a keepalive is sent, so that Discord and any NAT
+/// along the way keep the socket's mapping open.
+pub(crate) const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+/// Consecutive socket errors after which the socket may be dead.
+const DEAD_ERRORS: u32 = 3;
+/// Time without receiving anything after which a socket with errors is considered dead.
+const DEAD_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Traffic on the socket, to tell when it needs keeping alive or has died.
+struct Health {
+    last_sent: Instant,
+    last_received: Instant,
+    /// Socket errors since the last successful send.
+    errors: u32,
+    stats: Stats,
+}
+
+impl Health {
+    fn new(stats: Stats) -> Self {
+        let now = Instant::now();
+
+        Self {
+            last_sent: now,
+            last_received: now,
+            errors: 0,
+            stats,
+        }
+    }
+
+    fn sent(&mut self, result: io::Result<usize>) -> Result<()> {
+        match result {
+            Ok(len) => {
+                self.stats.sent(len);
+                self.last_sent = Instant::now();
+                self.errors = 0;
+
+                Ok(())
+            }
+            Err(error) => {
+                self.errors += 1;
+
+                Err(error.into())
+            }
+        }
+    }
+}
 
 pub struct DiscordVoiceConnection {
     pub udp_socket: UdpSocket,
+    ssrc: u32,
+    sequence: u16,
+    timestamp: u32,
+    cipher: Option<Cipher>,
+    packet: Vec<u8>,
+    /// RTP packets and payload bytes sent, as counted by sender reports.
+    packets_sent: u32,
+    octets_sent: u32,
+    health: Health,
 }
 
 impl DiscordVoiceConnection {
-    pub async fn connect(ip: String, port: u16) -> Result<Self> {
-        let udp_socket = UdpSocket::bind("0.0.0.0:0").await?;
-        udp_socket.connect(format!("{}:{}", ip, port)).await?;
+    /// Open a socket to the voice server at `ip`, bound to `bind` or else the unspecified
+    /// address of the same family.
+    pub(crate) async fn connect(
+        ip: IpAddr,
+        port: u16,
+        ssrc: u32,
+        bind: Option<IpAddr>,
+        stats: Stats,
+    ) -> Result<Self> {
+        let bind = bind.unwrap_or(match ip {
+            IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        });
+        let udp_socket = UdpSocket::bind((bind, 0)).await?;
+        udp_socket.connect((ip, port)).await?;
+
+        Ok(Self {
+            udp_socket,
+            ssrc,
+            sequence: rand::random(),
+            timestamp: rand::random(),
+            cipher: None,
+            packet: Vec::new(),
+            packets_sent: 0,
+            octets_sent: 0,
+            health: Health::new(stats),
+        })
+    }
 
-        Ok(Self { udp_socket })
+    /// Ask the voice server for our external address and port, to be sent with `SelectProtocol`.
+    pub async fn ip_discovery(&self, timeout: Duration) -> Result<SocketAddr> {
+        time::timeout(timeout, self.discover_ip())
+            .await
+            .map_err(|_| Error::IpDiscoveryTimeout)?
     }
 
-    pub async fn ip_discovery(&self, ssrc: u32) -> Result<()> {
+    async fn discover_ip(&self) -> Result<SocketAddr> {
         let mut buffer = [0; IpDiscoveryPacket::const_packet_size()];
 
         {
             let mut packet = MutableIpDiscoveryPacket::new(&mut buffer).unwrap();
             packet.set_pkt_type(IpDiscoveryType::Request);
-            packet.set_ssrc(ssrc);
+            packet.set_ssrc(self.ssrc);
             packet.set_length(70);
         }
 
         self.udp_socket.send(&buffer).await?;
 
-        let (ip, port) = loop {
+        let (address, port) = loop {
             let (len, _) = self.udp_socket.recv_from(&mut buffer).await?;
             if let Some(packet) = IpDiscoveryPacket::new(&buffer[..len]) {
                 if packet.get_pkt_type() == IpDiscoveryType::Response {
@@ -36,7 +143,189 @@ impl DiscordVoiceConnection {
             }
         };
 
-        println!("{:?}", ip);
+        // The address is a null-terminated string.
+        let end = address
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(address.len());
+        let ip = std::str::from_utf8(&address[..end])
+            .ok()
+            .and_then(|ip| ip.parse().ok())
+            .ok_or(Error::IpDiscovery)?;
+
+        Ok(SocketAddr::new(ip, port))
+    }
+
+    pub fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    /// Install the session key received in `SessionDescription`, enabling audio to be sent.
+    pub fn set_cipher(&mut self, cipher: Cipher) {
+        self.cipher = Some(cipher);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Wait for the next packet from the voice server, returning its length.
+    pub async fn recv(&self, buffer: &mut [u8]) -> Result<usize> {
+        Ok(self.udp_socket.recv(buffer).await?)
+    }
+
+    /// Note the outcome of [`recv`], as evidence of whether the socket is still alive.
+    ///
+    /// [`recv`]: Self::recv
+    pub fn received(&mut self, result: &Result<usize>) {
+        match result {
+            Ok(len) => {
+                self.health.stats.received(*len);
+                self.health.last_received = Instant::now();
+            }
+            Err(_) => self.health.errors += 1,
+        }
+    }
+
+    /// Whether sending or receiving keeps failing while nothing arrives from the voice server.
+    pub fn is_dead(&self) -> bool {
+        self.health.errors >= DEAD_ERRORS && self.health.last_received.elapsed() >= DEAD_TIMEOUT
+    }
+
+    /// Send a keepalive if nothing has been sent for [`KEEPALIVE_INTERVAL`].
+    pub async fn keepalive(&mut self) -> Result<()> {
+        if self.health.last_sent.elapsed() < KEEPALIVE_INTERVAL {
+            return Ok(());
+        }
+
+        let mut buffer = [0; KeepalivePacket::minimum_packet_size()];
+        MutableKeepalivePacket::new(&mut buffer)
+            .unwrap()
+            .set_ssrc(self.ssrc);
+
+        let result = self.udp_socket.send(&buffer).await;
+        self.health.sent(result)
+    }
+
+    /// Decrypt a received RTP packet, returning `Ok(None)` for packets which are not audio.
+    pub(crate) fn open(&self, packet: &[u8]) -> Result<Option<RtpPacket>> {
+        let cipher = self.cipher.as_ref().ok_or(Error::NotConnected)?;
+
+        let result = RtpPacket::open(packet, cipher);
+        if let Err(Error::Crypto) = result {
+            self.health.stats.decrypt_failed();
+        }
+
+        result
+    }
+
+    /// Decrypt a received RTCP packet, returning the sender and receiver reports within.
+    pub(crate) fn open_rtcp(&self, packet: &[u8]) -> Result<Vec<Report>> {
+        let cipher = self.cipher.as_ref().ok_or(Error::NotConnected)?;
+        let body = cipher
+            .decrypt_rtcp(packet)
+            .inspect_err(|_| self.health.stats.decrypt_failed())?;
+
+        Ok(rtcp::parse(&packet[..RTCP_HEADER_LEN], &body))
+    }
+
+    /// Whether any audio has been sent, and so there is something to describe in a sender
+    /// report.
+    pub fn has_sent(&self) -> bool {
+        self.packets_sent > 0
+    }
+
+    /// Encrypt and send a sender report about our stream, carrying `blocks` about the streams
+    /// we receive.
+    pub(crate) async fn send_sender_report(&mut self, blocks: &[ReportBlock]) -> Result<()> {
+        let cipher = self.cipher.as_mut().ok_or(Error::NotConnected)?;
+
+        let mut packet = rtcp::sender_report(
+            self.ssrc,
+            rtcp::ntp_now(),
+            self.timestamp,
+            self.packets_sent,
+            self.octets_sent,
+            blocks,
+        );
+        cipher
+            .encrypt_rtcp(&mut packet)
+            .inspect_err(|_| self.health.stats.encrypt_failed())?;
+
+        let result = self.udp_socket.send(&packet).await;
+        self.health.sent(result)
+    }
+
+    /// Encrypt and send one 20 ms Opus packet.
+    pub async fn send_opus(&mut self, payload: &[u8]) -> Result<()> {
+        let cipher = self.cipher.as_mut().ok_or(Error::NotConnected)?;
+
+        self.packet.clear();
+        self.packet.resize(RTP_HEADER_LEN, 0);
+        {
+            let mut rtp = MutableRtpPacket::new(&mut self.packet).unwrap();
+            rtp.set_version(2);
+            rtp.set_payload_type(RtpType::Dynamic(RTP_PROFILE_OPUS));
+            rtp.set_sequence(self.sequence.into());
+            rtp.set_timestamp(self.timestamp.into());
+            rtp.set_ssrc(self.ssrc);
+        }
+        self.packet.extend_from_slice(payload);
+        cipher
+            .encrypt(&mut self.packet)
+            .inspect_err(|_| self.health.stats.encrypt_failed())?;
+
+        let result = self.udp_socket.send(&self.packet).await;
+        self.health.sent(result)?;
+
+        self.packets_sent = self.packets_sent.wrapping_add(1);
+        self.octets_sent = self.octets_sent.wrapping_add(payload.len() as u32);
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp = self
+            .timestamp
+            .wrapping_add(constants::MONO_FRAME_SIZE as u32);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio::net::UdpSocket;
+
+    use twilight_model::id::Id;
+
+    use super::{DiscordVoiceConnection, DEAD_ERRORS, DEAD_TIMEOUT, KEEPALIVE_INTERVAL};
+    use crate::stats::Stats;
+
+    #[tokio::test]
+    async fn keeps_socket_alive() -> anyhow::Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").await?;
+        let address = server.local_addr()?;
+        let stats = Stats::new(Id::new(1));
+        let mut udp =
+            DiscordVoiceConnection::connect(address.ip(), address.port(), 42, None, stats.clone())
+                .await?;
+
+        // Nothing is sent while the socket has been used recently.
+        udp.keepalive().await?;
+        udp.health.last_sent = Instant::now() - KEEPALIVE_INTERVAL;
+        udp.keepalive().await?;
+
+        let mut buffer = [0; 16];
+        let len = server.recv(&mut buffer).await?;
+        assert_eq!(buffer[..len], 42u32.to_be_bytes());
+        assert_eq!(stats.snapshot(Default::default()).packets_sent, 1);
+
+        assert!(!udp.is_dead());
+        udp.health.errors = DEAD_ERRORS;
+        assert!(!udp.is_dead());
+        udp.health.last_received = Instant::now() - DEAD_TIMEOUT;
+        assert!(udp.is_dead());
+        udp.received(&Ok(len));
+        assert!(!udp.is_dead());
 
         Ok(())
     }